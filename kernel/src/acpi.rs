@@ -0,0 +1,182 @@
+// ACPI table discovery.
+//
+// Read-only: validates the RSDP handed over by the bootloader, walks the
+// RSDT (or XSDT on ACPI 2.0+) through the HHDM and records the physical
+// address of every table it lists. Every range is checked against the
+// page tables before it's read: Limine needn't map ACPI reclaimable or
+// NVS memory in the HHDM, and a bogus table address shouldn't fault.
+
+use core::ptr;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::{memory, paging};
+
+const MAX_TABLES: usize = 32;
+const SDT_HEADER_LEN: usize = 36;
+/// Largest RSDP or root table accepted; a real one is far smaller
+const MAX_ROOT_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The HHDM offset is unknown, so tables can't be mapped
+    NoHhdm,
+    BadRsdpSignature,
+    BadRsdpChecksum,
+    /// The RSDT/XSDT has the wrong signature, length or checksum
+    BadRootTable,
+    /// A table, or the RSDP, isn't mapped in the HHDM
+    NotMapped,
+}
+
+/// A table listed in the RSDT/XSDT
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTable {
+    pub signature: [u8; 4],
+    pub phys: PhysAddr,
+}
+
+impl AcpiTable {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+}
+
+/// Everything found during discovery
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Whether the root table is an XSDT (64-bit entries)
+    pub xsdt: bool,
+    tables: [Option<AcpiTable>; MAX_TABLES],
+    /// Entries the root table listed beyond `MAX_TABLES`, or pointing at a
+    /// header that isn't mapped
+    pub skipped: usize,
+}
+
+impl AcpiInfo {
+    pub fn tables(&self) -> impl Iterator<Item = &AcpiTable> {
+        self.tables.iter().flatten()
+    }
+
+    pub fn oem_id(&self) -> &str {
+        core::str::from_utf8(&self.oem_id).unwrap_or("?").trim_end()
+    }
+}
+
+pub static ACPI: Mutex<Option<AcpiInfo>> = Mutex::new(None);
+
+/// Check the `len` bytes at `phys` can be read through the HHDM
+pub fn check_mapped(phys: u64, len: usize) -> Result<(), AcpiError> {
+    let end = phys.checked_add(len as u64).ok_or(AcpiError::NotMapped)?;
+    let mut page = phys & !0xFFF;
+    while page < end {
+        let addr = PhysAddr::try_new(page.max(phys)).map_err(|_| AcpiError::NotMapped)?;
+        if paging::translate(memory::phys_to_virt(addr)) != Some(addr) {
+            return Err(AcpiError::NotMapped);
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// Pointer to physical memory through the HHDM
+fn phys_ptr(phys: u64) -> *const u8 {
+    memory::phys_to_virt(PhysAddr::new(phys)).as_ptr()
+}
+
+fn read_bytes<const N: usize>(phys: u64) -> [u8; N] {
+    unsafe { ptr::read_unaligned(phys_ptr(phys) as *const [u8; N]) }
+}
+
+fn read_u32(phys: u64) -> u32 {
+    u32::from_le_bytes(read_bytes(phys))
+}
+
+fn read_u64(phys: u64) -> u64 {
+    u64::from_le_bytes(read_bytes(phys))
+}
+
+/// Whether the `len` bytes at `phys` sum to zero
+fn checksum_ok(phys: u64, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_ptr(phys), len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Parse the tables reachable from the RSDP at physical address `rsdp`
+///
+/// Returns the number of tables found.
+pub fn init(rsdp: u64) -> Result<usize, AcpiError> {
+    memory::hhdm_offset().ok_or(AcpiError::NoHhdm)?;
+
+    check_mapped(rsdp, 20)?;
+    if &read_bytes::<8>(rsdp) != b"RSD PTR " {
+        return Err(AcpiError::BadRsdpSignature);
+    }
+    // The ACPI 1.0 part is always 20 bytes and has its own checksum
+    if !checksum_ok(rsdp, 20) {
+        return Err(AcpiError::BadRsdpChecksum);
+    }
+    let oem_id = read_bytes::<6>(rsdp + 9);
+    let revision = read_bytes::<1>(rsdp + 15)[0];
+
+    let (root, xsdt) = if revision >= 2 {
+        check_mapped(rsdp, 24)?;
+        let length = read_u32(rsdp + 20) as usize;
+        if !(24..=MAX_ROOT_LEN).contains(&length) {
+            return Err(AcpiError::BadRsdpChecksum);
+        }
+        check_mapped(rsdp, length)?;
+        if !checksum_ok(rsdp, length) {
+            return Err(AcpiError::BadRsdpChecksum);
+        }
+        (read_u64(rsdp + 24), true)
+    } else {
+        (read_u32(rsdp + 16) as u64, false)
+    };
+
+    let expected: &[u8; 4] = if xsdt { b"XSDT" } else { b"RSDT" };
+    check_mapped(root, SDT_HEADER_LEN)?;
+    let length = read_u32(root + 4) as usize;
+    if &read_bytes::<4>(root) != expected || !(SDT_HEADER_LEN..=MAX_ROOT_LEN).contains(&length) {
+        return Err(AcpiError::BadRootTable);
+    }
+    check_mapped(root, length)?;
+    if !checksum_ok(root, length) {
+        return Err(AcpiError::BadRootTable);
+    }
+
+    let entry_size = if xsdt { 8 } else { 4 };
+    let entries = (length - SDT_HEADER_LEN) / entry_size;
+    let mut info = AcpiInfo {
+        revision,
+        oem_id,
+        xsdt,
+        tables: [None; MAX_TABLES],
+        skipped: entries.saturating_sub(MAX_TABLES),
+    };
+    for i in 0..entries.min(MAX_TABLES) {
+        let entry = root + (SDT_HEADER_LEN + i * entry_size) as u64;
+        let phys = if xsdt { read_u64(entry) } else { read_u32(entry) as u64 };
+        // One bad entry shouldn't hide the tables after it
+        if check_mapped(phys, SDT_HEADER_LEN).is_err() {
+            info.skipped += 1;
+            continue;
+        }
+        info.tables[i] = Some(AcpiTable {
+            signature: read_bytes(phys),
+            phys: PhysAddr::new(phys),
+        });
+    }
+
+    let found = info.tables().count();
+    *ACPI.lock() = Some(info);
+    Ok(found)
+}
+
+/// Physical address of the first table with `signature`, e.g. `b"HPET"`
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let acpi = ACPI.lock();
+    let table = acpi.as_ref()?.tables().find(|t| &t.signature == signature)?;
+    Some(table.phys)
+}
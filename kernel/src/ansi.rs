@@ -0,0 +1,172 @@
+// Decoder for the key sequences a serial terminal sends.
+//
+// Terminals encode navigation keys as ANSI escape sequences (CSI `ESC [`
+// or SS3 `ESC O` followed by parameters and a final byte) rather than as
+// scancodes. `AnsiDecoder` turns them into the same reserved key codes the
+// keyboard driver uses, and normalizes Enter (CR) and Backspace (DEL), so
+// the shell doesn't care which console a key came from. It's fed one byte
+// at a time and keeps its state between calls, so a sequence split across
+// reads decodes the same as one that arrives all at once.
+
+use crate::keyboard::{
+    KEY_DELETE, KEY_DOWN, KEY_END, KEY_HOME, KEY_LEFT, KEY_PAGE_DOWN, KEY_PAGE_UP, KEY_RIGHT,
+    KEY_UP,
+};
+
+const ESC: u8 = 0x1B;
+const DEL: u8 = 0x7F;
+
+/// Longest CSI parameter string we keep; longer sequences are discarded
+const MAX_PARAMS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    Ss3,
+}
+
+pub struct AnsiDecoder {
+    state: State,
+    params: [u8; MAX_PARAMS],
+    len: usize,
+    overflow: bool,
+}
+
+impl AnsiDecoder {
+    pub const fn new() -> Self {
+        AnsiDecoder {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Feed one received byte; returns a key once one is complete
+    ///
+    /// Unknown or malformed sequences are swallowed whole so their tail
+    /// doesn't leak into the line as text.
+    pub fn feed(&mut self, byte: u8) -> Option<u8> {
+        match self.state {
+            State::Ground => match byte {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                b'\r' => Some(b'\n'),
+                DEL => Some(8),
+                _ => Some(byte),
+            },
+            State::Escape => match byte {
+                b'[' => {
+                    self.state = State::Csi;
+                    self.len = 0;
+                    self.overflow = false;
+                    None
+                }
+                b'O' => {
+                    self.state = State::Ss3;
+                    None
+                }
+                // ESC ESC: the first one was a lone Escape press
+                ESC => None,
+                _ => {
+                    // Alt+key or a stray Escape; keep the key itself
+                    self.state = State::Ground;
+                    self.feed(byte)
+                }
+            },
+            State::Csi => match byte {
+                b'0'..=b'9' | b';' => {
+                    if self.len < MAX_PARAMS {
+                        self.params[self.len] = byte;
+                        self.len += 1;
+                    } else {
+                        self.overflow = true;
+                    }
+                    None
+                }
+                0x40..=0x7E => {
+                    self.state = State::Ground;
+                    if self.overflow {
+                        None
+                    } else {
+                        csi_key(&self.params[..self.len], byte)
+                    }
+                }
+                _ => {
+                    // Not a valid CSI byte; abandon the sequence
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Ss3 => {
+                self.state = State::Ground;
+                cursor_key(byte)
+            }
+        }
+    }
+}
+
+/// Keys whose sequence ends in a letter: `ESC [ A`, `ESC O A`, `ESC [ 1 ; 5 A`
+fn cursor_key(final_byte: u8) -> Option<u8> {
+    match final_byte {
+        b'A' => Some(KEY_UP),
+        b'B' => Some(KEY_DOWN),
+        b'C' => Some(KEY_RIGHT),
+        b'D' => Some(KEY_LEFT),
+        b'H' => Some(KEY_HOME),
+        b'F' => Some(KEY_END),
+        _ => None,
+    }
+}
+
+fn csi_key(params: &[u8], final_byte: u8) -> Option<u8> {
+    if final_byte != b'~' {
+        // Any modifier parameters (e.g. Ctrl in `1;5A`) are ignored
+        return cursor_key(final_byte);
+    }
+    // VT-style `ESC [ n ~`, again ignoring modifiers after ';'
+    let code = params.split(|&b| b == b';').next().unwrap_or(&[]);
+    match code {
+        b"1" | b"7" => Some(KEY_HOME),
+        b"3" => Some(KEY_DELETE),
+        b"4" | b"8" => Some(KEY_END),
+        b"5" => Some(KEY_PAGE_UP),
+        b"6" => Some(KEY_PAGE_DOWN),
+        _ => None,
+    }
+}
+
+/// Decode a fixed set of sequences and check the key codes that come out
+///
+/// Covers CSI and SS3 arrows, `ESC [ 3 ~`, modifier parameters, the CR
+/// and DEL rewrites, and a sequence split across calls to `feed`.
+pub fn self_test() -> bool {
+    const CASES: &[(&[u8], &[u8])] = &[
+        (b"\x1b[A\x1b[B\x1b[C\x1b[D", &[KEY_UP, KEY_DOWN, KEY_RIGHT, KEY_LEFT]),
+        (b"\x1bOA\x1bOB\x1bOC\x1bOD", &[KEY_UP, KEY_DOWN, KEY_RIGHT, KEY_LEFT]),
+        (b"\x1bOH\x1bOF", &[KEY_HOME, KEY_END]),
+        (b"\x1b[3~", &[KEY_DELETE]),
+        (b"\x1b[5~\x1b[6~", &[KEY_PAGE_UP, KEY_PAGE_DOWN]),
+        (b"\x1b[1;5A\x1b[3;2~", &[KEY_UP, KEY_DELETE]),
+        (b"a\r\x7f", &[b'a', b'\n', 8]),
+        // Unknown sequences vanish without leaking their tail
+        (b"\x1b[99~x", b"x"),
+    ];
+    let decodes_to = |input: &[u8], expected: &[u8]| {
+        let mut decoder = AnsiDecoder::new();
+        let mut keys = input.iter().filter_map(|&byte| decoder.feed(byte));
+        expected.iter().all(|&key| keys.next() == Some(key)) && keys.next().is_none()
+    };
+    if !CASES.iter().all(|&(input, expected)| decodes_to(input, expected)) {
+        return false;
+    }
+
+    // One decoder across two feeds, as if the sequence arrived in two reads
+    let mut decoder = AnsiDecoder::new();
+    let first = b"\x1b[1;".iter().all(|&byte| decoder.feed(byte).is_none());
+    first && decoder.feed(b'5').is_none() && decoder.feed(b'C') == Some(KEY_RIGHT)
+}
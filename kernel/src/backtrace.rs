@@ -0,0 +1,108 @@
+// Stack traces from saved frame pointers.
+//
+// The kernel is built with frame pointers (see .cargo/config.toml), so
+// every prologue pushes the caller's RBP and points RBP at it: [rbp] is
+// the caller's frame and [rbp + 8] the return address. Walking that chain
+// gives raw return addresses, which can be resolved offline with
+// `addr2line -e kernel <address>`.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+
+use crate::{memory, paging};
+
+/// Most frames printed, in case the chain loops back on itself
+const MAX_FRAMES: usize = 32;
+
+/// How far above the first frame the walk may go: enough for the boot
+/// stack Limine hands over, small enough to stop in the weeds quickly
+const MAX_STACK_SPAN: u64 = 256 * 1024;
+
+/// Faulting RIP and RBP saved by `record_exception` (0 = none)
+static EXCEPTION_RIP: AtomicU64 = AtomicU64::new(0);
+static EXCEPTION_RBP: AtomicU64 = AtomicU64::new(0);
+
+/// The caller's frame pointer
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// The frame pointer of the code an interrupt handler interrupted
+///
+/// Must be inlined into the handler itself: the handler's prologue saved
+/// the interrupted RBP at [rbp].
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    unsafe { (frame_pointer() as *const u64).read() }
+}
+
+/// Whether `rbp` can be a frame at or above `base`
+fn frame_ok(rbp: u64, base: u64) -> bool {
+    rbp != 0
+        && rbp % 8 == 0
+        && rbp >= base
+        && rbp - base < MAX_STACK_SPAN
+        && VirtAddr::try_new(rbp).is_ok_and(|addr| memory::hhdm_offset().is_none() || paging::translate(addr).is_some())
+}
+
+/// Call `f` with each return address up the chain starting at `rbp`
+///
+/// Stops at a null or implausible frame pointer, one that doesn't climb
+/// the stack, or after `MAX_FRAMES`.
+pub fn walk(rbp: u64, mut f: impl FnMut(u64)) {
+    let base = rbp;
+    let mut rbp = rbp;
+    for _ in 0..MAX_FRAMES {
+        if !frame_ok(rbp, base) {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next, ret) = unsafe { (frame.read(), frame.add(1).read()) };
+        if ret == 0 {
+            break;
+        }
+        f(ret);
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Print a trace, with `rip` (the faulting instruction) as frame 0 if given
+pub fn write_trace(out: &mut dyn Write, rip: Option<u64>, rbp: u64) {
+    let _ = writeln!(out, "Backtrace:");
+    let mut index = 0;
+    let mut line = |addr: u64| {
+        let _ = writeln!(out, "  #{index:<2} {addr:#018x}");
+        index += 1;
+    };
+    if let Some(rip) = rip {
+        line(rip);
+    }
+    walk(rbp, &mut line);
+}
+
+/// Remember where an exception happened, for the panic it's about to raise
+///
+/// Call from the handler itself, so the interrupted frame pointer is
+/// picked up.
+#[inline(always)]
+pub fn record_exception(rip: u64) {
+    EXCEPTION_RBP.store(interrupted_frame_pointer(), Ordering::Relaxed);
+    EXCEPTION_RIP.store(rip, Ordering::Relaxed);
+}
+
+/// Print the trace for a panic: from the faulting instruction if an
+/// exception handler recorded one, otherwise from the caller
+pub fn write_panic_trace(out: &mut dyn Write) {
+    match EXCEPTION_RIP.swap(0, Ordering::Relaxed) {
+        0 => write_trace(out, None, frame_pointer()),
+        rip => write_trace(out, Some(rip), EXCEPTION_RBP.load(Ordering::Relaxed)),
+    }
+}
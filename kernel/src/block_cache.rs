@@ -0,0 +1,150 @@
+// LRU write-back block cache.
+//
+// Wraps any `BlockDevice` and implements `BlockDevice` itself, so callers
+// can't tell it's there except by speed. Writes only reach the device on
+// `flush` or when their slot is evicted; dropping the cache without a
+// flush discards dirty blocks.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::block_device::{BlockDevice, BlockError, BlockResult, BLOCK_SIZE};
+
+struct Slot {
+    /// Cached block id, or `None` if the slot is empty
+    block: Option<u64>,
+    dirty: bool,
+    /// Value of `CacheState::clock` at the last access
+    last_used: u64,
+    data: [u8; BLOCK_SIZE],
+}
+
+struct CacheState<D> {
+    device: D,
+    slots: Vec<Slot>,
+    /// Access counter used to order slots by recency
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<D: BlockDevice> CacheState<D> {
+    fn write_back(&mut self, index: usize) -> BlockResult<()> {
+        let slot = &mut self.slots[index];
+        if let (true, Some(id)) = (slot.dirty, slot.block) {
+            self.device.write_block(id, &slot.data)?;
+            slot.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Find the slot holding `block_id`, loading it on a miss
+    ///
+    /// When `load` is false a missed block isn't read from the device,
+    /// because the caller is about to overwrite it.
+    fn slot_for(&mut self, block_id: u64, load: bool) -> BlockResult<usize> {
+        if block_id >= self.device.block_count() {
+            return Err(BlockError::OutOfBounds);
+        }
+        self.clock += 1;
+
+        if let Some(index) = self.slots.iter().position(|s| s.block == Some(block_id)) {
+            self.hits += 1;
+            self.slots[index].last_used = self.clock;
+            return Ok(index);
+        }
+        self.misses += 1;
+
+        // Empty slots have `last_used` 0, so they go first
+        let index = (0..self.slots.len())
+            .min_by_key(|&i| self.slots[i].last_used)
+            .unwrap();
+        self.write_back(index)?;
+
+        let slot = &mut self.slots[index];
+        slot.block = None;
+        if load {
+            self.device.read_block(block_id, &mut slot.data)?;
+        }
+        slot.block = Some(block_id);
+        slot.last_used = self.clock;
+        Ok(index)
+    }
+}
+
+/// A fixed number of cached blocks in front of a device
+pub struct BlockCache<D: BlockDevice> {
+    state: Mutex<CacheState<D>>,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    /// Cache up to `capacity` blocks of `device`
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0
+    pub fn new(device: D, capacity: usize) -> Self {
+        assert!(capacity > 0, "block cache needs at least one slot");
+        let slots = (0..capacity)
+            .map(|_| Slot {
+                block: None,
+                dirty: false,
+                last_used: 0,
+                data: [0; BLOCK_SIZE],
+            })
+            .collect();
+        BlockCache {
+            state: Mutex::new(CacheState {
+                device,
+                slots,
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// `(hits, misses)` over all reads and writes so far
+    pub fn stats(&self) -> (u64, u64) {
+        let state = self.state.lock();
+        (state.hits, state.misses)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn read_block(&self, block_id: u64, buffer: &mut [u8; BLOCK_SIZE]) -> BlockResult<()> {
+        let mut state = self.state.lock();
+        let index = state.slot_for(block_id, true)?;
+        buffer.copy_from_slice(&state.slots[index].data);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buffer: &[u8; BLOCK_SIZE]) -> BlockResult<()> {
+        // Refuse now rather than when the dirty block is written back
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut state = self.state.lock();
+        let index = state.slot_for(block_id, false)?;
+        let slot = &mut state.slots[index];
+        slot.data.copy_from_slice(buffer);
+        slot.dirty = true;
+        Ok(())
+    }
+
+    /// Write back every dirty block, then flush the device itself
+    fn flush(&mut self) -> BlockResult<()> {
+        let mut state = self.state.lock();
+        for index in 0..state.slots.len() {
+            state.write_back(index)?;
+        }
+        state.device.flush()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.state.lock().device.block_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.state.lock().device.is_read_only()
+    }
+}
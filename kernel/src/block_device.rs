@@ -1,4 +1,5 @@
 use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 /// Standard block size (512 bytes, common for disk sectors)
 pub const BLOCK_SIZE: usize = 512;
@@ -15,6 +16,14 @@ pub enum BlockError {
     NotReady,
     /// A general I/O error occurred
     IoError,
+    /// The block's contents failed an integrity check
+    CorruptData,
+    /// Block 0 has no MBR signature
+    NoPartitionTable,
+    /// The device is write-protected
+    ReadOnly,
+    /// A buffer for the data couldn't be allocated
+    OutOfMemory,
 }
 
 impl fmt::Display for BlockError {
@@ -23,6 +32,10 @@ impl fmt::Display for BlockError {
             BlockError::OutOfBounds => write!(f, "Block out of bounds"),
             BlockError::NotReady => write!(f, "Device not ready"),
             BlockError::IoError => write!(f, "I/O error"),
+            BlockError::CorruptData => write!(f, "Data corrupted (checksum mismatch)"),
+            BlockError::NoPartitionTable => write!(f, "No partition table"),
+            BlockError::ReadOnly => write!(f, "Device is read-only"),
+            BlockError::OutOfMemory => write!(f, "Out of memory"),
         }
     }
 }
@@ -43,6 +56,54 @@ pub trait BlockDevice {
     /// * `buffer` - Buffer containing the block data (must be BLOCK_SIZE bytes)
     fn write_block(&mut self, block_id: u64, buffer: &[u8; BLOCK_SIZE]) -> BlockResult<()>;
 
+    /// Read consecutive blocks starting at `start` into `buffer`
+    ///
+    /// The whole range is validated before anything is read, so an
+    /// out-of-bounds span fails without touching `buffer`. The default
+    /// loops over `read_block`; devices with contiguous storage should
+    /// override it with a single copy.
+    ///
+    /// # Panics
+    /// Panics if `buffer.len()` is not a multiple of BLOCK_SIZE
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        let count = block_span(buffer.len());
+        check_range(start, count, self.block_count())?;
+
+        for (i, chunk) in buffer.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            let block: &mut [u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+            self.read_block(start + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// Write consecutive blocks starting at `start` from `buffer`
+    ///
+    /// Like `read_blocks`, the whole range is validated first, so an
+    /// out-of-bounds span fails without writing any block.
+    ///
+    /// # Panics
+    /// Panics if `buffer.len()` is not a multiple of BLOCK_SIZE
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> BlockResult<()> {
+        let count = block_span(buffer.len());
+        check_range(start, count, self.block_count())?;
+
+        for (i, chunk) in buffer.chunks_exact(BLOCK_SIZE).enumerate() {
+            let block: &[u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+            self.write_block(start + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// Make every completed write durable on the backing store
+    ///
+    /// Writes may be held back (by a cache, or by a disk's own write
+    /// buffer) until the next flush; after `flush` returns `Ok`, everything
+    /// written before the call survives a power loss. Devices that write
+    /// through, like `RamDisk`, keep the default no-op.
+    fn flush(&mut self) -> BlockResult<()> {
+        Ok(())
+    }
+
     /// Get the total number of blocks in this device
     fn block_count(&self) -> u64;
 
@@ -50,4 +111,98 @@ pub trait BlockDevice {
     fn block_size(&self) -> usize {
         BLOCK_SIZE
     }
+
+    /// Whether writes are refused with `BlockError::ReadOnly`
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Lets a wrapper such as `BlockCache` borrow a device instead of owning it
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn read_block(&self, block_id: u64, buffer: &mut [u8; BLOCK_SIZE]) -> BlockResult<()> {
+        (**self).read_block(block_id, buffer)
+    }
+
+    fn write_block(&mut self, block_id: u64, buffer: &[u8; BLOCK_SIZE]) -> BlockResult<()> {
+        (**self).write_block(block_id, buffer)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        (**self).read_blocks(start, buffer)
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> BlockResult<()> {
+        (**self).write_blocks(start, buffer)
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        (**self).flush()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        (**self).is_read_only()
+    }
+}
+
+/// Number of blocks covered by a buffer of `len` bytes
+///
+/// # Panics
+/// Panics if `len` is not a multiple of BLOCK_SIZE
+pub fn block_span(len: usize) -> u64 {
+    assert!(
+        len % BLOCK_SIZE == 0,
+        "buffer length must be a multiple of block size"
+    );
+    (len / BLOCK_SIZE) as u64
+}
+
+/// Check that `count` blocks starting at `start` lie within `block_count`
+pub fn check_range(start: u64, count: u64, block_count: u64) -> BlockResult<()> {
+    match start.checked_add(count) {
+        Some(end) if end <= block_count => Ok(()),
+        _ => Err(BlockError::OutOfBounds),
+    }
+}
+
+/// Read and write counters for one block, for access-pattern reports
+///
+/// Atomic so that `read_block`, which only takes `&self`, can count too.
+pub struct BlockHeat {
+    reads: AtomicU32,
+    writes: AtomicU32,
+}
+
+impl BlockHeat {
+    pub const fn new() -> Self {
+        BlockHeat {
+            reads: AtomicU32::new(0),
+            writes: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record_read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reads(&self) -> u32 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> u32 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.reads.store(0, Ordering::Relaxed);
+        self.writes.store(0, Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,91 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// A physical console the shell can read from or write to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConsoleKind {
+    Framebuffer = 0,
+    Serial = 1,
+}
+
+impl ConsoleKind {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ConsoleKind::Serial,
+            _ => ConsoleKind::Framebuffer,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ConsoleKind::Framebuffer => "fb",
+            ConsoleKind::Serial => "serial",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "fb" => Some(ConsoleKind::Framebuffer),
+            "serial" => Some(ConsoleKind::Serial),
+            _ => None,
+        }
+    }
+}
+
+/// Where keyboard-style input is accepted from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputRoute {
+    All,
+    Only(ConsoleKind),
+}
+
+const INPUT_ALL: u8 = 0xFF;
+
+/// When set, output goes to every console; otherwise only to `ACTIVE_OUTPUT`
+static MIRROR: AtomicBool = AtomicBool::new(true);
+static ACTIVE_OUTPUT: AtomicU8 = AtomicU8::new(ConsoleKind::Framebuffer as u8);
+static ACTIVE_INPUT: AtomicU8 = AtomicU8::new(INPUT_ALL);
+
+pub fn mirror_enabled() -> bool {
+    MIRROR.load(Ordering::Relaxed)
+}
+
+pub fn set_mirror(enabled: bool) {
+    MIRROR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn active_output() -> ConsoleKind {
+    ConsoleKind::from_u8(ACTIVE_OUTPUT.load(Ordering::Relaxed))
+}
+
+pub fn set_active_output(kind: ConsoleKind) {
+    ACTIVE_OUTPUT.store(kind as u8, Ordering::Relaxed);
+}
+
+/// Whether shell output should currently be sent to `kind`
+pub fn output_enabled(kind: ConsoleKind) -> bool {
+    mirror_enabled() || active_output() == kind
+}
+
+pub fn input_route() -> InputRoute {
+    match ACTIVE_INPUT.load(Ordering::Relaxed) {
+        INPUT_ALL => InputRoute::All,
+        v => InputRoute::Only(ConsoleKind::from_u8(v)),
+    }
+}
+
+pub fn set_input_route(route: InputRoute) {
+    let v = match route {
+        InputRoute::All => INPUT_ALL,
+        InputRoute::Only(kind) => kind as u8,
+    };
+    ACTIVE_INPUT.store(v, Ordering::Relaxed);
+}
+
+/// Whether input arriving from `kind` should be delivered to the shell
+pub fn input_enabled(kind: ConsoleKind) -> bool {
+    match input_route() {
+        InputRoute::All => true,
+        InputRoute::Only(k) => k == kind,
+    }
+}
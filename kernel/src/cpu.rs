@@ -0,0 +1,103 @@
+// Processor identification through CPUID.
+
+use core::arch::x86_64::{CpuidResult, __cpuid, __cpuid_count};
+
+const LEAF_VENDOR: u32 = 0x0000_0000;
+const LEAF_FEATURES: u32 = 0x0000_0001;
+const LEAF_EXTENDED_FEATURES: u32 = 0x0000_0007;
+const LEAF_MAX_EXTENDED: u32 = 0x8000_0000;
+const LEAF_BRAND: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+
+#[derive(Clone, Copy)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// A CPUID feature flag: leaf, register and bit
+pub struct Feature {
+    pub name: &'static str,
+    leaf: u32,
+    reg: Reg,
+    bit: u32,
+}
+
+pub const SSE: Feature = Feature { name: "sse", leaf: LEAF_FEATURES, reg: Reg::Edx, bit: 25 };
+pub const SSE2: Feature = Feature { name: "sse2", leaf: LEAF_FEATURES, reg: Reg::Edx, bit: 26 };
+pub const SSE3: Feature = Feature { name: "sse3", leaf: LEAF_FEATURES, reg: Reg::Ecx, bit: 0 };
+pub const SSSE3: Feature = Feature { name: "ssse3", leaf: LEAF_FEATURES, reg: Reg::Ecx, bit: 9 };
+pub const SSE4_1: Feature = Feature { name: "sse4.1", leaf: LEAF_FEATURES, reg: Reg::Ecx, bit: 19 };
+pub const SSE4_2: Feature = Feature { name: "sse4.2", leaf: LEAF_FEATURES, reg: Reg::Ecx, bit: 20 };
+pub const APIC: Feature = Feature { name: "apic", leaf: LEAF_FEATURES, reg: Reg::Edx, bit: 9 };
+pub const PAT: Feature = Feature { name: "pat", leaf: LEAF_FEATURES, reg: Reg::Edx, bit: 16 };
+pub const AVX: Feature = Feature { name: "avx", leaf: LEAF_FEATURES, reg: Reg::Ecx, bit: 28 };
+pub const RDRAND: Feature = Feature { name: "rdrand", leaf: LEAF_FEATURES, reg: Reg::Ecx, bit: 30 };
+pub const AVX2: Feature = Feature { name: "avx2", leaf: LEAF_EXTENDED_FEATURES, reg: Reg::Ebx, bit: 5 };
+
+/// The flags `info cpu` reports
+pub const FEATURES: [&Feature; 11] = [&SSE, &SSE2, &SSE3, &SSSE3, &SSE4_1, &SSE4_2, &AVX, &AVX2, &APIC, &PAT, &RDRAND];
+
+fn max_leaf() -> u32 {
+    unsafe { __cpuid(LEAF_VENDOR) }.eax
+}
+
+/// Whether the CPU reports `feature`
+pub fn has(feature: &Feature) -> bool {
+    if feature.leaf > max_leaf() {
+        return false;
+    }
+    let CpuidResult { ebx, ecx, edx, .. } = unsafe { __cpuid_count(feature.leaf, 0) };
+    let value = match feature.reg {
+        Reg::Ebx => ebx,
+        Reg::Ecx => ecx,
+        Reg::Edx => edx,
+    };
+    value & (1 << feature.bit) != 0
+}
+
+/// Vendor string, e.g. `GenuineIntel` or `AuthenticAMD`
+pub fn vendor() -> [u8; 12] {
+    let leaf = unsafe { __cpuid(LEAF_VENDOR) };
+    let mut vendor = [0; 12];
+    // The string runs EBX, EDX, ECX
+    for (chunk, reg) in vendor.chunks_mut(4).zip([leaf.ebx, leaf.edx, leaf.ecx]) {
+        chunk.copy_from_slice(&reg.to_le_bytes());
+    }
+    vendor
+}
+
+/// Processor brand string, held inline
+pub struct Brand {
+    bytes: [u8; 48],
+    len: usize,
+}
+
+impl Brand {
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("?")
+    }
+}
+
+/// Brand string, e.g. `Intel(R) Core(TM) i7-8700 CPU @ 3.20GHz`
+///
+/// Falls back to the vendor string on CPUs without the brand leaves.
+pub fn brand() -> Brand {
+    let mut bytes = [0; 48];
+    if unsafe { __cpuid(LEAF_MAX_EXTENDED) }.eax >= LEAF_BRAND[2] {
+        for (chunk, leaf) in bytes.chunks_mut(16).zip(LEAF_BRAND) {
+            let r = unsafe { __cpuid(leaf) };
+            for (dst, reg) in chunk.chunks_mut(4).zip([r.eax, r.ebx, r.ecx, r.edx]) {
+                dst.copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+    } else {
+        bytes[..12].copy_from_slice(&vendor());
+    }
+
+    // NUL-terminated, and often padded with leading spaces
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let start = bytes[..end].iter().position(|&b| b != b' ').unwrap_or(end);
+    bytes.copy_within(start..end, 0);
+    Brand { bytes, len: end - start }
+}
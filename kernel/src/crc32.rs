@@ -0,0 +1,15 @@
+/// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320)
+///
+/// Bitwise rather than table-driven to keep the kernel image small; this
+/// is only used on block-sized buffers where the cost doesn't matter.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,67 @@
+// Readable descriptions of exception error codes.
+//
+// Each wrapper implements `Display`, so handlers can drop the decode
+// straight into their panic message next to the raw value.
+
+use core::fmt;
+
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// Selector error code pushed by #GP (and #TS, #NP, #SS) as readable text,
+/// e.g. "IDT vector 0x80, external event"
+///
+/// Zero means the fault wasn't caused by loading a segment or gate.
+pub struct SelectorCause(pub u64);
+
+impl fmt::Display for SelectorCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return write!(f, "not segment related");
+        }
+        let index = (code >> 3) & 0x1FFF;
+        // Bit 1 set means the IDT whatever bit 2 says
+        match (code >> 1) & 0b11 {
+            0b00 => write!(f, "GDT entry {index} (selector {:#x})", index << 3)?,
+            0b10 => write!(f, "LDT entry {index} (selector {:#x})", index << 3 | 0b100)?,
+            _ => write!(f, "IDT vector {index:#x}")?,
+        }
+        if code & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Page fault error code bits as readable text, e.g.
+/// "write to a non-present page"
+pub struct PageFaultCause(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a present page (protection violation)"
+        } else {
+            "a non-present page"
+        };
+        write!(f, "{access} {page}")?;
+        for (flag, note) in [
+            (PageFaultErrorCode::MALFORMED_TABLE, "reserved bit set in a page table"),
+            (PageFaultErrorCode::PROTECTION_KEY, "protection key"),
+            (PageFaultErrorCode::SHADOW_STACK, "shadow stack"),
+        ] {
+            if code.contains(flag) {
+                write!(f, ", {note}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -390,3 +390,74 @@ pub static FONT_8X16: [u8; 128 * 16] = [
     0x00, 0x00, 0x00, 0x00, 0x10, 0x38, 0x6C, 0xC6,
     0xC6, 0xC6, 0xFE, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
+
+/// Largest glyph height a font may use
+pub const MAX_FONT_HEIGHT: usize = 32;
+
+/// Errors from validating font data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// Glyph height is zero or larger than `MAX_FONT_HEIGHT`
+    BadHeight,
+    /// The data is too short to hold every glyph at the given height
+    TooShort,
+}
+
+/// An 8-pixel-wide bitmap font, one byte per glyph row
+///
+/// Construction validates the data length, so rendering can index any
+/// glyph below `glyph_count` without bounds panics.
+#[derive(Clone, Copy)]
+pub struct Font {
+    data: &'static [u8],
+    height: usize,
+    glyph_count: usize,
+}
+
+impl Font {
+    /// Validate `data` as a font of `glyph_count` glyphs, `height` rows each
+    pub fn new(data: &'static [u8], height: usize, glyph_count: usize) -> Result<Self, FontError> {
+        if height == 0 || height > MAX_FONT_HEIGHT {
+            return Err(FontError::BadHeight);
+        }
+        if glyph_count == 0 || data.len() < glyph_count * height {
+            return Err(FontError::TooShort);
+        }
+        Ok(Font { data, height, glyph_count })
+    }
+
+    /// The compiled-in 8x16 font
+    pub const fn builtin() -> Self {
+        Font {
+            data: &FONT_8X16,
+            height: FONT_HEIGHT,
+            glyph_count: 128,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+
+    /// Bitmap rows for character `c`; out-of-range characters use glyph 0
+    pub fn glyph(&self, c: u8) -> &'static [u8] {
+        let idx = if (c as usize) < self.glyph_count { c as usize } else { 0 };
+        &self.data[idx * self.height..(idx + 1) * self.height]
+    }
+}
+
+/// Check `Font::new` accepts data of exactly the right size and rejects
+/// truncated data and out-of-range heights
+pub fn self_test() -> bool {
+    static DATA: [u8; 256 * FONT_HEIGHT] = [0; 256 * FONT_HEIGHT];
+    Font::new(&DATA, FONT_HEIGHT, 256).is_ok()
+        && Font::new(&DATA[..DATA.len() - 1], FONT_HEIGHT, 256).err() == Some(FontError::TooShort)
+        && Font::new(&DATA[..FONT_HEIGHT], FONT_HEIGHT, 256).err() == Some(FontError::TooShort)
+        && Font::new(&DATA, FONT_HEIGHT, 0).err() == Some(FontError::TooShort)
+        && Font::new(&DATA, 0, 256).err() == Some(FontError::BadHeight)
+        && Font::new(&DATA, MAX_FONT_HEIGHT + 1, 1).err() == Some(FontError::BadHeight)
+}
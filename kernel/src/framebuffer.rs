@@ -1,6 +1,16 @@
-use crate::font::{FONT_8X16, FONT_HEIGHT, FONT_WIDTH};
+use crate::font::{Font, FontError, FONT_HEIGHT, FONT_WIDTH};
+use crate::heap;
+use crate::memory;
+use crate::paging;
+use crate::tsc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cell::Cell;
 use core::fmt;
 use core::ptr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
 #[derive(Clone, Copy)]
@@ -16,8 +26,80 @@ impl Color {
     }
 }
 
+/// Default text colors, restored by SGR 0 (`ESC [ 0 m`)
+const DEFAULT_FG: Color = Color::new(0xCC, 0xCC, 0xCC); // light gray
+const DEFAULT_BG: Color = Color::new(0x00, 0x00, 0x00); // black
+
+/// The 16 ANSI colors: SGR 30-37/40-47, then the bright 90-97/100-107
+const PALETTE: [Color; 16] = [
+    Color::new(0x00, 0x00, 0x00),
+    Color::new(0xAA, 0x00, 0x00),
+    Color::new(0x00, 0xAA, 0x00),
+    Color::new(0xAA, 0x55, 0x00),
+    Color::new(0x00, 0x00, 0xAA),
+    Color::new(0xAA, 0x00, 0xAA),
+    Color::new(0x00, 0xAA, 0xAA),
+    Color::new(0xAA, 0xAA, 0xAA),
+    Color::new(0x55, 0x55, 0x55),
+    Color::new(0xFF, 0x55, 0x55),
+    Color::new(0x55, 0xFF, 0x55),
+    Color::new(0xFF, 0xFF, 0x55),
+    Color::new(0x55, 0x55, 0xFF),
+    Color::new(0xFF, 0x55, 0xFF),
+    Color::new(0x55, 0xFF, 0xFF),
+    Color::new(0xFF, 0xFF, 0xFF),
+];
+
+/// Names accepted by `color_by_name`, in ANSI order
+pub const COLOR_NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// Look up a named color
+///
+/// Everything but black comes from the bright half of the palette, which is
+/// easier to read on the console than the dim ANSI colors.
+pub fn color_by_name(name: &str) -> Option<Color> {
+    match COLOR_NAMES.iter().position(|&n| n == name)? {
+        0 => Some(PALETTE[0]),
+        i => Some(PALETTE[i + 8]),
+    }
+}
+
+/// The console's startup colors as `(fg, bg)`
+pub fn default_colors() -> (Color, Color) {
+    (DEFAULT_FG, DEFAULT_BG)
+}
+
+const MAX_CSI_PARAMS: usize = 8;
+
+/// Columns between tab stops
+const TAB_WIDTH: usize = 8;
+
+/// Cursor underline thickness in pixels, and half its blink period
+const CURSOR_HEIGHT: usize = 2;
+const CURSOR_BLINK_MS: u64 = 500;
+
+/// Progress through an escape sequence in the output stream
+enum Escape {
+    /// Plain text
+    Ground,
+    /// Seen ESC
+    Esc,
+    /// Inside `ESC [`, collecting numeric parameters
+    Csi {
+        params: [u16; MAX_CSI_PARAMS],
+        count: usize,
+    },
+}
+
 pub struct FramebufferWriter {
+    /// Where drawing goes: `vram`, or the back buffer when one is in use
     buffer: *mut u8,
+    /// The real framebuffer
+    vram: *mut u8,
+    /// Back buffer in RAM, if one could be mapped
+    back: Option<*mut u8>,
+    /// Pixel rectangle `(x0, y0, x1, y1)` drawn since the last `flush`
+    dirty: Cell<Option<(usize, usize, usize, usize)>>,
     width: usize,
     height: usize,
     pitch: usize,
@@ -25,33 +107,148 @@ pub struct FramebufferWriter {
     red_shift: u8,
     green_shift: u8,
     blue_shift: u8,
+    /// Bits per channel, for packing colors into narrow pixels
+    channel_bits: [u8; 3],
     col: usize,
     row: usize,
+    /// Set after writing the last column; the wrap happens on the next char
+    pending_wrap: bool,
     max_cols: usize,
     max_rows: usize,
     fg: Color,
     bg: Color,
+    escape: Escape,
+    font: Font,
+    write_bandwidth: Option<u64>,
+    paused_output: PausedOutput,
+    text: Option<TextGrid>,
+    /// Highlighted cell range (inclusive, as row-major cell indexes)
+    selection: Option<(usize, usize)>,
+    /// Cell under the mouse pointer, drawn inverted
+    pointer: Option<usize>,
+    /// Blink phase: whether the cursor should be showing
+    cursor_on: bool,
+    /// Where the cursor is drawn and the XOR mask it was drawn with
+    cursor_drawn: Option<(usize, usize, u32)>,
+    /// Rows that scrolled off the top, oldest first
+    scrollback: VecDeque<Vec<u8>>,
+    /// How many rows the view is scrolled back (0 shows the live screen)
+    view_offset: usize,
 }
 
+/// The characters currently on screen, so on-screen text can be selected
+///
+/// One byte per cell plus a per-row flag recording whether the row ran
+/// into the next one (a wrapped line rather than an explicit newline).
+/// Backed by fixed static storage; `cols * rows` must fit in it.
+struct TextGrid {
+    cells: &'static mut [u8; MAX_TEXT_CELLS],
+    wrapped: &'static mut [bool; MAX_TEXT_ROWS],
+    cols: usize,
+    rows: usize,
+}
+
+impl TextGrid {
+    fn fits(cols: usize, rows: usize) -> bool {
+        cols * rows <= MAX_TEXT_CELLS && rows <= MAX_TEXT_ROWS
+    }
+
+    fn row(&mut self, row: usize) -> &mut [u8] {
+        &mut self.cells[row * self.cols..(row + 1) * self.cols]
+    }
+
+    fn clear(&mut self) {
+        self.cells[..self.cols * self.rows].fill(b' ');
+        self.wrapped[..self.rows].fill(false);
+    }
+
+    fn scroll_up(&mut self) {
+        let (cols, rows) = (self.cols, self.rows);
+        self.cells.copy_within(cols..rows * cols, 0);
+        self.wrapped.copy_within(1..rows, 0);
+        self.row(rows - 1).fill(b' ');
+        self.wrapped[rows - 1] = false;
+    }
+}
+
+/// Rows kept in the scrollback buffer once the heap is up
+const SCROLLBACK_LINES: usize = 1000;
+
+const MAX_TEXT_ROWS: usize = 256;
+const MAX_TEXT_CELLS: usize = 64 * 1024;
+static mut TEXT_CELLS: [u8; MAX_TEXT_CELLS] = [b' '; MAX_TEXT_CELLS];
+static mut TEXT_WRAPPED: [bool; MAX_TEXT_ROWS] = [false; MAX_TEXT_ROWS];
+/// Set once a writer has claimed the grid storage above
+static TEXT_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// Bytes held back while output is paused (Scroll Lock)
+///
+/// Bounded: once full, further bytes are dropped and counted, and a
+/// marker reporting the loss is shown when output resumes. Blocking
+/// instead isn't an option, since writers may have interrupts disabled
+/// and the keypress that resumes output arrives by interrupt.
+struct PausedOutput {
+    buf: [u8; PAUSE_BUFFER_SIZE],
+    len: usize,
+    dropped: usize,
+}
+
+const PAUSE_BUFFER_SIZE: usize = 4096;
+
+/// Set while output is paused; see `set_paused`
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Pause or resume framebuffer output
+///
+/// While paused the screen is frozen and written bytes are buffered.
+/// Buffered output is rendered on the next write or `flush_paused` call
+/// after resuming.
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::Relaxed);
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Below this write bandwidth (MB/s) the framebuffer is probably mapped
+/// uncached rather than write-combining
+pub const SLOW_BANDWIDTH_MBPS: u64 = 200;
+
 unsafe impl Send for FramebufferWriter {}
 
+/// A framebuffer's address and pixel layout, as reported by the bootloader
+pub struct Mode {
+    pub buffer: *mut u8,
+    pub width: usize,
+    pub height: usize,
+    pub pitch: usize,
+    pub bpp: usize,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
 impl FramebufferWriter {
-    pub fn new(
-        buffer: *mut u8,
-        width: usize,
-        height: usize,
-        pitch: usize,
-        bpp: usize,
-        red_shift: u8,
-        green_shift: u8,
-        blue_shift: u8,
-    ) -> Self {
+    /// A writer drawing straight to the framebuffer, or into `back` (which
+    /// must hold `height * pitch` bytes) with changes copied over by
+    /// `flush`
+    pub fn new(mode: &Mode, back: Option<*mut u8>) -> Self {
+        let Mode { buffer, width, height, pitch, bpp, red_shift, green_shift, blue_shift } = *mode;
         let bytes_per_pixel = bpp / 8;
+        debug_assert!(
+            matches!(bytes_per_pixel, 2..=4),
+            "unsupported framebuffer depth: {bpp} bpp"
+        );
         let max_cols = width / FONT_WIDTH;
-        let max_rows = height / FONT_HEIGHT;
+        let font = Font::builtin();
+        let max_rows = height / font.height();
 
         let mut writer = FramebufferWriter {
-            buffer,
+            buffer: back.unwrap_or(buffer),
+            vram: buffer,
+            back,
+            dirty: Cell::new(None),
             width,
             height,
             pitch,
@@ -59,52 +256,305 @@ impl FramebufferWriter {
             red_shift,
             green_shift,
             blue_shift,
+            channel_bits: channel_bits(bytes_per_pixel, [red_shift, green_shift, blue_shift]),
             col: 0,
             row: 0,
+            pending_wrap: false,
             max_cols,
             max_rows,
-            fg: Color::new(0xCC, 0xCC, 0xCC), // light gray
-            bg: Color::new(0x00, 0x00, 0x00), // black
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            escape: Escape::Ground,
+            font,
+            write_bandwidth: None,
+            paused_output: PausedOutput {
+                buf: [0; PAUSE_BUFFER_SIZE],
+                len: 0,
+                dropped: 0,
+            },
+            text: None,
+            selection: None,
+            pointer: None,
+            cursor_on: true,
+            cursor_drawn: None,
+            scrollback: VecDeque::new(),
+            view_offset: 0,
         };
+        writer.claim_text_grid();
         writer.clear_screen();
         writer
     }
 
+    /// Take the static grid storage, unless another writer already has
+    fn claim_text_grid(&mut self) {
+        if TEXT_CLAIMED.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let (cells, wrapped) = unsafe {
+            (
+                &mut *core::ptr::addr_of_mut!(TEXT_CELLS),
+                &mut *core::ptr::addr_of_mut!(TEXT_WRAPPED),
+            )
+        };
+        self.text = Some(TextGrid {
+            cells,
+            wrapped,
+            cols: self.max_cols,
+            rows: self.max_rows,
+        });
+    }
+
+    /// The text grid, if the screen's layout fits in it
+    fn text_grid(&mut self) -> Option<&mut TextGrid> {
+        self.text
+            .as_mut()
+            .filter(|grid| TextGrid::fits(grid.cols, grid.rows))
+    }
+
     fn color_to_pixel(&self, color: Color) -> u32 {
-        ((color.r as u32) << self.red_shift)
-            | ((color.g as u32) << self.green_shift)
-            | ((color.b as u32) << self.blue_shift)
+        let [r, g, b] = self.channel_bits;
+        // Keep the top bits of each 8-bit component
+        let channel = |value: u8, bits: u8, shift: u8| ((value >> (8 - bits)) as u32) << shift;
+        channel(color.r, r, self.red_shift)
+            | channel(color.g, g, self.green_shift)
+            | channel(color.b, b, self.blue_shift)
     }
 
     fn put_pixel(&self, x: usize, y: usize, color: Color) {
         if x >= self.width || y >= self.height {
             return;
         }
+        self.store_pixel(x, y, self.color_to_pixel(color));
+    }
+
+    /// Invert the bits of a pixel selected by `mask`
+    fn xor_pixel(&self, x: usize, y: usize, mask: u32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        self.store_pixel(x, y, self.load_pixel(x, y) ^ mask);
+    }
+
+    /// Write a packed pixel value at the framebuffer's pixel width
+    fn store_pixel(&self, x: usize, y: usize, pixel: u32) {
         let offset = y * self.pitch + x * self.bytes_per_pixel;
-        let pixel = self.color_to_pixel(color);
         unsafe {
-            ptr::write_volatile(self.buffer.add(offset) as *mut u32, pixel);
+            let dst = self.buffer.add(offset);
+            match self.bytes_per_pixel {
+                2 => ptr::write_volatile(dst as *mut u16, pixel as u16),
+                3 => {
+                    // Little-endian, and not necessarily 4-byte aligned
+                    let [b0, b1, b2, _] = pixel.to_le_bytes();
+                    ptr::write_volatile(dst, b0);
+                    ptr::write_volatile(dst.add(1), b1);
+                    ptr::write_volatile(dst.add(2), b2);
+                }
+                _ => ptr::write_volatile(dst as *mut u32, pixel),
+            }
         }
     }
 
+    fn load_pixel(&self, x: usize, y: usize) -> u32 {
+        let offset = y * self.pitch + x * self.bytes_per_pixel;
+        unsafe {
+            let src = self.buffer.add(offset);
+            match self.bytes_per_pixel {
+                2 => ptr::read_volatile(src as *const u16) as u32,
+                3 => u32::from_le_bytes([
+                    ptr::read_volatile(src),
+                    ptr::read_volatile(src.add(1)),
+                    ptr::read_volatile(src.add(2)),
+                    0,
+                ]),
+                _ => ptr::read_volatile(src as *const u32),
+            }
+        }
+    }
+
+    // --- Drawing ---
+    //
+    // These work in pixels and are clipped to the screen. They leave the
+    // text cursor position and the text grid alone, so text written later
+    // lands where it would have anyway (and may draw over the shapes).
+
+    /// Fill a `w` x `h` rectangle with its top-left corner at (`x`, `y`)
+    pub fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: Color) {
+        let (x_end, y_end) = (x.saturating_add(w).min(self.width), y.saturating_add(h).min(self.height));
+        if x >= x_end || y >= y_end {
+            return;
+        }
+        self.hide_cursor();
+        let pixel = self.color_to_pixel(color);
+        for py in y..y_end {
+            for px in x..x_end {
+                self.store_pixel(px, py, pixel);
+            }
+        }
+        self.mark_dirty(x, y, x_end, y_end);
+        self.show_cursor();
+        self.flush();
+    }
+
+    /// Outline a `w` x `h` rectangle with its top-left corner at (`x`, `y`)
+    pub fn draw_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: Color) {
+        if w == 0 || h == 0 {
+            return;
+        }
+        self.fill_rect(x, y, w, 1, color);
+        self.fill_rect(x, y + h - 1, w, 1, color);
+        self.fill_rect(x, y, 1, h, color);
+        self.fill_rect(x + w - 1, y, 1, h, color);
+    }
+
+    /// Draw a line from (`x0`, `y0`) to (`x1`, `y1`) inclusive (Bresenham)
+    ///
+    /// Points off the screen are skipped, so lines may start or end there.
+    pub fn draw_line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, color: Color) {
+        self.hide_cursor();
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            if x >= 0 && y >= 0 {
+                // put_pixel clips against the far edges
+                self.put_pixel(x as usize, y as usize, color);
+                self.mark_dirty(x as usize, y as usize, x as usize + 1, y as usize + 1);
+            }
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+        self.show_cursor();
+        self.flush();
+    }
+
+    /// Paint the whole screen one color
+    ///
+    /// Unlike `clear_screen` this is purely graphical: the text grid and
+    /// cursor position are kept.
+    pub fn fill_screen(&mut self, color: Color) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
     fn render_char(&self, c: u8, col: usize, row: usize) {
-        let idx = (c as usize) & 0x7F;
-        let glyph = &FONT_8X16[idx * FONT_HEIGHT..(idx + 1) * FONT_HEIGHT];
+        self.draw_glyph(c, col, row, self.fg, self.bg);
+    }
+
+    fn draw_glyph(&self, c: u8, col: usize, row: usize, fg: Color, bg: Color) {
+        let glyph = self.font.glyph(c);
 
         let x0 = col * FONT_WIDTH;
-        let y0 = row * FONT_HEIGHT;
+        let y0 = row * self.font.height();
 
         for (dy, &bits) in glyph.iter().enumerate() {
             for dx in 0..FONT_WIDTH {
                 let on = (bits >> (7 - dx)) & 1 != 0;
-                let color = if on { self.fg } else { self.bg };
+                let color = if on { fg } else { bg };
                 self.put_pixel(x0 + dx, y0 + dy, color);
             }
         }
+        self.mark_dirty(x0, y0, x0 + FONT_WIDTH, y0 + glyph.len());
+    }
+
+    // --- Back buffer ---
+    //
+    // With a back buffer, drawing goes to RAM and `flush` copies the
+    // rectangle that changed to the framebuffer. That avoids slow reads
+    // of (uncached or write-combining) video memory when scrolling, and
+    // turns many small volatile writes into row copies. Every public
+    // method that draws ends with `flush`, so callers never see a stale
+    // screen.
+
+    /// Grow the dirty rectangle to cover `[x0, x1) x [y0, y1)`
+    fn mark_dirty(&self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        let (x1, y1) = (x1.min(self.width), y1.min(self.height));
+        let rect = match self.dirty.get() {
+            Some((a, b, c, d)) => (a.min(x0), b.min(y0), c.max(x1), d.max(y1)),
+            None => (x0, y0, x1, y1),
+        };
+        self.dirty.set(Some(rect));
+    }
+
+    /// Copy everything drawn since the last call to the framebuffer
+    pub fn flush(&self) {
+        let Some((x0, y0, x1, y1)) = self.dirty.take() else { return };
+        if self.buffer == self.vram || x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        let (start, len) = (x0 * self.bytes_per_pixel, (x1 - x0) * self.bytes_per_pixel);
+        for y in y0..y1 {
+            let offset = y * self.pitch + start;
+            unsafe { ptr::copy_nonoverlapping(self.buffer.add(offset), self.vram.add(offset), len) };
+        }
+    }
+
+    /// Whether drawing currently goes through the back buffer
+    pub fn back_buffer_enabled(&self) -> bool {
+        self.buffer != self.vram
+    }
+
+    /// Switch the back buffer on or off, returning whether it's now in use
+    ///
+    /// It can only be switched on if one was mapped at startup. The screen
+    /// contents carry over either way.
+    pub fn set_back_buffer(&mut self, enable: bool) -> bool {
+        let len = self.height * self.pitch;
+        match (enable, self.back) {
+            (true, Some(back)) if !self.back_buffer_enabled() => {
+                // One slow read of video memory to pick up the screen
+                unsafe { ptr::copy_nonoverlapping(self.vram, back, len) };
+                self.buffer = back;
+            }
+            (false, _) if self.back_buffer_enabled() => {
+                self.flush();
+                self.buffer = self.vram;
+            }
+            _ => {}
+        }
+        self.back_buffer_enabled()
+    }
+
+    /// Scroll the pixels up `count` text rows and redraw the text, returning
+    /// the microseconds per scroll
+    ///
+    /// Timed with the TSC (calibrated against the PIT at boot) since the
+    /// PIT tick count stands still with interrupts off. Text comes back in
+    /// the current colors.
+    pub fn time_scrolls(&mut self, count: u64) -> Option<u64> {
+        let ticks_per_ms = tsc::ticks_per_ms()?;
+        self.hide_cursor();
+        let start = tsc::read();
+        for _ in 0..count {
+            self.scroll_up();
+            self.flush();
+        }
+        unsafe { core::arch::x86_64::_mm_sfence() };
+        let elapsed = tsc::read() - start;
+        self.redraw_view();
+        self.show_cursor();
+        self.flush();
+        Some(elapsed * 1000 / ticks_per_ms / count.max(1))
+    }
+
+    /// Draw `c` at a cell and remember it in the text grid
+    fn put_char(&mut self, c: u8, col: usize, row: usize) {
+        self.render_char(c, col, row);
+        if let Some(grid) = self.text_grid() {
+            grid.row(row)[col] = c;
+        }
     }
 
     fn scroll_up(&self) {
-        let row_bytes = FONT_HEIGHT * self.pitch;
+        let row_bytes = self.font.height() * self.pitch;
         let total_rows = self.max_rows;
 
         unsafe {
@@ -118,42 +568,529 @@ impl FramebufferWriter {
             let last_row_start = self.buffer.add((total_rows - 1) * row_bytes);
             ptr::write_bytes(last_row_start, 0, row_bytes);
         }
+        self.mark_dirty(0, 0, self.width, total_rows * self.font.height());
+    }
+
+    fn scroll_text_up(&mut self) {
+        self.scroll_up();
+        let keep = heap::is_ready();
+        let Some(grid) = self.text_grid() else { return };
+        // Trailing blanks are implied, which keeps short lines cheap
+        let line = keep.then(|| grid.row(0).trim_ascii_end().to_vec());
+        grid.scroll_up();
+        if let Some(line) = line {
+            if self.scrollback.len() == SCROLLBACK_LINES {
+                self.scrollback.pop_front();
+            }
+            self.scrollback.push_back(line);
+        }
     }
 
     fn new_line(&mut self) {
         self.col = 0;
+        self.pending_wrap = false;
         if self.row + 1 < self.max_rows {
             self.row += 1;
         } else {
-            self.scroll_up();
+            self.scroll_text_up();
+        }
+        let row = self.row;
+        if let Some(grid) = self.text_grid() {
+            grid.wrapped[row] = false;
+        }
+    }
+
+    /// Buffer `byte` if output is paused; otherwise flush anything buffered
+    /// and return false so the caller renders it
+    fn hold_if_paused(&mut self, byte: u8) -> bool {
+        if is_paused() {
+            let held = &mut self.paused_output;
+            if held.len < held.buf.len() {
+                held.buf[held.len] = byte;
+                held.len += 1;
+            } else {
+                held.dropped += 1;
+            }
+            return true;
+        }
+        self.flush_paused();
+        false
+    }
+
+    /// Render output held back while paused, if output has since resumed
+    pub fn flush_paused(&mut self) {
+        if is_paused() || (self.paused_output.len == 0 && self.paused_output.dropped == 0) {
+            return;
+        }
+        let len = core::mem::take(&mut self.paused_output.len);
+        let dropped = core::mem::take(&mut self.paused_output.dropped);
+        self.snap_to_bottom();
+        self.hide_cursor();
+        for i in 0..len {
+            match self.paused_output.buf[i] {
+                8 => self.erase_char(),
+                byte => self.render_byte(byte),
+            }
+        }
+        self.show_cursor();
+        self.flush();
+        if dropped > 0 {
+            let _ = fmt::Write::write_fmt(self, format_args!("\n[{dropped} bytes dropped while paused]\n"));
         }
     }
 
     pub fn write_byte(&mut self, byte: u8) {
+        self.put_byte(byte);
+        self.flush();
+    }
+
+    /// `write_byte` without flushing, so a string reaches the screen at once
+    fn put_byte(&mut self, byte: u8) {
+        if !self.hold_if_paused(byte) {
+            self.snap_to_bottom();
+            self.hide_cursor();
+            self.render_byte(byte);
+            self.show_cursor();
+        }
+    }
+
+    fn render_byte(&mut self, byte: u8) {
+        // Plain text never enters the escape state machine
+        if byte == 0x1B || !matches!(self.escape, Escape::Ground) {
+            self.escape_byte(byte);
+            return;
+        }
+        self.clear_highlight();
         match byte {
             b'\n' => self.new_line(),
+            b'\r' => {
+                self.col = 0;
+                self.pending_wrap = false;
+            }
+            b'\t' => {
+                self.wrap_if_pending();
+                // Blank up to the next stop, so the gap shows the background
+                let stop = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                for col in self.col..stop.min(self.max_cols) {
+                    self.put_char(b' ', col, self.row);
+                }
+                if stop < self.max_cols {
+                    self.col = stop;
+                } else {
+                    // Past the last stop: continue on the next row
+                    self.pending_wrap = true;
+                    self.wrap_if_pending();
+                }
+            }
             byte => {
-                if self.col >= self.max_cols {
-                    self.new_line();
+                self.wrap_if_pending();
+                self.put_char(byte, self.col, self.row);
+                if self.col + 1 < self.max_cols {
+                    self.col += 1;
+                } else {
+                    self.pending_wrap = true;
+                }
+            }
+        }
+    }
+
+    /// Deferred wrap: the cursor stays on the last column until another
+    /// character actually needs the next row
+    fn wrap_if_pending(&mut self) {
+        if self.pending_wrap {
+            let row = self.row;
+            if let Some(grid) = self.text_grid() {
+                grid.wrapped[row] = true;
+            }
+            self.new_line();
+        }
+    }
+
+    // --- ANSI escape sequences ---
+
+    /// Feed one byte of an escape sequence
+    ///
+    /// CSI sequences for colors (SGR, `m`), cursor position (`H`/`f`),
+    /// cursor forward/back (`C`/`D`) and erase in line (`K`) are applied;
+    /// anything else is consumed silently.
+    fn escape_byte(&mut self, byte: u8) {
+        self.escape = match (core::mem::replace(&mut self.escape, Escape::Ground), byte) {
+            (_, 0x1B) => Escape::Esc,
+            (Escape::Esc, b'[') => Escape::Csi {
+                params: [0; MAX_CSI_PARAMS],
+                count: 0,
+            },
+            (Escape::Csi { mut params, count }, b'0'..=b'9') => {
+                let slot = &mut params[count.min(MAX_CSI_PARAMS - 1)];
+                *slot = slot.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                // A digit opens the first parameter
+                Escape::Csi { params, count: count.max(1) }
+            }
+            (Escape::Csi { params, count }, b';') => Escape::Csi {
+                params,
+                // An empty first parameter still counts
+                count: (count.max(1) + 1).min(MAX_CSI_PARAMS),
+            },
+            (Escape::Csi { params, count }, 0x40..=0x7E) => {
+                self.csi(byte, &params[..count]);
+                Escape::Ground
+            }
+            // Intermediate and private-marker bytes (e.g. '?') are ignored
+            (csi @ Escape::Csi { .. }, 0x20..=0x3F) => csi,
+            // Unknown or malformed sequence: drop it
+            _ => Escape::Ground,
+        };
+    }
+
+    /// Apply a complete CSI sequence with final byte `command`
+    fn csi(&mut self, command: u8, params: &[u16]) {
+        let param = |i: usize| params.get(i).copied().unwrap_or(0);
+        match command {
+            b'm' => {
+                if params.is_empty() {
+                    self.sgr(0);
                 }
-                self.render_char(byte, self.col, self.row);
-                self.col += 1;
+                for &p in params {
+                    self.sgr(p);
+                }
+            }
+            b'H' | b'f' => {
+                // 1-based, with 0 or missing meaning 1
+                let row = (param(0).max(1) as usize - 1).min(self.max_rows - 1);
+                let col = (param(1).max(1) as usize - 1).min(self.max_cols - 1);
+                self.row = row;
+                self.col = col;
+                self.pending_wrap = false;
             }
+            b'C' | b'D' => {
+                // Within the row only, like a terminal
+                let n = param(0).max(1) as usize;
+                self.col = match command {
+                    b'C' => (self.col + n).min(self.max_cols - 1),
+                    _ => self.col.saturating_sub(n),
+                };
+                self.pending_wrap = false;
+            }
+            b'K' => {
+                self.clear_highlight();
+                let (row, col) = (self.row, self.col);
+                let range = match param(0) {
+                    0 => col..self.max_cols,
+                    1 => 0..col + 1,
+                    2 => 0..self.max_cols,
+                    _ => return,
+                };
+                for c in range {
+                    self.put_char(b' ', c, row);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply one SGR (Select Graphic Rendition) parameter
+    fn sgr(&mut self, p: u16) {
+        match p {
+            0 => {
+                self.fg = DEFAULT_FG;
+                self.bg = DEFAULT_BG;
+            }
+            30..=37 => self.fg = PALETTE[(p - 30) as usize],
+            39 => self.fg = DEFAULT_FG,
+            40..=47 => self.bg = PALETTE[(p - 40) as usize],
+            49 => self.bg = DEFAULT_BG,
+            90..=97 => self.fg = PALETTE[(p - 90) as usize + 8],
+            100..=107 => self.bg = PALETTE[(p - 100) as usize + 8],
+            // Bold, underline and the rest have no rendering here
+            _ => {}
         }
     }
 
     pub fn backspace(&mut self) {
-        if self.col > 0 {
+        if !self.hold_if_paused(8) {
+            self.snap_to_bottom();
+            self.hide_cursor();
+            self.erase_char();
+            self.show_cursor();
+            self.flush();
+        }
+    }
+
+    // --- Cursor ---
+
+    /// Blink the cursor: call periodically with the uptime in milliseconds
+    pub fn tick(&mut self, now_ms: u64) {
+        let on = (now_ms / CURSOR_BLINK_MS) % 2 == 0;
+        if on != self.cursor_on {
+            self.cursor_on = on;
+            self.hide_cursor();
+            self.show_cursor();
+            self.flush();
+        }
+    }
+
+    /// Draw the cursor at (`col`, `row`) if it's in the visible blink phase
+    fn show_cursor(&mut self) {
+        let live = self.view_offset == 0;
+        if live && self.cursor_on && self.cursor_drawn.is_none() && self.row < self.max_rows {
+            let mask = self.color_to_pixel(self.fg) ^ self.color_to_pixel(self.bg);
+            self.cursor_drawn = Some((self.col, self.row, mask));
+            self.xor_underline(self.col, self.row, mask);
+        }
+    }
+
+    /// Erase the cursor, restoring the pixels underneath
+    ///
+    /// Must run before anything redraws or moves the cursor's cell, or the
+    /// XOR would land on different pixels.
+    fn hide_cursor(&mut self) {
+        if let Some((col, row, mask)) = self.cursor_drawn.take() {
+            self.xor_underline(col, row, mask);
+        }
+    }
+
+    /// XOR an underline into the bottom rows of a cell
+    ///
+    /// Drawing it twice with the same mask restores the glyph exactly.
+    fn xor_underline(&self, col: usize, row: usize, mask: u32) {
+        let height = self.font.height();
+        let y0 = row * height;
+        for y in y0 + height.saturating_sub(CURSOR_HEIGHT)..y0 + height {
+            for x in col * FONT_WIDTH..(col + 1) * FONT_WIDTH {
+                self.xor_pixel(x, y, mask);
+            }
+        }
+        self.mark_dirty(col * FONT_WIDTH, y0, (col + 1) * FONT_WIDTH, y0 + height);
+    }
+
+    fn erase_char(&mut self) {
+        self.clear_highlight();
+        if self.pending_wrap {
+            // The character just written at the last column is under the cursor
+            self.pending_wrap = false;
+            self.put_char(b' ', self.col, self.row);
+        } else if self.col > 0 {
             self.col -= 1;
-            self.render_char(b' ', self.col, self.row);
+            self.put_char(b' ', self.col, self.row);
         } else if self.row > 0 {
             self.row -= 1;
             self.col = self.max_cols - 1;
-            self.render_char(b' ', self.col, self.row);
+            self.put_char(b' ', self.col, self.row);
         }
         // At (0, 0): do nothing
     }
 
+    /// Install a new font, re-laying out the text grid and clearing the screen
+    ///
+    /// Rejected fonts leave the built-in font in place, so a bad load can
+    /// never make rendering panic.
+    pub fn set_font(&mut self, font: Result<Font, FontError>) -> Result<(), FontError> {
+        let result = font.map(|f| self.font = f);
+        if result.is_err() {
+            self.font = Font::builtin();
+        }
+        self.max_rows = self.height / self.font.height();
+        self.selection = None;
+        self.pointer = None;
+        if let Some(ref mut grid) = self.text {
+            grid.rows = self.max_rows;
+        }
+        self.clear_screen();
+        result
+    }
+
+    // --- Scrollback ---
+    //
+    // Rows scrolled off the top are kept as text (up to SCROLLBACK_LINES,
+    // once the heap exists), and the view can be moved back through them.
+    // Any output snaps the view back to the live screen first, and so does
+    // the shell on the next ordinary keypress. Only characters are kept, so
+    // redrawn rows come back in the current colors.
+
+    /// Move the view `lines` rows back in history (negative: forward)
+    pub fn scroll_view(&mut self, lines: isize) {
+        let offset = self
+            .view_offset
+            .saturating_add_signed(lines)
+            .min(self.scrollback.len());
+        if offset == self.view_offset || self.text_grid().is_none() {
+            return;
+        }
+        self.hide_cursor();
+        self.clear_highlight();
+        self.view_offset = offset;
+        self.redraw_view();
+        self.show_cursor();
+        self.flush();
+    }
+
+    /// Return the view to the live screen
+    pub fn snap_to_bottom(&mut self) {
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.redraw_view();
+            self.show_cursor();
+            self.flush();
+        }
+    }
+
+    /// Redraw every row for the current view offset
+    fn redraw_view(&self) {
+        let Some(ref grid) = self.text else { return };
+        let (cols, rows) = (self.max_cols, self.max_rows);
+        let history = self.scrollback.len();
+        for row in 0..rows {
+            // Index into the history rows followed by the live rows
+            let line = history - self.view_offset + row;
+            let text = match self.scrollback.get(line) {
+                Some(text) => text.as_slice(),
+                None => &grid.cells[(line - history) * cols..(line - history + 1) * cols],
+            };
+            for col in 0..cols {
+                let c = text.get(col).copied().unwrap_or(b' ');
+                self.draw_glyph(c, col, row, self.fg, self.bg);
+            }
+        }
+    }
+
+    // --- Text selection ---
+
+    /// Cell index under pixel (`x`, `y`), if on-screen text is tracked
+    pub fn cell_at(&mut self, x: usize, y: usize) -> Option<usize> {
+        let (cols, rows) = (self.max_cols, self.max_rows);
+        self.text_grid()?;
+        let (col, row) = (x / FONT_WIDTH, y / self.font.height());
+        (col < cols && row < rows).then_some(row * cols + col)
+    }
+
+    /// The highlighted selection, if any (cleared by any output)
+    pub fn selection(&self) -> Option<(usize, usize)> {
+        self.selection
+    }
+
+    fn is_highlighted(&self, index: usize) -> bool {
+        highlighted(self.selection, self.pointer, index)
+    }
+
+    /// Redraw one cell from the text grid, inverted if highlighted
+    fn render_cell(&self, index: usize) {
+        let Some(ref grid) = self.text else { return };
+        let (col, row) = (index % self.max_cols, index / self.max_cols);
+        let c = grid.cells[index];
+        if self.is_highlighted(index) {
+            self.draw_glyph(c, col, row, self.bg, self.fg);
+        } else {
+            self.draw_glyph(c, col, row, self.fg, self.bg);
+        }
+    }
+
+    /// Highlight the inclusive cell range `selection` and the pointer cell
+    ///
+    /// Only cells whose highlighting changes are redrawn. Any output to
+    /// the screen clears both, since scrolling would move the text out
+    /// from under them.
+    pub fn set_highlight(&mut self, selection: Option<(usize, usize)>, pointer: Option<usize>) {
+        // Cell indexes refer to the live screen, not the scrollback view
+        if self.text_grid().is_none() || self.view_offset != 0 {
+            return;
+        }
+        let cells = self.max_cols * self.max_rows;
+        let selection = selection.map(|(a, b)| (a.min(b), a.max(b).min(cells - 1)));
+        let pointer = pointer.filter(|&p| p < cells);
+
+        // Every cell whose state may change lies within this range
+        let ends = [self.selection, selection]
+            .into_iter()
+            .flatten()
+            .flat_map(|(a, b)| [a, b])
+            .chain([self.pointer, pointer].into_iter().flatten());
+        let (lo, hi) = ends.fold((usize::MAX, 0), |(lo, hi), i| (lo.min(i), hi.max(i)));
+        if lo > hi {
+            return;
+        }
+
+        let old_selection = core::mem::replace(&mut self.selection, selection);
+        let old_pointer = core::mem::replace(&mut self.pointer, pointer);
+        self.hide_cursor();
+        for index in lo..=hi {
+            if self.is_highlighted(index) != highlighted(old_selection, old_pointer, index) {
+                self.render_cell(index);
+            }
+        }
+        self.show_cursor();
+        self.flush();
+    }
+
+    fn clear_highlight(&mut self) {
+        if self.selection.is_some() || self.pointer.is_some() {
+            self.set_highlight(None, None);
+        }
+    }
+
+    /// Copy the selected text into `out`, returning the length used
+    ///
+    /// Trailing blanks on each row are dropped and rows are joined with
+    /// `\n`, except where a long line wrapped onto the next row.
+    pub fn selected_text(&self, out: &mut [u8]) -> usize {
+        let (Some(grid), Some((start, end))) = (self.text.as_ref(), self.selection) else {
+            return 0;
+        };
+        let cols = self.max_cols;
+        let mut len = 0;
+        for row in start / cols..=end / cols {
+            let from = if row == start / cols { start % cols } else { 0 };
+            let to = if row == end / cols { end % cols + 1 } else { cols };
+            let cells = &grid.cells[row * cols + from..row * cols + to];
+            let wraps = grid.wrapped[row] && row != end / cols;
+            let text = if wraps { cells } else { cells.trim_ascii_end() };
+            for &c in text {
+                if len < out.len() {
+                    out[len] = c;
+                    len += 1;
+                }
+            }
+            if !wraps && row != end / cols && len < out.len() {
+                out[len] = b'\n';
+                len += 1;
+            }
+        }
+        len
+    }
+
+    /// Current text colors as `(fg, bg)`
+    pub fn colors(&self) -> (Color, Color) {
+        (self.fg, self.bg)
+    }
+
+    /// Colors for text written from now on; what's on screen is unchanged
+    pub fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    pub fn set_fg(&mut self, fg: Color) {
+        self.fg = fg;
+    }
+
+    /// Background for glyphs drawn from now on; the rest of the screen keeps
+    /// its current background
+    pub fn set_bg(&mut self, bg: Color) {
+        self.bg = bg;
+    }
+
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    /// Virtual address and length in bytes of the pixel buffer
+    pub fn buffer_range(&self) -> (u64, u64) {
+        (self.vram as u64, (self.height * self.pitch) as u64)
+    }
+
+    pub fn bpp(&self) -> usize {
+        self.bytes_per_pixel * 8
+    }
+
     pub fn width(&self) -> usize {
         self.width
     }
@@ -170,40 +1107,193 @@ impl FramebufferWriter {
         self.max_rows
     }
 
+    /// Time one full-screen write and record the bandwidth in MB/s
+    ///
+    /// Needs a calibrated TSC; returns `None` otherwise. The write is the
+    /// same as `clear_screen`, so the screen ends up blank either way.
+    pub fn measure_write_bandwidth(&mut self) -> Option<u64> {
+        let ticks_per_ms = tsc::ticks_per_ms()?;
+        let total_bytes = (self.height * self.pitch) as u64;
+
+        let start = tsc::read();
+        self.clear_screen();
+        // Push any write-combining buffers out before stopping the clock
+        unsafe { core::arch::x86_64::_mm_sfence() };
+        let elapsed = (tsc::read() - start).max(1);
+
+        // bytes / (elapsed / ticks_per_ms) ms, scaled to MB/s
+        let mbps = total_bytes * ticks_per_ms * 1000 / elapsed / (1024 * 1024);
+        self.write_bandwidth = Some(mbps);
+        Some(mbps)
+    }
+
+    /// Write bandwidth measured at init, in MB/s
+    pub fn write_bandwidth(&self) -> Option<u64> {
+        self.write_bandwidth
+    }
+
+    /// Back to a clean console: default colors, no half-read escape
+    /// sequence, and an empty screen with the cursor at the top left
+    pub fn reset(&mut self) {
+        self.escape = Escape::Ground;
+        self.pending_wrap = false;
+        (self.fg, self.bg) = default_colors();
+        self.clear_screen();
+    }
+
     pub fn clear_screen(&mut self) {
         let total_bytes = self.height * self.pitch;
         unsafe {
             ptr::write_bytes(self.buffer, 0, total_bytes);
         }
+        // The wipe took the old cursor with it
+        self.cursor_drawn = None;
+        self.view_offset = 0;
         self.col = 0;
         self.row = 0;
+        self.pending_wrap = false;
+        self.selection = None;
+        self.pointer = None;
+        if let Some(grid) = self.text_grid() {
+            grid.clear();
+        }
+        self.mark_dirty(0, 0, self.width, self.height);
+        self.show_cursor();
+        self.flush();
     }
 }
 
+/// Whether a cell is drawn inverted: selected or under the pointer, but
+/// not both, so the pointer stays visible inside a selection
+fn highlighted(selection: Option<(usize, usize)>, pointer: Option<usize>, index: usize) -> bool {
+    let selected = selection.is_some_and(|(start, end)| (start..=end).contains(&index));
+    selected != (pointer == Some(index))
+}
+
 impl fmt::Write for FramebufferWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for byte in s.bytes() {
-            self.write_byte(byte);
+            self.put_byte(byte);
         }
+        self.flush();
         Ok(())
     }
 }
 
+/// Width of each color channel, from the gaps between the channel shifts
+///
+/// The bootloader's mask sizes aren't passed in, but the channels are packed
+/// next to each other, so each one runs up to the next shift (or the top of
+/// the pixel). That gives 5/6/5 for RGB565 and 8/8/8 for 24 and 32 bpp.
+fn channel_bits(bytes_per_pixel: usize, shifts: [u8; 3]) -> [u8; 3] {
+    let top = (bytes_per_pixel * 8) as u8;
+    shifts.map(|shift| {
+        let next = shifts.iter().copied().filter(|&s| s > shift).min().unwrap_or(top);
+        next.saturating_sub(shift).clamp(1, 8)
+    })
+}
+
 pub static FRAMEBUFFER: Mutex<Option<FramebufferWriter>> = Mutex::new(None);
 
-pub fn init(
-    buffer: *mut u8,
-    width: usize,
-    height: usize,
-    pitch: usize,
-    bpp: usize,
-    red_shift: u8,
-    green_shift: u8,
-    blue_shift: u8,
-) {
-    let writer = FramebufferWriter::new(
-        buffer, width, height, pitch, bpp,
-        red_shift, green_shift, blue_shift,
-    );
-    *FRAMEBUFFER.lock() = Some(writer);
+/// Most framebuffers `init_all` sets up
+pub const MAX_FRAMEBUFFERS: usize = 4;
+/// Displays beyond the first, which get no console output
+static SECONDARY: Mutex<[Option<FramebufferWriter>; MAX_FRAMEBUFFERS - 1]> =
+    Mutex::new([const { None }; MAX_FRAMEBUFFERS - 1]);
+
+/// Where the console's back buffer is mapped, after the heap
+const BACK_BUFFER_START: u64 = 0xffff_c100_0000_0000;
+/// Frames to leave free after mapping the back buffer
+const BACK_BUFFER_RESERVE_FRAMES: u64 = 1024;
+
+/// Map `bytes` of RAM for a back buffer, unless memory is tight
+fn map_back_buffer(bytes: usize) -> Option<*mut u8> {
+    let pages = bytes.div_ceil(4096) as u64;
+    let (allocated, total) = memory::frame_counts()?;
+    if total - allocated < pages + BACK_BUFFER_RESERVE_FRAMES {
+        return None;
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    paging::map_pages(VirtAddr::new(BACK_BUFFER_START), pages, flags).ok()?;
+    Some(BACK_BUFFER_START as *mut u8)
+}
+
+/// Set up a writer for each framebuffer, returning how many were set up
+///
+/// The first becomes the console in `FRAMEBUFFER`, with a back buffer if
+/// there's memory to spare; up to `MAX_FRAMEBUFFERS - 1` more are kept for
+/// `with_framebuffer`, and any beyond that are ignored.
+pub fn init_all(modes: impl Iterator<Item = Mode>) -> usize {
+    let mut count = 0;
+    for mode in modes.take(MAX_FRAMEBUFFERS) {
+        let back = if count == 0 { map_back_buffer(mode.height * mode.pitch) } else { None };
+        let writer = Some(FramebufferWriter::new(&mode, back));
+        match count {
+            0 => *FRAMEBUFFER.lock() = writer,
+            i => SECONDARY.lock()[i - 1] = writer,
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Number of framebuffers set up by `init_all`
+pub fn count() -> usize {
+    FRAMEBUFFER.lock().iter().count() + SECONDARY.lock().iter().flatten().count()
+}
+
+/// Run `f` on framebuffer `index` (0 is the console), if it exists
+pub fn with_framebuffer<R>(index: usize, f: impl FnOnce(&mut FramebufferWriter) -> R) -> Option<R> {
+    match index {
+        0 => FRAMEBUFFER.lock().as_mut().map(f),
+        i => SECONDARY.lock().get_mut(i - 1)?.as_mut().map(f),
+    }
+}
+
+/// Size of the off-screen writer `self_test` draws into
+const TEST_COLS: usize = 8;
+const TEST_ROWS: usize = 3;
+const TEST_PITCH: usize = TEST_COLS * FONT_WIDTH * 4;
+static mut TEST_PIXELS: [u8; TEST_PITCH * TEST_ROWS * FONT_HEIGHT] = [0; TEST_PITCH * TEST_ROWS * FONT_HEIGHT];
+
+/// Check the deferred wrap on a small off-screen writer
+///
+/// A full row of characters must leave the cursor on the last column, and
+/// whatever comes next (a character or a newline) must land at column 0
+/// of the following row, with no blank row in between.
+pub fn self_test() -> bool {
+    let mode = Mode {
+        buffer: core::ptr::addr_of_mut!(TEST_PIXELS).cast(),
+        width: TEST_COLS * FONT_WIDTH,
+        height: TEST_ROWS * FONT_HEIGHT,
+        pitch: TEST_PITCH,
+        bpp: 32,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+    let mut writer = FramebufferWriter::new(&mode, None);
+    let full_row = |writer: &mut FramebufferWriter| {
+        for _ in 0..writer.max_cols {
+            writer.render_byte(b'#');
+        }
+        writer.col == writer.max_cols - 1 && writer.pending_wrap
+    };
+
+    // A character after a full row starts the next one
+    let row = writer.row;
+    let char_ok = full_row(&mut writer) && {
+        writer.render_byte(b'x');
+        (writer.row, writer.col) == (row + 1, 1)
+    };
+
+    // So does a newline, rather than adding a blank row
+    writer.render_byte(b'\r');
+    let row = writer.row;
+    let newline_ok = full_row(&mut writer) && {
+        writer.render_byte(b'\n');
+        writer.render_byte(b'y');
+        (writer.row, writer.col) == (row + 1, 1)
+    };
+    char_ok && newline_ok && writer.max_cols == TEST_COLS
 }
@@ -1,40 +1,98 @@
+use core::ptr::{addr_of, addr_of_mut};
+
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::PageTableFlags;
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use crate::paging::{self, MapError};
 
+/// IST slot the double-fault handler switches to
+///
+/// Zero-based, as `set_stack_index` takes it; the CPU calls this IST1.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-static mut DOUBLE_FAULT_STACK: [u8; 8192] = [0; 8192];
+/// IST slot the page-fault handler switches to
+///
+/// A page fault raised by running off the end of a stack can't push its
+/// frame onto that same stack, so it gets one of its own.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+/// Size of each IST stack, in 4 KiB pages
+const IST_STACK_PAGES: u64 = 5;
+const IST_STACK_SIZE: usize = IST_STACK_PAGES as usize * 4096;
+
+/// Where `install_guarded_stacks` maps the IST stacks, past the paging
+/// self-test page
+///
+/// Each stack takes `IST_STACK_PAGES + 1` pages: an unmapped guard page
+/// followed by the stack itself.
+const GUARDED_STACKS_START: u64 = 0xffff_c300_0000_0000;
+
+/// Size of the ring-0 stack the CPU switches to when an interrupt or
+/// syscall arrives from ring 3
+const PRIVILEGE_STACK_SIZE: usize = 4 * 4096;
+
+#[repr(C, align(4096))]
+struct Stack<const N: usize>([u8; N]);
+
+/// Boot-time IST stacks, used until `install_guarded_stacks` replaces them
+///
+/// They sit in .bss with nothing below them, so an overflow silently
+/// corrupts whatever the linker put there.
+static mut DOUBLE_FAULT_STACK: Stack<IST_STACK_SIZE> = Stack([0; IST_STACK_SIZE]);
+static mut PAGE_FAULT_STACK: Stack<IST_STACK_SIZE> = Stack([0; IST_STACK_SIZE]);
+
+/// Loaded into RSP on ring 3 -> ring 0 transitions (TSS RSP0)
+static mut PRIVILEGE_STACK: Stack<PRIVILEGE_STACK_SIZE> = Stack([0; PRIVILEGE_STACK_SIZE]);
+
+/// The CPU reads IST entries from here on every switching interrupt, so
+/// they can be repointed after the TSS is loaded without reloading it.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            let stack_start = VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK);
-            stack_start + 8192u64
-        };
-        tss
-    };
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, data_selector, tss_selector })
+        let mut user_code_selector = gdt.append(Descriptor::user_code_segment());
+        let mut user_data_selector = gdt.append(Descriptor::user_data_segment());
+        user_code_selector.set_rpl(PrivilegeLevel::Ring3);
+        user_data_selector.set_rpl(PrivilegeLevel::Ring3);
+        // SAFETY: TSS is a static, so it outlives the GDT
+        let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(addr_of!(TSS)) });
+        let selectors = Selectors { code_selector, data_selector, user_code_selector, user_data_selector, tss_selector };
+        (gdt, selectors)
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// Point IST slot `index` at the stack ending (exclusive) at `top`
+fn set_ist(index: u16, top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = top;
+    });
+}
+
 pub fn init() {
     use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
     use x86_64::instructions::tables::load_tss;
 
+    set_ist(DOUBLE_FAULT_IST_INDEX, VirtAddr::from_ptr(addr_of!(DOUBLE_FAULT_STACK)) + IST_STACK_SIZE as u64);
+    set_ist(PAGE_FAULT_IST_INDEX, VirtAddr::from_ptr(addr_of!(PAGE_FAULT_STACK)) + IST_STACK_SIZE as u64);
+    unsafe {
+        (*addr_of_mut!(TSS)).privilege_stack_table[0] =
+            VirtAddr::from_ptr(addr_of!(PRIVILEGE_STACK)) + PRIVILEGE_STACK_SIZE as u64;
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
@@ -44,3 +102,26 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// The ring-3 code and data selectors, with RPL 3 already set
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// Move the IST stacks onto freshly mapped frames, each with an unmapped
+/// guard page below it
+///
+/// Overflowing one of them then page faults at a known address instead of
+/// scribbling over .bss. Needs the HHDM and the frame allocator. On error
+/// the stacks already moved stay moved and the rest keep their boot-time
+/// stacks, so the TSS always points at usable memory.
+pub fn install_guarded_stacks() -> Result<(), MapError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for (slot, index) in [DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX].into_iter().enumerate() {
+        let guard = GUARDED_STACKS_START + slot as u64 * (IST_STACK_PAGES + 1) * 4096;
+        let bottom = VirtAddr::new(guard + 4096);
+        paging::map_pages(bottom, IST_STACK_PAGES, flags)?;
+        set_ist(index, bottom + IST_STACK_SIZE as u64);
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+// Kernel heap.
+//
+// A fixed virtual range is backed with frames from the frame allocator and
+// handed to `linked_list_allocator`, so `alloc` collections work once
+// `init` has run. Interrupt handlers must not allocate: the heap lock
+// isn't interrupt-safe.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use linked_list_allocator::LockedHeap;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::paging::{self, MapError};
+
+/// Start of the heap, in an otherwise unused part of the higher half
+pub const HEAP_START: u64 = 0xffff_c000_0000_0000;
+pub const HEAP_SIZE: usize = 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Map the heap and hand it to the allocator
+pub fn init() -> Result<(), MapError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    paging::map_pages(VirtAddr::new(HEAP_START), HEAP_SIZE as u64 / 4096, flags)?;
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+    }
+    READY.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Whether allocations can succeed; without a heap they panic
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// `(used, free)` heap bytes
+pub fn usage() -> (usize, usize) {
+    let heap = ALLOCATOR.lock();
+    (heap.used(), heap.free())
+}
@@ -0,0 +1,177 @@
+// High Precision Event Timer.
+//
+// Located through the ACPI "HPET" table. The register block is MMIO,
+// which the HHDM doesn't necessarily cover, so it gets an uncached
+// mapping of its own.
+// The main counter runs at a fixed rate (typically 10-25 MHz) and serves as
+// a high-resolution monotonic clock. Timer 0 can replace PIT channel 0 as
+// the IRQ0 source via legacy replacement routing, so the existing IRQ0
+// handler and everything built on it keep working unchanged.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi;
+use crate::memory;
+use crate::paging::{self, MapError};
+
+/// Where `init` maps the register block, past the guarded IST stacks
+const REGS_PAGE: u64 = 0xffff_c400_0000_0000;
+
+// Register offsets
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_COUNTER: usize = 0x0F0;
+const REG_T0_CONFIG: usize = 0x100;
+const REG_T0_COMPARATOR: usize = 0x108;
+
+// General configuration bits
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+// Timer configuration/capability bits
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VAL_SET: u64 = 1 << 6;
+
+/// Longest counter period the spec allows, in femtoseconds (100 ns)
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// ACPI has no HPET table
+    NotPresent,
+    /// The HPET table's address field isn't mapped in the HHDM
+    NotMapped,
+    /// The register block couldn't be mapped
+    Map(MapError),
+    /// The reported counter period is outside the spec's range
+    BadPeriod,
+    /// Timer 0 can't run in periodic mode
+    NoPeriodicTimer,
+}
+
+/// Virtual address of the register block (0 until `init`)
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Counter period in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn read_reg(base: u64, offset: usize) -> u64 {
+    unsafe { ptr::read_volatile((base as usize + offset) as *const u64) }
+}
+
+fn write_reg(base: u64, offset: usize, value: u64) {
+    unsafe { ptr::write_volatile((base as usize + offset) as *mut u64, value) }
+}
+
+fn base() -> Option<u64> {
+    match BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(base),
+    }
+}
+
+/// Find the HPET and start its main counter
+///
+/// Returns the counter frequency in Hz.
+pub fn init() -> Result<u64, HpetError> {
+    let table = acpi::find_table(b"HPET").ok_or(HpetError::NotPresent)?;
+    // Discovery only checked the header is mapped
+    acpi::check_mapped(table.as_u64() + 44, 8).map_err(|_| HpetError::NotMapped)?;
+    // The base address is the 64-bit field of the Generic Address Structure
+    // that follows the 36-byte header and 4-byte block ID
+    let addr_ptr = memory::phys_to_virt(table + 44u64).as_ptr::<u64>();
+    let phys = PhysAddr::new(unsafe { ptr::read_unaligned(addr_ptr) });
+
+    // The block is 1 KiB, so it never straddles a page
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE;
+    paging::map_page(VirtAddr::new(REGS_PAGE), phys, flags).map_err(HpetError::Map)?;
+    let base = REGS_PAGE + (phys.as_u64() & 0xfff);
+
+    let period = read_reg(base, REG_CAPABILITIES) >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        return Err(HpetError::BadPeriod);
+    }
+
+    let config = read_reg(base, REG_CONFIG);
+    write_reg(base, REG_CONFIG, config | CONFIG_ENABLE);
+
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    BASE.store(base, Ordering::Relaxed);
+    Ok(FS_PER_SEC / period)
+}
+
+/// Counter frequency in Hz, or `None` if there's no HPET
+pub fn frequency() -> Option<u64> {
+    base()?;
+    Some(FS_PER_SEC / PERIOD_FS.load(Ordering::Relaxed))
+}
+
+/// Counter period in femtoseconds, or `None` if there's no HPET
+pub fn period_fs() -> Option<u64> {
+    base()?;
+    Some(PERIOD_FS.load(Ordering::Relaxed))
+}
+
+/// Current main counter value, or `None` if there's no HPET
+pub fn counter() -> Option<u64> {
+    Some(read_reg(base()?, REG_COUNTER))
+}
+
+/// Number of comparators the HPET implements
+pub fn timer_count() -> Option<u8> {
+    let caps = read_reg(base()?, REG_CAPABILITIES);
+    Some(((caps >> 8) & 0x1F) as u8 + 1)
+}
+
+/// Busy-wait for `us` microseconds on the main counter
+///
+/// Returns `false` without waiting if there's no HPET.
+pub fn sleep_us(us: u64) -> bool {
+    let (Some(start), Some(freq)) = (counter(), frequency()) else {
+        return false;
+    };
+    let ticks = us * freq / 1_000_000;
+    while counter().unwrap_or(u64::MAX).wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Drive IRQ0 from timer 0 in periodic mode at `hz` instead of the PIT
+///
+/// Enables legacy replacement routing, which disconnects PIT channel 0 from
+/// IRQ0. Returns the actual rate after rounding to whole counter ticks.
+pub fn start_periodic(hz: u32) -> Result<u32, HpetError> {
+    let base = base().ok_or(HpetError::NotPresent)?;
+    let timer_config = read_reg(base, REG_T0_CONFIG);
+    if timer_config & TIMER_PERIODIC_CAP == 0 {
+        return Err(HpetError::NoPeriodicTimer);
+    }
+    let freq = FS_PER_SEC / PERIOD_FS.load(Ordering::Relaxed);
+    let ticks = (freq / hz as u64).max(1);
+
+    // Stop and reset the counter so the first comparator match is one
+    // full period away
+    let config = read_reg(base, REG_CONFIG);
+    write_reg(base, REG_CONFIG, config & !CONFIG_ENABLE);
+    write_reg(base, REG_COUNTER, 0);
+
+    // With VAL_SET, the first write sets the comparator and the second the
+    // periodic reload value
+    write_reg(
+        base,
+        REG_T0_CONFIG,
+        timer_config | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VAL_SET,
+    );
+    write_reg(base, REG_T0_COMPARATOR, ticks);
+    write_reg(base, REG_T0_COMPARATOR, ticks);
+
+    write_reg(base, REG_CONFIG, config | CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    Ok((freq / ticks) as u32)
+}
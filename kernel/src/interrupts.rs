@@ -1,10 +1,14 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
 
+use crate::backtrace;
+use crate::exceptions::{PageFaultCause, SelectorCause};
 use crate::gdt;
-use crate::keyboard;
 use crate::pic;
+use crate::serial;
+use crate::syscall;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -17,38 +21,239 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt.general_protection_fault.set_handler_fn(gpf_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[32].set_handler_fn(timer_handler);
-        idt[33].set_handler_fn(keyboard_handler);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
+        install_fatal_handlers(&mut idt);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        unsafe {
+            idt[syscall::SYSCALL_VECTOR]
+                .set_handler_addr(VirtAddr::new(syscall::entry_address()))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+        for (irq, &trampoline) in IRQ_TRAMPOLINES.iter().enumerate() {
+            idt[pic::PIC1_OFFSET + irq as u8].set_handler_fn(trampoline);
+        }
         idt
     };
 }
 
+// --- IRQ dispatch ---
+
+/// A device driver's handler for one hardware IRQ
+///
+/// Runs in interrupt context with interrupts disabled; the EOI is sent by
+/// the dispatcher afterwards, so handlers must not send one themselves.
+pub type IrqHandler = fn();
+
+/// Registered handlers, stored as function pointer addresses (0 = none)
+static IRQ_HANDLERS: [AtomicUsize; 16] = [const { AtomicUsize::new(0) }; 16];
+
+/// Interrupts that arrived on IRQs with no registered handler
+static UNCLAIMED: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// Spurious interrupts seen on IRQ7 and IRQ15
+static SPURIOUS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+
+/// Install `handler` for hardware IRQ `irq` (0-15), replacing any previous one
+///
+/// The IRQ still has to be unmasked at the PIC before it will fire.
+pub fn set_irq_handler(irq: u8, handler: IrqHandler) {
+    assert!(irq < 16, "IRQ {irq} out of range");
+    IRQ_HANDLERS[irq as usize].store(handler as usize, Ordering::Release);
+}
+
+/// Whether a handler is registered for `irq`
+pub fn irq_claimed(irq: u8) -> bool {
+    IRQ_HANDLERS[irq as usize].load(Ordering::Relaxed) != 0
+}
+
+/// Number of interrupts seen on `irq` with no handler registered
+pub fn unclaimed_count(irq: u8) -> u64 {
+    UNCLAIMED[irq as usize].load(Ordering::Relaxed)
+}
+
+/// Number of spurious interrupts seen on `irq` (only IRQ7 and IRQ15 can
+/// be spurious)
+pub fn spurious_count(irq: u8) -> u64 {
+    match irq {
+        7 => SPURIOUS[0].load(Ordering::Relaxed),
+        15 => SPURIOUS[1].load(Ordering::Relaxed),
+        _ => 0,
+    }
+}
+
+/// Whether an IRQ7 or IRQ15 is spurious, sending any EOI it still needs
+///
+/// A PIC raises its lowest-priority line when a request goes away before
+/// the CPU acknowledges it, without setting that line's ISR bit. A spurious
+/// IRQ7 must not be EOI'd at all, since that could ack a real interrupt in
+/// service. A spurious IRQ15 still arrived through the master's cascade
+/// line, so the master (only) needs its EOI.
+fn is_spurious(irq: u8) -> bool {
+    let (bit, counter) = match irq {
+        7 => (1 << 7, &SPURIOUS[0]),
+        15 => (1 << 15, &SPURIOUS[1]),
+        _ => return false,
+    };
+    if pic::read_isr() & bit != 0 {
+        return false;
+    }
+    counter.fetch_add(1, Ordering::Relaxed);
+    if irq == 15 {
+        pic::send_eoi(pic::PIC1_OFFSET);
+    }
+    true
+}
+
+fn dispatch_irq(irq: u8) {
+    if is_spurious(irq) {
+        return;
+    }
+    let addr = IRQ_HANDLERS[irq as usize].load(Ordering::Acquire);
+    if addr != 0 {
+        let handler: IrqHandler = unsafe { core::mem::transmute(addr) };
+        handler();
+    } else if UNCLAIMED[irq as usize].fetch_add(1, Ordering::Relaxed) == 0 {
+        // Log only the first occurrence so a stuck line can't flood serial
+        use core::fmt::Write;
+        if let Some(mut serial) = serial::SERIAL.try_lock() {
+            let _ = writeln!(serial, "[!] Unclaimed IRQ {irq}");
+        }
+    }
+    pic::send_eoi(pic::PIC1_OFFSET + irq);
+}
+
+macro_rules! irq_trampolines {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        static IRQ_TRAMPOLINES: [extern "x86-interrupt" fn(InterruptStackFrame); 16] = [$($name),*];
+    };
+}
+
+irq_trampolines! {
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3,
+    irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11,
+    irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
+}
+
 pub fn init() {
     IDT.load();
 }
 
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
     panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
 }
 
+/// Handlers for the exceptions nothing recovers from yet: each records
+/// where it happened and panics with the vector's name, so an unexpected
+/// fault names itself instead of escalating to a double fault
+///
+/// Entries are grouped by the error code the CPU pushes: none, an opaque
+/// code, or a segment selector error code.
+macro_rules! fatal_exceptions {
+    (
+        plain { $($plain:ident: $plain_fn:ident => $plain_name:literal),* $(,)? }
+        code { $($code:ident: $code_fn:ident => $code_name:literal),* $(,)? }
+        selector { $($sel:ident: $sel_fn:ident => $sel_name:literal),* $(,)? }
+    ) => {
+        $(
+            extern "x86-interrupt" fn $plain_fn(stack_frame: InterruptStackFrame) {
+                backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
+                panic!(concat!("EXCEPTION: ", $plain_name, "\n{:#?}"), stack_frame);
+            }
+        )*
+        $(
+            extern "x86-interrupt" fn $code_fn(stack_frame: InterruptStackFrame, error_code: u64) {
+                backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
+                panic!(concat!("EXCEPTION: ", $code_name, " (error_code={:#x})\n{:#?}"), error_code, stack_frame);
+            }
+        )*
+        $(
+            extern "x86-interrupt" fn $sel_fn(stack_frame: InterruptStackFrame, error_code: u64) {
+                backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
+                panic!(
+                    concat!("EXCEPTION: ", $sel_name, "\nCause:       {}\nError code:  {:#x}\n{:#?}"),
+                    SelectorCause(error_code),
+                    error_code,
+                    stack_frame
+                );
+            }
+        )*
+
+        fn install_fatal_handlers(idt: &mut InterruptDescriptorTable) {
+            $(idt.$plain.set_handler_fn($plain_fn);)*
+            $(idt.$code.set_handler_fn($code_fn);)*
+            $(idt.$sel.set_handler_fn($sel_fn);)*
+        }
+    };
+}
+
+fatal_exceptions! {
+    plain {
+        debug: debug_handler => "DEBUG (#DB)",
+        non_maskable_interrupt: nmi_handler => "NON-MASKABLE INTERRUPT",
+        overflow: overflow_handler => "OVERFLOW (#OF)",
+        bound_range_exceeded: bound_range_handler => "BOUND RANGE EXCEEDED (#BR)",
+        invalid_opcode: invalid_opcode_handler => "INVALID OPCODE (#UD)",
+        device_not_available: device_not_available_handler => "DEVICE NOT AVAILABLE (#NM)",
+        x87_floating_point: x87_floating_point_handler => "X87 FLOATING POINT (#MF)",
+        simd_floating_point: simd_floating_point_handler => "SIMD FLOATING POINT (#XM)",
+        virtualization: virtualization_handler => "VIRTUALIZATION (#VE)",
+        hv_injection_exception: hv_injection_handler => "HYPERVISOR INJECTION (#HV)",
+    }
+    code {
+        alignment_check: alignment_check_handler => "ALIGNMENT CHECK (#AC)",
+        cp_protection_exception: cp_protection_handler => "CONTROL PROTECTION (#CP)",
+        vmm_communication_exception: vmm_communication_handler => "VMM COMMUNICATION (#VC)",
+        security_exception: security_handler => "SECURITY (#SX)",
+    }
+    selector {
+        invalid_tss: invalid_tss_handler => "INVALID TSS (#TS)",
+        segment_not_present: segment_not_present_handler => "SEGMENT NOT PRESENT (#NP)",
+        stack_segment_fault: stack_segment_handler => "STACK SEGMENT FAULT (#SS)",
+    }
+}
+
+/// #MC can't return, so it doesn't fit `fatal_exceptions!`
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
+    panic!("EXCEPTION: MACHINE CHECK (#MC)\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     use core::fmt::Write;
     let mut serial = crate::serial::SERIAL.lock();
     let _ = writeln!(serial, "EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    let rbp = backtrace::interrupted_frame_pointer();
+    backtrace::write_trace(&mut *serial, Some(stack_frame.instruction_pointer.as_u64()), rbp);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn gpf_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
     panic!(
-        "EXCEPTION: GENERAL PROTECTION FAULT (error_code={})\n{:#?}",
-        error_code, stack_frame
+        "EXCEPTION: GENERAL PROTECTION FAULT\n\
+         Cause:       {}\n\
+         Error code:  {error_code:#x}\n\
+         {stack_frame:#?}",
+        SelectorCause(error_code),
     );
 }
 
@@ -57,20 +262,24 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
+    // Nothing can be paged in yet, so every fault ends here; the origin is
+    // still worth reporting, since user-mode faults will be recoverable
+    // once there is a user space
+    let origin = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        "user mode"
+    } else {
+        "kernel"
+    };
+    let address = Cr2::read().map_or(0, |addr| addr.as_u64());
+    backtrace::record_exception(stack_frame.instruction_pointer.as_u64());
     panic!(
-        "EXCEPTION: PAGE FAULT\nAccessed address: {:?}\nError code: {:?}\n{:#?}",
-        Cr2::read(),
-        error_code,
-        stack_frame
+        "EXCEPTION: PAGE FAULT in {origin}\n\
+         Address:     {address:#018x}\n\
+         Access:      {}\n\
+         Instruction: {:#018x}\n\
+         Error code:  {:#x}",
+        PageFaultCause(error_code),
+        stack_frame.instruction_pointer.as_u64(),
+        error_code.bits(),
     );
 }
-
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    pic::send_eoi(32);
-}
-
-extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
-    let scancode: u8 = unsafe { Port::new(0x60).read() };
-    keyboard::handle_scancode(scancode);
-    pic::send_eoi(33);
-}
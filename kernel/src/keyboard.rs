@@ -1,5 +1,13 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
 use spin::Mutex;
 
+use crate::framebuffer;
+use crate::pit;
+use crate::serial;
+use crate::tsc;
+
 pub struct KeyBuffer {
     buf: [u8; 256],
     read_pos: usize,
@@ -8,7 +16,7 @@ pub struct KeyBuffer {
 }
 
 impl KeyBuffer {
-    const fn new() -> Self {
+    pub const fn new() -> Self {
         KeyBuffer {
             buf: [0; 256],
             read_pos: 0,
@@ -25,6 +33,14 @@ impl KeyBuffer {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count == 256
+    }
+
     pub fn pop(&mut self) -> Option<u8> {
         if self.count == 0 {
             return None;
@@ -38,11 +54,276 @@ impl KeyBuffer {
 
 pub static KEY_BUFFER: Mutex<KeyBuffer> = Mutex::new(KeyBuffer::new());
 
-static mut SHIFT_HELD: bool = false;
+// Navigation keys have no ASCII code, so they're delivered as bytes from
+// the otherwise unused 0x80-0xFF range
+pub const KEY_UP: u8 = 0x80;
+pub const KEY_DOWN: u8 = 0x81;
+pub const KEY_LEFT: u8 = 0x82;
+pub const KEY_RIGHT: u8 = 0x83;
+pub const KEY_HOME: u8 = 0x84;
+pub const KEY_END: u8 = 0x85;
+pub const KEY_DELETE: u8 = 0x86;
+pub const KEY_PAGE_UP: u8 = 0x87;
+pub const KEY_PAGE_DOWN: u8 = 0x88;
+/// Ctrl+Shift+U: start entering a codepoint in hex
+pub const KEY_HEX_INPUT: u8 = 0x89;
+/// Shift+Page Up / Shift+Page Down: move the scrollback view
+pub const KEY_SCROLL_UP: u8 = 0x8A;
+pub const KEY_SCROLL_DOWN: u8 = 0x8B;
+
+// --- Stuck-key / scancode storm detection ---
+
+/// More repeats of one make code than this within the window is a storm.
+/// Typematic repeat tops out around 30/s, so this leaves ample headroom.
+const STORM_THRESHOLD: u32 = 64;
+const STORM_WINDOW_MS: u64 = 500;
+
+struct StormDetector {
+    last_key: u8,
+    repeats: u32,
+    window_start: u64,
+    suppressing: bool,
+}
+
+impl StormDetector {
+    const fn new() -> Self {
+        StormDetector {
+            last_key: 0,
+            repeats: 0,
+            window_start: 0,
+            suppressing: false,
+        }
+    }
+
+    /// Record a make code; returns false if it should be dropped
+    fn on_make(&mut self, key: u8) -> bool {
+        if self.suppressing && key == self.last_key {
+            return false;
+        }
+
+        let now = tsc::read();
+        let window = tsc::ticks_per_ms().unwrap_or(0) * STORM_WINDOW_MS;
+        if key != self.last_key || now.wrapping_sub(self.window_start) > window {
+            self.last_key = key;
+            self.repeats = 0;
+            self.window_start = now;
+            self.suppressing = false;
+        }
+
+        self.repeats += 1;
+        if self.repeats > STORM_THRESHOLD {
+            self.suppressing = true;
+            STORMS.fetch_add(1, Ordering::Relaxed);
+            if let Some(mut serial) = serial::SERIAL.try_lock() {
+                let _ = writeln!(serial, "[!] Keyboard: scancode {key:#04x} repeating too fast, suppressing until release");
+            }
+            return false;
+        }
+        true
+    }
+
+    fn on_release(&mut self, key: u8) {
+        if key == self.last_key {
+            self.repeats = 0;
+            self.suppressing = false;
+        }
+    }
+}
+
+static STORM: Mutex<StormDetector> = Mutex::new(StormDetector::new());
+static STORMS: AtomicU64 = AtomicU64::new(0);
+static SUPPRESSED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of storms detected and scancodes dropped because of them
+pub fn storm_stats() -> (u64, u64) {
+    (STORMS.load(Ordering::Relaxed), SUPPRESSED.load(Ordering::Relaxed))
+}
+
+// --- Modifier keys ---
+
+/// Modifier key state, one bit per modifier
+///
+/// Written only by the IRQ1 handler but readable from anywhere, so it's a
+/// lock-free atomic bitfield rather than a `static mut`.
+pub struct Modifiers {
+    bits: AtomicU8,
+}
+
+impl Modifiers {
+    pub const SHIFT: u8 = 1 << 0;
+    pub const CTRL: u8 = 1 << 1;
+
+    const fn new() -> Self {
+        Modifiers {
+            bits: AtomicU8::new(0),
+        }
+    }
+
+    fn set(&self, modifier: u8, held: bool) {
+        if held {
+            self.bits.fetch_or(modifier, Ordering::Relaxed);
+        } else {
+            self.bits.fetch_and(!modifier, Ordering::Relaxed);
+        }
+    }
+
+    pub fn held(&self, modifier: u8) -> bool {
+        self.bits.load(Ordering::Relaxed) & modifier != 0
+    }
+}
+
+pub static MODIFIERS: Modifiers = Modifiers::new();
 
-// Scancode set 1 -> ASCII (unshifted)
+// --- Lock keys and LEDs ---
+
+/// LED bits for the keyboard's "set LEDs" command (0xED)
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Current lock-key state, using the LED bit layout
+static LOCK_STATE: AtomicU8 = AtomicU8::new(0);
+
+/// Lock keys currently held down, using the LED bit layout
+static LOCKS_HELD: AtomicU8 = AtomicU8::new(0);
+
+/// Scancodes that arrived while `wait_ack` was polling for an ACK, replayed
+/// by `handle_irq` once the LED update is done
+static STRAY: Mutex<KeyBuffer> = Mutex::new(KeyBuffer::new());
+
+fn wait_input_empty() {
+    // Bit 1 of the status port: controller input buffer still full
+    let mut status = Port::<u8>::new(0x64);
+    for _ in 0..100_000 {
+        if unsafe { status.read() } & 0x02 == 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for the keyboard to acknowledge a command byte
+///
+/// Called from the IRQ1 handler with interrupts disabled, so the ACK has
+/// to be polled rather than arriving as another interrupt. Gives up after
+/// a bounded wait; any late ACK is discarded by `handle_scancode`. Keys
+/// pressed meanwhile are set aside in `STRAY` rather than lost.
+fn wait_ack() -> bool {
+    let mut status = Port::<u8>::new(0x64);
+    let mut data = Port::<u8>::new(0x60);
+    for _ in 0..100_000 {
+        // Bit 0: output buffer full; bit 5: the byte is from the mouse
+        let st = unsafe { status.read() };
+        if st & 0x21 == 0x01 {
+            match unsafe { data.read() } {
+                0xFA => return true,
+                // Resend: the keyboard rejected the byte
+                0xFE => return false,
+                byte => STRAY.lock().push(byte),
+            }
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Send the "set LEDs" command, waiting for the ACK to 0xED before
+/// sending the LED mask, as the keyboard ignores a too-early second byte
+fn set_leds(leds: u8) {
+    let mut data = Port::<u8>::new(0x60);
+    wait_input_empty();
+    unsafe { data.write(0xEDu8) };
+    if !wait_ack() {
+        return;
+    }
+    wait_input_empty();
+    unsafe { data.write(leds) };
+    wait_ack();
+}
+
+/// Flip a lock key's state and update the LEDs; returns the new state
+fn toggle_lock(led: u8) -> bool {
+    let state = LOCK_STATE.fetch_xor(led, Ordering::Relaxed) ^ led;
+    set_leds(state);
+    state & led != 0
+}
+
+/// Handle a lock key's make or break code
+///
+/// Only the first make code toggles the lock: the keyboard's typematic
+/// repeats while the key is held are ignored. Returns the new state if it
+/// toggled.
+fn lock_key(led: u8, is_release: bool) -> Option<bool> {
+    if is_release {
+        LOCKS_HELD.fetch_and(!led, Ordering::Relaxed);
+        return None;
+    }
+    if LOCKS_HELD.fetch_or(led, Ordering::Relaxed) & led != 0 {
+        return None;
+    }
+    Some(toggle_lock(led))
+}
+
+/// Set after a 0xE0 prefix byte; the next scancode is an extended key
+static EXTENDED_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// An extended (0xE0-prefixed) key that produces a character
+pub struct ExtendedKey {
+    pub scancode: u8,
+    pub ascii: u8,
+    pub name: &'static str,
+}
+
+/// Extended keys that map to characters or reserved key codes; all others
+/// are ignored for now
+pub static EXTENDED_KEYS: [ExtendedKey; 11] = [
+    ExtendedKey { scancode: 0x1C, ascii: b'\n', name: "Keypad Enter" },
+    ExtendedKey { scancode: 0x35, ascii: b'/', name: "Keypad /" },
+    ExtendedKey { scancode: 0x47, ascii: KEY_HOME, name: "Home" },
+    ExtendedKey { scancode: 0x48, ascii: KEY_UP, name: "Up" },
+    ExtendedKey { scancode: 0x49, ascii: KEY_PAGE_UP, name: "Page Up" },
+    ExtendedKey { scancode: 0x4B, ascii: KEY_LEFT, name: "Left" },
+    ExtendedKey { scancode: 0x4D, ascii: KEY_RIGHT, name: "Right" },
+    ExtendedKey { scancode: 0x4F, ascii: KEY_END, name: "End" },
+    ExtendedKey { scancode: 0x50, ascii: KEY_DOWN, name: "Down" },
+    ExtendedKey { scancode: 0x51, ascii: KEY_PAGE_DOWN, name: "Page Down" },
+    ExtendedKey { scancode: 0x53, ascii: KEY_DELETE, name: "Delete" },
+];
+
+/// A keypad key that depends on Num Lock
+pub struct KeypadKey {
+    pub scancode: u8,
+    /// Produced with Num Lock on
+    pub digit: u8,
+    /// Produced with Num Lock off (0 for nothing)
+    pub nav: u8,
+}
+
+/// Keypad keys whose meaning depends on Num Lock. The rest of the keypad
+/// (*, -, +) is in the scancode tables, and Enter and / are extended keys.
+/// These share scancodes with the separate navigation cluster, which sends
+/// them with an 0xE0 prefix.
+pub static KEYPAD_KEYS: [KeypadKey; 11] = [
+    KeypadKey { scancode: 0x47, digit: b'7', nav: KEY_HOME },
+    KeypadKey { scancode: 0x48, digit: b'8', nav: KEY_UP },
+    KeypadKey { scancode: 0x49, digit: b'9', nav: KEY_PAGE_UP },
+    KeypadKey { scancode: 0x4B, digit: b'4', nav: KEY_LEFT },
+    KeypadKey { scancode: 0x4C, digit: b'5', nav: 0 },
+    KeypadKey { scancode: 0x4D, digit: b'6', nav: KEY_RIGHT },
+    KeypadKey { scancode: 0x4F, digit: b'1', nav: KEY_END },
+    KeypadKey { scancode: 0x50, digit: b'2', nav: KEY_DOWN },
+    KeypadKey { scancode: 0x51, digit: b'3', nav: KEY_PAGE_DOWN },
+    // Insert has no key code yet
+    KeypadKey { scancode: 0x52, digit: b'0', nav: 0 },
+    KeypadKey { scancode: 0x53, digit: b'.', nav: KEY_DELETE },
+];
+
+/// Bytes still to skip of the Pause key's 0xE1-prefixed sequence
+static PAUSE_REMAINING: AtomicU8 = AtomicU8::new(0);
+
+// US QWERTY, scancode set 1 -> ASCII (unshifted)
 #[rustfmt::skip]
-static SCANCODE_UNSHIFTED: [u8; 128] = [
+static US_UNSHIFTED: [u8; 128] = [
     0,   27,  b'1', b'2', b'3', b'4', b'5', b'6',  // 0x00-0x07
     b'7', b'8', b'9', b'0', b'-', b'=', 8,   b'\t', // 0x08-0x0F
     b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i', // 0x10-0x17
@@ -52,7 +333,7 @@ static SCANCODE_UNSHIFTED: [u8; 128] = [
     b'b', b'n', b'm', b',', b'.', b'/', 0,   b'*', // 0x30-0x37
     0,   b' ', 0,   0,    0,    0,    0,    0,      // 0x38-0x3F
     0,   0,   0,   0,    0,    0,    0,    0,       // 0x40-0x47
-    0,   0,   0,   0,    0,    0,    0,    0,       // 0x48-0x4F
+    0,   0,   b'-', 0,   0,    0,    b'+', 0,       // 0x48-0x4F
     0,   0,   0,   0,    0,    0,    0,    0,       // 0x50-0x57
     0,   0,   0,   0,    0,    0,    0,    0,       // 0x58-0x5F
     0,   0,   0,   0,    0,    0,    0,    0,       // 0x60-0x67
@@ -61,9 +342,9 @@ static SCANCODE_UNSHIFTED: [u8; 128] = [
     0,   0,   0,   0,    0,    0,    0,    0,       // 0x78-0x7F
 ];
 
-// Scancode set 1 -> ASCII (shifted)
+// US QWERTY, scancode set 1 -> ASCII (shifted)
 #[rustfmt::skip]
-static SCANCODE_SHIFTED: [u8; 128] = [
+static US_SHIFTED: [u8; 128] = [
     0,   27,  b'!', b'@', b'#', b'$', b'%', b'^', // 0x00-0x07
     b'&', b'*', b'(', b')', b'_', b'+', 8,   b'\t', // 0x08-0x0F
     b'Q', b'W', b'E', b'R', b'T', b'Y', b'U', b'I', // 0x10-0x17
@@ -73,7 +354,49 @@ static SCANCODE_SHIFTED: [u8; 128] = [
     b'B', b'N', b'M', b'<', b'>', b'?', 0,   b'*',  // 0x30-0x37
     0,   b' ', 0,   0,    0,    0,    0,    0,       // 0x38-0x3F
     0,   0,   0,   0,    0,    0,    0,    0,        // 0x40-0x47
-    0,   0,   0,   0,    0,    0,    0,    0,        // 0x48-0x4F
+    0,   0,   b'-', 0,   0,    0,    b'+', 0,        // 0x48-0x4F
+    0,   0,   0,   0,    0,    0,    0,    0,        // 0x50-0x57
+    0,   0,   0,   0,    0,    0,    0,    0,        // 0x58-0x5F
+    0,   0,   0,   0,    0,    0,    0,    0,        // 0x60-0x67
+    0,   0,   0,   0,    0,    0,    0,    0,        // 0x68-0x6F
+    0,   0,   0,   0,    0,    0,    0,    0,        // 0x70-0x77
+    0,   0,   0,   0,    0,    0,    0,    0,        // 0x78-0x7F
+];
+
+// Dvorak, scancode set 1 -> ASCII (unshifted)
+#[rustfmt::skip]
+static DVORAK_UNSHIFTED: [u8; 128] = [
+    0,   27,  b'1', b'2', b'3', b'4', b'5', b'6',  // 0x00-0x07
+    b'7', b'8', b'9', b'0', b'[', b']', 8,   b'\t', // 0x08-0x0F
+    b'\'', b',', b'.', b'p', b'y', b'f', b'g', b'c', // 0x10-0x17
+    b'r', b'l', b'/', b'=', b'\n', 0,   b'a', b'o', // 0x18-0x1F
+    b'e', b'u', b'i', b'd', b'h', b't', b'n', b's', // 0x20-0x27
+    b'-', b'`', 0,   b'\\', b';', b'q', b'j', b'k', // 0x28-0x2F
+    b'x', b'b', b'm', b'w', b'v', b'z', 0,   b'*', // 0x30-0x37
+    0,   b' ', 0,   0,    0,    0,    0,    0,      // 0x38-0x3F
+    0,   0,   0,   0,    0,    0,    0,    0,       // 0x40-0x47
+    0,   0,   b'-', 0,   0,    0,    b'+', 0,       // 0x48-0x4F
+    0,   0,   0,   0,    0,    0,    0,    0,       // 0x50-0x57
+    0,   0,   0,   0,    0,    0,    0,    0,       // 0x58-0x5F
+    0,   0,   0,   0,    0,    0,    0,    0,       // 0x60-0x67
+    0,   0,   0,   0,    0,    0,    0,    0,       // 0x68-0x6F
+    0,   0,   0,   0,    0,    0,    0,    0,       // 0x70-0x77
+    0,   0,   0,   0,    0,    0,    0,    0,       // 0x78-0x7F
+];
+
+// Dvorak, scancode set 1 -> ASCII (shifted)
+#[rustfmt::skip]
+static DVORAK_SHIFTED: [u8; 128] = [
+    0,   27,  b'!', b'@', b'#', b'$', b'%', b'^', // 0x00-0x07
+    b'&', b'*', b'(', b')', b'{', b'}', 8,   b'\t', // 0x08-0x0F
+    b'"', b'<', b'>', b'P', b'Y', b'F', b'G', b'C', // 0x10-0x17
+    b'R', b'L', b'?', b'+', b'\n', 0,   b'A', b'O', // 0x18-0x1F
+    b'E', b'U', b'I', b'D', b'H', b'T', b'N', b'S', // 0x20-0x27
+    b'_', b'~', 0,   b'|', b':', b'Q', b'J', b'K',  // 0x28-0x2F
+    b'X', b'B', b'M', b'W', b'V', b'Z', 0,   b'*',  // 0x30-0x37
+    0,   b' ', 0,   0,    0,    0,    0,    0,       // 0x38-0x3F
+    0,   0,   0,   0,    0,    0,    0,    0,        // 0x40-0x47
+    0,   0,   b'-', 0,   0,    0,    b'+', 0,        // 0x48-0x4F
     0,   0,   0,   0,    0,    0,    0,    0,        // 0x50-0x57
     0,   0,   0,   0,    0,    0,    0,    0,        // 0x58-0x5F
     0,   0,   0,   0,    0,    0,    0,    0,        // 0x60-0x67
@@ -82,14 +405,203 @@ static SCANCODE_SHIFTED: [u8; 128] = [
     0,   0,   0,   0,    0,    0,    0,    0,        // 0x78-0x7F
 ];
 
+/// A keyboard layout: scancode set 1 -> ASCII, without and with Shift
+///
+/// Only the main block differs between layouts; the keypad, extended keys
+/// and modifiers are handled the same way for all of them.
+pub struct Layout {
+    pub name: &'static str,
+    unshifted: &'static [u8; 128],
+    shifted: &'static [u8; 128],
+}
+
+pub static LAYOUTS: [Layout; 2] = [
+    Layout { name: "us", unshifted: &US_UNSHIFTED, shifted: &US_SHIFTED },
+    Layout { name: "dvorak", unshifted: &DVORAK_UNSHIFTED, shifted: &DVORAK_SHIFTED },
+];
+
+/// Index into `LAYOUTS` of the layout in use
+///
+/// An atomic rather than a lock: the IRQ1 handler reads it, and must never
+/// wait on a shell command that's switching layouts.
+static ACTIVE_LAYOUT: AtomicUsize = AtomicUsize::new(0);
+
+/// The layout scancodes are currently translated with
+pub fn layout() -> &'static Layout {
+    &LAYOUTS[ACTIVE_LAYOUT.load(Ordering::Relaxed)]
+}
+
+/// Switch to the layout called `name`; returns false if there's none
+pub fn set_layout(name: &str) -> bool {
+    match LAYOUTS.iter().position(|l| l.name == name) {
+        Some(index) => {
+            ACTIVE_LAYOUT.store(index, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+fn handle_extended(key: u8, is_release: bool) {
+    match key {
+        // Right ctrl
+        0x1D => MODIFIERS.set(Modifiers::CTRL, !is_release),
+        // Fake shifts sent around PrintScreen and the navigation cluster
+        0x2A | 0x36 => {}
+        _ if is_release => {}
+        _ => {
+            if let Some(ext) = EXTENDED_KEYS.iter().find(|e| e.scancode == key) {
+                let ascii = match ext.ascii {
+                    KEY_PAGE_UP if MODIFIERS.held(Modifiers::SHIFT) => KEY_SCROLL_UP,
+                    KEY_PAGE_DOWN if MODIFIERS.held(Modifiers::SHIFT) => KEY_SCROLL_DOWN,
+                    ascii => ascii,
+                };
+                press(key_id(key, true), ascii);
+            }
+        }
+    }
+}
+
+// --- Software auto-repeat ---
+
+/// How long a key must be held before it starts repeating
+const REPEAT_DELAY_MS: u64 = 500;
+/// Time between repeats once a key is repeating
+const REPEAT_INTERVAL_MS: u64 = 30;
+
+/// The last key pressed that produced a character, while it's held
+struct HeldKey {
+    id: u16,
+    ascii: u8,
+    /// Uptime at which to push `ascii` again
+    next_ms: u64,
+}
+
+/// Only used by the IRQ0 and IRQ1 handlers, which can't interrupt each
+/// other, so the lock is never contended
+static HELD: Mutex<Option<HeldKey>> = Mutex::new(None);
+
+/// Identifies a physical key: an extended key shares its scancode with a
+/// keypad key, so the prefix is part of the id
+fn key_id(key: u8, extended: bool) -> u16 {
+    key as u16 | (extended as u16) << 8
+}
+
+/// Deliver a key's character and start timing its auto-repeat
+///
+/// Keys that produce nothing (modifiers, lock keys) never get here, so
+/// they never repeat.
+fn press(id: u16, ascii: u8) {
+    KEY_BUFFER.lock().push(ascii);
+    // Repeating the codepoint-entry key would start entry over and over
+    *HELD.lock() = (ascii != KEY_HEX_INPUT).then(|| HeldKey {
+        id,
+        ascii,
+        next_ms: pit::uptime_ms() + REPEAT_DELAY_MS,
+    });
+}
+
+/// Repeat the held key if it's due; called from the timer interrupt
+pub fn auto_repeat(now_ms: u64) {
+    let Some(mut held) = HELD.try_lock() else {
+        return;
+    };
+    if let Some(ref mut h) = *held {
+        if now_ms >= h.next_ms {
+            h.next_ms = now_ms + REPEAT_INTERVAL_MS;
+            KEY_BUFFER.lock().push(h.ascii);
+        }
+    }
+}
+
+/// IRQ1 handler
+pub fn handle_irq() {
+    let scancode: u8 = unsafe { Port::new(0x60).read() };
+    handle_scancode(scancode);
+    loop {
+        let stray = STRAY.lock().pop();
+        let Some(scancode) = stray else {
+            break;
+        };
+        handle_scancode(scancode);
+    }
+}
+
 pub fn handle_scancode(scancode: u8) {
+    // Command ACK from the keyboard (e.g. after setting LEDs)
+    if scancode == 0xFA {
+        return;
+    }
+
+    // Pause sends E1 1D 45 E1 9D C5 with no release; none of it is a
+    // real Ctrl or Num Lock press, so swallow the whole sequence
+    if PAUSE_REMAINING.load(Ordering::Relaxed) > 0 {
+        PAUSE_REMAINING.fetch_sub(1, Ordering::Relaxed);
+        return;
+    }
+    if scancode == 0xE1 {
+        PAUSE_REMAINING.store(5, Ordering::Relaxed);
+        return;
+    }
+
+    if scancode == 0xE0 {
+        EXTENDED_PENDING.store(true, Ordering::Relaxed);
+        return;
+    }
+    let extended = EXTENDED_PENDING.swap(false, Ordering::Relaxed);
+
     let is_release = scancode & 0x80 != 0;
     let key = scancode & 0x7F;
 
+    if is_release {
+        STORM.lock().on_release(key);
+    } else if !STORM.lock().on_make(key) {
+        SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    // Repeats are made in software, so drop the keyboard's own typematic
+    // make codes for the key being held
+    let id = key_id(key, extended);
+    {
+        let mut held = HELD.lock();
+        match *held {
+            Some(ref h) if h.id == id && is_release => *held = None,
+            Some(ref h) if h.id == id => return,
+            _ => {}
+        }
+    }
+
+    if extended {
+        handle_extended(key, is_release);
+        return;
+    }
+
     // Track shift state
     if key == 0x2A || key == 0x36 {
-        unsafe {
-            SHIFT_HELD = !is_release;
+        MODIFIERS.set(Modifiers::SHIFT, !is_release);
+        return;
+    }
+
+    // Track ctrl state (left ctrl; right ctrl is handled as an extended key)
+    if key == 0x1D {
+        MODIFIERS.set(Modifiers::CTRL, !is_release);
+        return;
+    }
+
+    let lock = match key {
+        0x46 => Some(LED_SCROLL_LOCK),
+        0x3A => Some(LED_CAPS_LOCK),
+        0x45 => Some(LED_NUM_LOCK),
+        _ => None,
+    };
+    if let Some(led) = lock {
+        let toggled = lock_key(led, is_release);
+        // Scroll Lock pauses framebuffer output until pressed again
+        if led == LED_SCROLL_LOCK {
+            if let Some(paused) = toggled {
+                framebuffer::set_paused(paused);
+            }
         }
         return;
     }
@@ -98,13 +610,43 @@ pub fn handle_scancode(scancode: u8) {
         return;
     }
 
-    let ascii = if unsafe { SHIFT_HELD } {
-        SCANCODE_SHIFTED[key as usize]
+    if let Some(pad) = KEYPAD_KEYS.iter().find(|k| k.scancode == key) {
+        let num_lock = LOCK_STATE.load(Ordering::Relaxed) & LED_NUM_LOCK != 0;
+        let ascii = if num_lock { pad.digit } else { pad.nav };
+        if ascii != 0 {
+            press(key_id(key, false), ascii);
+        }
+        return;
+    }
+
+    // Caps Lock inverts Shift, but only for letters
+    let shift = MODIFIERS.held(Modifiers::SHIFT);
+    let caps = LOCK_STATE.load(Ordering::Relaxed) & LED_CAPS_LOCK != 0;
+    let layout = layout();
+    let shifted = if layout.unshifted[key as usize].is_ascii_alphabetic() {
+        shift != caps
+    } else {
+        shift
+    };
+    let mut ascii = if shifted {
+        layout.shifted[key as usize]
     } else {
-        SCANCODE_UNSHIFTED[key as usize]
+        layout.unshifted[key as usize]
     };
 
+    // Ctrl+letter produces the matching control code (Ctrl+D -> 0x04).
+    // Other printable keys produce nothing with Ctrl held, so a Ctrl
+    // combination never types a character by accident.
+    if MODIFIERS.held(Modifiers::CTRL) {
+        ascii = match ascii {
+            b'u' | b'U' if shift => KEY_HEX_INPUT,
+            _ if ascii.is_ascii_alphabetic() => ascii & 0x1F,
+            b' '..=b'~' => 0,
+            _ => ascii,
+        };
+    }
+
     if ascii != 0 {
-        KEY_BUFFER.lock().push(ascii);
+        press(key_id(key, false), ascii);
     }
 }
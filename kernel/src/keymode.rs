@@ -0,0 +1,56 @@
+// Declarative key bindings for interactive sub-modes.
+//
+// A sub-mode describes its keys as a static table of `Binding`s and feeds
+// each incoming key to `KeyMap::dispatch`, then matches on the returned
+// action instead of on raw key codes. Bindings can be restricted to one
+// mode (e.g. vi-style normal vs insert) or apply in every mode. Keys with
+// no binding come back as `Dispatch::Unbound` so the sub-mode decides what
+// they mean: an insert mode typically treats them as text, while a
+// read-only mode like the pager just ignores them. Every sub-mode should
+// bind Ctrl+D (`KEY_EOF` in the shell) to its exit action.
+
+/// One key binding
+pub struct Binding<M, A> {
+    /// Mode the binding applies in, or `None` for all modes
+    pub mode: Option<M>,
+    /// Key code as delivered by the keyboard buffer
+    pub key: u8,
+    /// Action the key triggers
+    pub action: A,
+}
+
+/// Result of dispatching a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch<A> {
+    Action(A),
+    Unbound(u8),
+}
+
+/// A binding table together with the current mode
+pub struct KeyMap<M: 'static, A: 'static> {
+    bindings: &'static [Binding<M, A>],
+    mode: M,
+}
+
+impl<M: Copy + PartialEq, A: Copy> KeyMap<M, A> {
+    pub const fn new(bindings: &'static [Binding<M, A>], initial: M) -> Self {
+        KeyMap {
+            bindings,
+            mode: initial,
+        }
+    }
+
+    /// Look up `key` in the current mode; mode-specific bindings take
+    /// precedence over ones that apply in all modes
+    pub fn dispatch(&self, key: u8) -> Dispatch<A> {
+        let in_mode = self
+            .bindings
+            .iter()
+            .find(|b| b.key == key && b.mode == Some(self.mode));
+        let any_mode = || self.bindings.iter().find(|b| b.key == key && b.mode.is_none());
+        match in_mode.or_else(any_mode) {
+            Some(b) => Dispatch::Action(b.action),
+            None => Dispatch::Unbound(key),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::serial;
+use crate::tsc;
+
+/// Size of the kernel log ring in bytes
+pub const LOG_SIZE: usize = 8192;
+
+/// Circular kernel log; the oldest bytes are overwritten once full
+pub struct LogBuffer {
+    buf: [u8; LOG_SIZE],
+    /// Total bytes ever written; `written % LOG_SIZE` is the write position
+    written: u64,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        LogBuffer {
+            buf: [0; LOG_SIZE],
+            written: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buf[(self.written % LOG_SIZE as u64) as usize] = byte;
+        self.written += 1;
+    }
+
+    /// Absolute position of the oldest byte still held
+    pub fn start(&self) -> u64 {
+        self.written.saturating_sub(LOG_SIZE as u64)
+    }
+
+    /// A cursor positioned at the oldest retained byte
+    pub fn cursor(&self) -> LogCursor {
+        LogCursor { pos: self.start() }
+    }
+
+    /// Copy bytes at `cursor` into `out` and advance the cursor
+    ///
+    /// If the ring has wrapped past the cursor, it is moved up to the
+    /// oldest retained byte and the number of lost bytes is reported.
+    pub fn read(&self, cursor: &mut LogCursor, out: &mut [u8]) -> LogRead {
+        let dropped = self.start().saturating_sub(cursor.pos);
+        cursor.pos += dropped;
+
+        let available = (self.written - cursor.pos) as usize;
+        let copied = available.min(out.len());
+        for (i, slot) in out[..copied].iter_mut().enumerate() {
+            *slot = self.buf[((cursor.pos + i as u64) % LOG_SIZE as u64) as usize];
+        }
+        cursor.pos += copied as u64;
+        LogRead { copied, dropped }
+    }
+}
+
+/// A reader's position in the log; each follower keeps its own
+#[derive(Debug, Clone, Copy)]
+pub struct LogCursor {
+    pos: u64,
+}
+
+/// Result of a `LogBuffer::read`
+pub struct LogRead {
+    /// Bytes copied into the output buffer
+    pub copied: usize,
+    /// Bytes overwritten before this reader got to them
+    pub dropped: u64,
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.push(b);
+        }
+        Ok(())
+    }
+}
+
+pub static LOG: Mutex<LogBuffer> = Mutex::new(LogBuffer::new());
+
+/// Whether log lines are echoed to COM2 instead of the console's COM1
+static ECHO_COM2: AtomicBool = AtomicBool::new(false);
+
+pub fn set_echo_com2(enabled: bool) {
+    ECHO_COM2.store(enabled, Ordering::Relaxed);
+}
+
+/// Append a timestamped line to the kernel log and echo it to serial
+pub fn log(args: fmt::Arguments) {
+    without_interrupts(|| {
+        let ms = tsc::uptime_ms();
+        let mut log = LOG.lock();
+        let _ = write!(log, "[{ms:>8}ms] ");
+        let _ = log.write_fmt(args);
+        let _ = log.write_str("\n");
+
+        let port: &Mutex<serial::SerialPort> = if ECHO_COM2.load(Ordering::Relaxed) {
+            &serial::SERIAL2
+        } else {
+            &serial::SERIAL
+        };
+        let mut serial = port.lock();
+        let _ = write!(serial, "[{ms:>8}ms] ");
+        let _ = serial.write_fmt(args);
+        let _ = serial.write_str("\n");
+    });
+}
@@ -0,0 +1,40 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::tsc;
+
+/// Number of histogram buckets; bucket `i` counts gaps in `[2^i, 2^(i+1))` cycles
+pub const BUCKETS: usize = 64;
+
+static LAST_TSC: AtomicU64 = AtomicU64::new(0);
+static HISTOGRAM: [AtomicU64; BUCKETS] = [const { AtomicU64::new(0) }; BUCKETS];
+
+/// Record the gap since the previous timer interrupt
+///
+/// Called from the IRQ0 handler, so it does no more than a TSC read, a
+/// subtraction and one counter increment.
+pub fn record() {
+    let now = tsc::read();
+    let last = LAST_TSC.swap(now, Ordering::Relaxed);
+    if last != 0 {
+        let gap = now.wrapping_sub(last).max(1);
+        let bucket = 63 - gap.leading_zeros() as usize;
+        HISTOGRAM[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Copy of the histogram counts
+pub fn snapshot() -> [u64; BUCKETS] {
+    let mut counts = [0u64; BUCKETS];
+    for (count, bucket) in counts.iter_mut().zip(HISTOGRAM.iter()) {
+        *count = bucket.load(Ordering::Relaxed);
+    }
+    counts
+}
+
+/// Clear all counts and restart gap measurement
+pub fn reset() {
+    for bucket in HISTOGRAM.iter() {
+        bucket.store(0, Ordering::Relaxed);
+    }
+    LAST_TSC.store(0, Ordering::Relaxed);
+}
@@ -2,9 +2,12 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+extern crate alloc;
+
 mod vga_buffer;
 mod serial;
 mod block_device;
+mod block_cache;
 mod ramdisk;
 mod font;
 mod framebuffer;
@@ -13,12 +16,44 @@ mod pic;
 mod interrupts;
 mod keyboard;
 mod shell;
+mod console;
+mod power;
+mod tsc;
+mod memory;
+mod pat;
+mod klog;
+mod crc32;
+mod pit;
+mod latency;
+mod keymode;
+mod acpi;
+mod hpet;
+mod ansi;
+mod rand;
+mod mouse;
+mod partition;
+mod tarfs;
+mod qemu;
+mod paging;
+mod heap;
+mod sfs;
+mod rtc;
+mod speaker;
+mod cpu;
+mod rng;
+mod syscall;
+mod backtrace;
+mod exceptions;
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::panic::PanicInfo;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 use block_device::{BlockDevice, BLOCK_SIZE};
 use limine::BaseRevision;
-use limine::request::{FramebufferRequest, RequestsStartMarker, RequestsEndMarker};
+use limine::request::{ExecutableCmdlineRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, ModuleRequest, RsdpRequest, RequestsStartMarker, RequestsEndMarker};
+use x86_64::PrivilegeLevel;
 
 #[used]
 #[link_section = ".requests"]
@@ -28,6 +63,26 @@ static BASE_REVISION: BaseRevision = BaseRevision::new();
 #[link_section = ".requests"]
 static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 
+#[used]
+#[link_section = ".requests"]
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
 #[used]
 #[link_section = ".requests_start_marker"]
 static _REQUEST_START: RequestsStartMarker = RequestsStartMarker::new();
@@ -38,15 +93,47 @@ static _REQUEST_END: RequestsEndMarker = RequestsEndMarker::new();
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    let mut serial = serial::SERIAL.lock();
+    // Locks COM1 per line, so a panic during boot can still report
+    let mut serial = serial::Writer;
+
+    // Switch baud rate before the banner so all of it arrives intact
+    let baud_arg = boot_arg("baud");
+    if let Some(baud) = baud_arg.and_then(serial::parse_baud) {
+        serial::SERIAL.lock().set_baud(baud);
+    }
 
     writeln!(serial, "ShadowOS v0.1.0").unwrap();
     writeln!(serial, "================").unwrap();
     writeln!(serial).unwrap();
 
+    match baud_arg {
+        Some(arg) if serial::parse_baud(arg).is_none() => {
+            writeln!(serial, "[!] Unsupported baud={arg}; keeping 115200").unwrap();
+        }
+        _ => {}
+    }
+    // Kernel log lines go to COM2 with klog=com2
+    if boot_arg("klog") == Some("com2") {
+        klog::set_echo_com2(true);
+        writeln!(serial, "[*] Kernel log echoed to COM2").unwrap();
+    }
+
+    // Pick the panic policy first so it covers the rest of boot
+    if let Some(arg) = boot_arg("panic") {
+        match power::PanicPolicy::parse(arg) {
+            Some(policy) => power::set_panic_policy(policy),
+            None => writeln!(serial, "[!] Unknown panic={arg}; keeping halt").unwrap(),
+        }
+    }
+
     // Initialize GDT (must be first — IDT references TSS)
     gdt::init();
     writeln!(serial, "[*] GDT initialized").unwrap();
+    let (user_code, user_data) = gdt::user_selectors();
+    if user_code.rpl() != PrivilegeLevel::Ring3 || user_data.rpl() != PrivilegeLevel::Ring3 {
+        writeln!(serial, "[!] GDT: user selectors lack RPL 3").unwrap();
+    }
+    writeln!(serial, "[*] GDT: user code {:#x}, user data {:#x}", user_code.0, user_data.0).unwrap();
 
     // Initialize PIC (remap IRQs to 32-47, all masked)
     pic::init();
@@ -56,28 +143,167 @@ pub extern "C" fn _start() -> ! {
     interrupts::init();
     writeln!(serial, "[*] IDT loaded").unwrap();
 
-    // Unmask keyboard IRQ (IRQ1)
+    // Start the system timer (IRQ0)
+    pit::init(TIMER_HZ);
+    interrupts::set_irq_handler(0, pit::handle_irq);
+    pic::unmask_irq(0);
+    writeln!(serial, "[*] PIT running at {} Hz", pit::frequency()).unwrap();
+
+    // Claim and unmask keyboard IRQ (IRQ1)
+    interrupts::set_irq_handler(1, keyboard::handle_irq);
     pic::unmask_irq(1);
     writeln!(serial, "[*] Keyboard IRQ unmasked").unwrap();
 
+    // Serial input on COM1 (IRQ4), so the shell works over a headless console,
+    // and output is sent from a queue instead of busy-waiting per byte
+    interrupts::set_irq_handler(4, serial::handle_irq);
+    serial::SERIAL.lock().enable_interrupts();
+    pic::unmask_irq(4);
+    writeln!(serial, "[*] Serial IRQ unmasked (buffered transmit)").unwrap();
+
+    // PS/2 mouse on the controller's aux port (IRQ12), if there is one
+    match mouse::init() {
+        Ok(()) => {
+            interrupts::set_irq_handler(12, mouse::handle_irq);
+            pic::unmask_irq(12);
+            writeln!(serial, "[*] PS/2 mouse enabled").unwrap();
+            shell::subsystem_up("mouse");
+        }
+        Err(e) => writeln!(serial, "[*] No PS/2 mouse ({e:?})").unwrap(),
+    }
+
+    // Record the higher-half direct map offset for physical memory access
+    if let Some(response) = HHDM_REQUEST.get_response() {
+        memory::init_hhdm(response.offset());
+        writeln!(serial, "[*] HHDM offset: {:#x}", response.offset()).unwrap();
+        shell::subsystem_up("hhdm");
+    } else {
+        writeln!(serial, "[!] HHDM request not answered by bootloader; physical memory access disabled").unwrap();
+    }
+
+    // Record the physical memory map
+    if let Some(response) = MEMORY_MAP_REQUEST.get_response() {
+        let usable = memory::init_memory_map(response.entries());
+        writeln!(
+            serial,
+            "[*] Memory map: {} entries, {} MB usable",
+            response.entries().len(),
+            usable / (1024 * 1024)
+        ).unwrap();
+    } else {
+        writeln!(serial, "[!] Memory map request not answered by bootloader").unwrap();
+    }
+
+    // Hand out physical frames from the usable regions
+    if !memory::frame_allocator_self_test() {
+        writeln!(serial, "[!] Frame allocator self-test failed").unwrap();
+    }
+    match memory::init_frame_allocator() {
+        Some(frames) => {
+            writeln!(serial, "[*] Frame allocator: {frames} frames available").unwrap();
+            // The first real frame must be reachable through the HHDM
+            let ok = memory::hhdm_offset().is_none()
+                || memory::allocate_frame().is_some_and(|frame| unsafe {
+                    let ptr = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+                    ptr.write_volatile(0x5ad0_f4a3_e000_0001);
+                    ptr.read_volatile() == 0x5ad0_f4a3_e000_0001
+                });
+            if !ok {
+                writeln!(serial, "[!] Frame allocator: first frame not usable").unwrap();
+            }
+            if memory::hhdm_offset().is_some() && !paging::self_test() {
+                writeln!(serial, "[!] Paging self-test failed").unwrap();
+            }
+            if memory::hhdm_offset().is_some() {
+                match gdt::install_guarded_stacks() {
+                    Ok(()) => writeln!(serial, "[*] IST stacks moved to guarded pages").unwrap(),
+                    Err(e) => writeln!(serial, "[!] IST stacks left unguarded ({e:?})").unwrap(),
+                }
+            }
+        }
+        None => writeln!(serial, "[!] Frame allocator disabled (no memory map)").unwrap(),
+    }
+
+    // Map the kernel heap and prove it works before anything relies on it
+    match heap::init() {
+        Ok(()) => {
+            let boxed = Box::new(0x5ad0_u32);
+            let squares: Vec<u64> = (0..64).map(|n| n * n).collect();
+            if *boxed == 0x5ad0 && squares.len() == 64 && squares[63] == 63 * 63 {
+                writeln!(serial, "[*] Heap: {} KB at {:#x}", heap::HEAP_SIZE / 1024, heap::HEAP_START).unwrap();
+                shell::subsystem_up("heap");
+            } else {
+                writeln!(serial, "[!] Heap: smoke test failed").unwrap();
+            }
+        }
+        Err(e) => writeln!(serial, "[!] Heap disabled ({e:?})").unwrap(),
+    }
+
+    // Discover ACPI tables (the RSDP address is physical in base revision 3)
+    if let Some(response) = RSDP_REQUEST.get_response() {
+        match acpi::init(response.address() as u64) {
+            Ok(count) => {
+                writeln!(serial, "[*] ACPI: {count} tables found").unwrap();
+                shell::subsystem_up("acpi");
+            }
+            Err(e) => writeln!(serial, "[!] ACPI: discovery failed ({e:?})").unwrap(),
+        }
+    } else {
+        writeln!(serial, "[*] ACPI not available (no RSDP)").unwrap();
+    }
+
+    // Prefer the HPET for the system tick unless booted with timer=pit
+    if boot_arg("timer") == Some("pit") {
+        writeln!(serial, "[*] HPET disabled by timer=pit").unwrap();
+    } else {
+        match hpet::init().and_then(|_| hpet::start_periodic(TIMER_HZ)) {
+            Ok(hz) => {
+                pit::set_tick_source(pit::TickSource::Hpet, hz);
+                writeln!(serial, "[*] HPET driving IRQ0 at {hz} Hz").unwrap();
+                shell::subsystem_up("hpet");
+            }
+            Err(e) => writeln!(serial, "[*] HPET unavailable ({e:?}); keeping the PIT").unwrap(),
+        }
+    }
+
+    // Calibrate TSC against the PIT (used for timing measurements)
+    let tsc_per_ms = tsc::calibrate();
+    writeln!(serial, "[*] TSC calibrated: {} MHz", tsc_per_ms / 1000).unwrap();
+    shell::subsystem_up("tsc");
+
     // Initialize framebuffer
     if let Some(response) = FRAMEBUFFER_REQUEST.get_response() {
-        if let Some(fb) = response.framebuffers().next() {
-            writeln!(serial, "[*] Framebuffer: {}x{}, {}bpp, pitch={}",
+        for (i, fb) in response.framebuffers().enumerate() {
+            writeln!(serial, "[*] Framebuffer {i}: {}x{}, {}bpp, pitch={}",
                      fb.width(), fb.height(), fb.bpp(), fb.pitch()).unwrap();
+        }
+        let modes = response.framebuffers().map(|fb| framebuffer::Mode {
+            buffer: fb.addr(),
+            width: fb.width() as usize,
+            height: fb.height() as usize,
+            pitch: fb.pitch() as usize,
+            bpp: fb.bpp() as usize,
+            red_shift: fb.red_mask_shift(),
+            green_shift: fb.green_mask_shift(),
+            blue_shift: fb.blue_mask_shift(),
+        });
+        let count = framebuffer::init_all(modes);
+        if count > 0 {
+            writeln!(serial, "[*] Framebuffer initialized ({count} in use, console on 0)").unwrap();
+            shell::subsystem_up("fb");
+            let back = framebuffer::FRAMEBUFFER.lock().as_ref().is_some_and(|w| w.back_buffer_enabled());
+            if !back {
+                writeln!(serial, "[!] No memory for a back buffer; drawing straight to the framebuffer").unwrap();
+            }
 
-            framebuffer::init(
-                fb.addr(),
-                fb.width() as usize,
-                fb.height() as usize,
-                fb.pitch() as usize,
-                fb.bpp() as usize,
-                fb.red_mask_shift(),
-                fb.green_mask_shift(),
-                fb.blue_mask_shift(),
-            );
-
-            writeln!(serial, "[*] Framebuffer initialized").unwrap();
+            let bandwidth = framebuffer::FRAMEBUFFER.lock().as_mut()
+                .and_then(|writer| writer.measure_write_bandwidth());
+            if let Some(mbps) = bandwidth {
+                writeln!(serial, "[*] Framebuffer write bandwidth: {mbps} MB/s").unwrap();
+                if mbps < framebuffer::SLOW_BANDWIDTH_MBPS {
+                    writeln!(serial, "[!] Framebuffer writes are slow; mapping may be uncached").unwrap();
+                }
+            }
         } else {
             writeln!(serial, "[!] No framebuffers available").unwrap();
         }
@@ -85,27 +311,134 @@ pub extern "C" fn _start() -> ! {
         writeln!(serial, "[!] Framebuffer request not answered by bootloader").unwrap();
     }
 
-    // Initialize RAM disk
+    // Install a replacement font if the bootloader loaded one
+    if let Some(data) = find_module(FONT_MODULE_NAME) {
+        let font = font::Font::new(data, font::FONT_HEIGHT, FONT_MODULE_GLYPHS);
+        let result = framebuffer::FRAMEBUFFER.lock().as_mut().map(|w| w.set_font(font));
+        match result {
+            Some(Ok(())) => writeln!(serial, "[*] Loaded font from {FONT_MODULE_NAME}").unwrap(),
+            Some(Err(e)) => writeln!(serial, "[!] Rejected {FONT_MODULE_NAME} ({e:?}); using built-in font").unwrap(),
+            None => {}
+        }
+    }
+
+    // Initialize RAM disk, from the initrd module if the bootloader loaded one
     writeln!(serial, "[*] Initializing RAM disk...").unwrap();
-    ramdisk::init();
+    match find_module(RAMDISK_MODULE_NAME).map(ramdisk::init_from_module) {
+        Some(Ok(())) => {
+            let rd = ramdisk::RAMDISK.lock();
+            let files = rd.as_ref().map_or(0, |disk| tarfs::TarFs::new(disk).list().count());
+            writeln!(serial, "    Loaded from {RAMDISK_MODULE_NAME} ({files} files)").unwrap();
+            shell::subsystem_up("initrd");
+        }
+        Some(Err(e)) => {
+            writeln!(serial, "[!] Rejected {RAMDISK_MODULE_NAME} ({e}); created empty").unwrap();
+            ramdisk::init();
+        }
+        None => {
+            writeln!(serial, "    No {RAMDISK_MODULE_NAME} module; created empty").unwrap();
+            ramdisk::init();
+        }
+    }
+    shell::subsystem_up("block");
 
     // Test RAM disk
     test_ramdisk(&mut serial);
 
+    if heap::is_ready() && !sfs::self_test() {
+        writeln!(serial, "[!] sfs self-test failed").unwrap();
+    }
+
+    if !font::self_test() {
+        writeln!(serial, "[!] Font validation self-test failed").unwrap();
+    }
+
+    if !framebuffer::self_test() {
+        writeln!(serial, "[!] Framebuffer line wrap self-test failed").unwrap();
+    }
+
+    if !ansi::self_test() {
+        writeln!(serial, "[!] ANSI key decoder self-test failed").unwrap();
+    }
+
+    if !shell::command_table_self_test() {
+        writeln!(serial, "[!] Shell command table has duplicate names").unwrap();
+    }
+
     writeln!(serial, "\n[*] Kernel initialization complete.").unwrap();
     writeln!(serial, "[*] Enabling interrupts...").unwrap();
 
-    // Drop serial lock before enabling interrupts to prevent deadlock
-    drop(serial);
-
     // Enable interrupts
     x86_64::instructions::interrupts::enable();
 
+    if syscall::self_test() {
+        shell::subsystem_up("syscall");
+    } else {
+        writeln!(serial, "[!] int 0x80 syscall self-test failed").unwrap();
+    }
+
+    if !shell::caps_self_test() {
+        writeln!(serial, "[!] caps: a subsystem that came up is unlisted or reported absent").unwrap();
+    }
+
+    // Run the boot-time init script, if the bootloader loaded one or the
+    // initrd has one
+    if let Some(script) = find_module(INIT_SCRIPT_NAME) {
+        let lines = shell::run_script(script);
+        klog::log(format_args!("init: executed {lines} lines from {INIT_SCRIPT_NAME}"));
+    } else if let Some(script) = initrd_file(INIT_SCRIPT_NAME) {
+        let lines = shell::run_script(&script);
+        klog::log(format_args!("init: executed {lines} lines from {RAMDISK_MODULE_NAME}:{INIT_SCRIPT_NAME}"));
+    }
+
     // Hand off to the interactive shell
     shell::run();
 }
 
-fn test_ramdisk(serial: &mut serial::SerialPort) {
+/// System timer interrupt rate
+const TIMER_HZ: u32 = 100;
+
+/// Module path suffix identifying the boot-time init script
+const INIT_SCRIPT_NAME: &str = "init.sh";
+
+/// Module path suffix identifying the initial RAM disk image
+const RAMDISK_MODULE_NAME: &str = "initrd.tar";
+
+/// Module path suffix identifying a raw 8x16, 256-glyph font
+const FONT_MODULE_NAME: &str = "font.bin";
+/// Glyphs in a font module; at `FONT_HEIGHT` rows each it must hold 4 KiB
+const FONT_MODULE_GLYPHS: usize = 256;
+
+/// Value of `name=value` on the kernel command line
+fn boot_arg(name: &str) -> Option<&'static str> {
+    let cmdline = CMDLINE_REQUEST.get_response()?.cmdline().to_str().ok()?;
+    cmdline.split_ascii_whitespace().find_map(|arg| {
+        let (key, value) = arg.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// Contents of the first Limine module whose path ends with `suffix`
+fn find_module(suffix: &str) -> Option<&'static [u8]> {
+    let response = MODULE_REQUEST.get_response()?;
+    let module = response.modules().iter().find(|m| {
+        m.path().to_bytes().ends_with(suffix.as_bytes())
+    })?;
+    Some(unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) })
+}
+
+/// Contents of `name` in the tar archive on the RAM disk, if there is one
+fn initrd_file(name: &str) -> Option<Vec<u8>> {
+    if !heap::is_ready() {
+        return None;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let rd = ramdisk::RAMDISK.lock();
+        tarfs::TarFs::new(rd.as_ref()?).read_file(name).ok()?
+    })
+}
+
+fn test_ramdisk(serial: &mut serial::Writer) {
     let mut ramdisk_guard = ramdisk::RAMDISK.lock();
 
     if let Some(ref mut ramdisk) = *ramdisk_guard {
@@ -113,8 +446,10 @@ fn test_ramdisk(serial: &mut serial::SerialPort) {
         writeln!(serial, "    RAM disk has {} blocks ({} KB)",
                  block_count, block_count * BLOCK_SIZE as u64 / 1024).unwrap();
 
-        // Test writing to block 0
+        // Test writing to block 0, which may hold the initrd's first header
         writeln!(serial, "[*] Testing RAM disk I/O...").unwrap();
+        let mut saved_block0 = [0u8; BLOCK_SIZE];
+        let _ = ramdisk.read_block(0, &mut saved_block0);
 
         let mut write_buffer = [0u8; BLOCK_SIZE];
         let test_data = b"ShadowOS RAM disk test block!";
@@ -140,30 +475,254 @@ fn test_ramdisk(serial: &mut serial::SerialPort) {
             },
             Err(e) => writeln!(serial, "    Read from block 0: FAILED ({:?})", e).unwrap(),
         }
+        let _ = ramdisk.write_block(0, &saved_block0);
+
+        // Test that the multi-block read matches block-by-block reads
+        let mut bulk = [0u8; BLOCK_SIZE * 4];
+        let mut looped = [0u8; BLOCK_SIZE * 4];
+        let bulk_ok = ramdisk.read_blocks(0, &mut bulk).is_ok();
+        let loop_ok = looped.chunks_exact_mut(BLOCK_SIZE).enumerate().all(|(i, chunk)| {
+            ramdisk.read_block(i as u64, chunk.try_into().unwrap()).is_ok()
+        });
+        if bulk_ok && loop_ok && bulk == looped {
+            writeln!(serial, "    Multi-block read: PASSED").unwrap();
+        } else {
+            writeln!(serial, "    Multi-block read: FAILED").unwrap();
+        }
+        match ramdisk.read_blocks(block_count - 1, &mut bulk) {
+            Ok(_) => writeln!(serial, "    Multi-block bounds test: FAILED (should have errored)").unwrap(),
+            Err(_) => writeln!(serial, "    Multi-block bounds test: PASSED").unwrap(),
+        }
+
+        // Test a multi-block write of a partial range, leaving its neighbours alone
+        let mut pattern = [0u8; BLOCK_SIZE * 2];
+        for (i, byte) in pattern.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let mut before = [0u8; BLOCK_SIZE * 4];
+        let mut after = [0u8; BLOCK_SIZE * 4];
+        let write_ok = ramdisk.read_blocks(1, &mut before).is_ok()
+            && ramdisk.write_blocks(2, &pattern).is_ok()
+            && ramdisk.read_blocks(1, &mut after).is_ok();
+        if write_ok
+            && after[BLOCK_SIZE..BLOCK_SIZE * 3] == pattern
+            && after[..BLOCK_SIZE] == before[..BLOCK_SIZE]
+            && after[BLOCK_SIZE * 3..] == before[BLOCK_SIZE * 3..]
+        {
+            writeln!(serial, "    Multi-block write: PASSED").unwrap();
+        } else {
+            writeln!(serial, "    Multi-block write: FAILED").unwrap();
+        }
+        let _ = ramdisk.write_blocks(1, &before);
+
+        // Zero-length spans succeed anywhere up to the end, but not past it
+        let empty_ok = ramdisk.read_blocks(block_count, &mut []).is_ok()
+            && ramdisk.write_blocks(block_count, &[]).is_ok()
+            && ramdisk.write_blocks(block_count + 1, &[]).is_err();
+        let bounds_ok = ramdisk.write_blocks(block_count - 1, &pattern)
+            == Err(block_device::BlockError::OutOfBounds)
+            && ramdisk.write_blocks(u64::MAX, &pattern).is_err();
+        if empty_ok && bounds_ok {
+            writeln!(serial, "    Multi-block edge cases: PASSED").unwrap();
+        } else {
+            writeln!(serial, "    Multi-block edge cases: FAILED").unwrap();
+        }
+
+        // A read-only disk refuses single and multi-block writes alike
+        ramdisk.set_read_only(true);
+        let refused = ramdisk.write_block(1, &write_buffer) == Err(block_device::BlockError::ReadOnly)
+            && ramdisk.write_blocks(1, &pattern) == Err(block_device::BlockError::ReadOnly);
+        ramdisk.set_read_only(false);
+        if refused && !ramdisk.is_read_only() {
+            writeln!(serial, "    Read-only mode: PASSED").unwrap();
+        } else {
+            writeln!(serial, "    Read-only mode: FAILED").unwrap();
+        }
+
+        // The RAM disk writes through, so a flush is a successful no-op
+        match ramdisk.flush() {
+            Ok(_) => writeln!(serial, "    Flush: PASSED").unwrap(),
+            Err(e) => writeln!(serial, "    Flush: FAILED ({e:?})").unwrap(),
+        }
 
         // Test out of bounds access
         match ramdisk.read_block(block_count + 1, &mut read_buffer) {
             Ok(_) => writeln!(serial, "    Out of bounds test: FAILED (should have errored)").unwrap(),
             Err(_) => writeln!(serial, "    Out of bounds test: PASSED").unwrap(),
         }
+
+        if heap::is_ready() {
+            test_block_cache(serial, ramdisk);
+        }
+        test_partitions(serial, ramdisk);
     } else {
         writeln!(serial, "    ERROR: RAM disk not initialized!").unwrap();
     }
 }
 
+/// Exercise a small `BlockCache` over blocks 1-3, restoring them afterwards
+fn test_block_cache(serial: &mut serial::Writer, ramdisk: &mut ramdisk::RamDisk) {
+    let mut saved = [0u8; BLOCK_SIZE * 3];
+    if ramdisk.read_blocks(1, &mut saved).is_err() {
+        writeln!(serial, "    Block cache: SKIPPED (disk too small)").unwrap();
+        return;
+    }
+    let pattern = [0xC5u8; BLOCK_SIZE];
+    let mut block = [0u8; BLOCK_SIZE];
+
+    // A read after a write is served from the cache
+    let mut cache = block_cache::BlockCache::new(&mut *ramdisk, 2);
+    let hit_ok = cache.write_block(1, &pattern).is_ok()
+        && cache.read_block(1, &mut block).is_ok()
+        && block == pattern
+        && cache.stats() == (1, 1);
+    drop(cache);
+
+    // Unflushed writes never reached the device
+    let unflushed_ok = ramdisk.read_block(1, &mut block).is_ok() && block[..] == saved[..BLOCK_SIZE];
+
+    // A flush writes dirty blocks through
+    let mut cache = block_cache::BlockCache::new(&mut *ramdisk, 2);
+    let flush_ok = cache.write_block(2, &pattern).is_ok() && cache.flush().is_ok();
+    drop(cache);
+    let flush_ok = flush_ok && ramdisk.read_block(2, &mut block).is_ok() && block == pattern;
+
+    // Evicting a dirty block writes it back first
+    let mut cache = block_cache::BlockCache::new(&mut *ramdisk, 1);
+    let evict_ok = cache.write_block(3, &pattern).is_ok() && cache.read_block(1, &mut block).is_ok();
+    drop(cache);
+    let evict_ok = evict_ok && ramdisk.read_block(3, &mut block).is_ok() && block == pattern;
+
+    let _ = ramdisk.write_blocks(1, &saved);
+    for (name, ok) in [
+        ("write-then-read hit", hit_ok),
+        ("write-back", unflushed_ok),
+        ("flush", flush_ok),
+        ("dirty eviction", evict_ok),
+    ] {
+        writeln!(serial, "    Block cache {name}: {}", if ok { "PASSED" } else { "FAILED" }).unwrap();
+    }
+}
+
+/// Parse a hand-made MBR in block 0, restoring the block afterwards
+fn test_partitions(serial: &mut serial::Writer, ramdisk: &mut ramdisk::RamDisk) {
+    let mut saved = [0u8; BLOCK_SIZE];
+    if ramdisk.read_block(0, &mut saved).is_err() {
+        return;
+    }
+
+    // Entry 0: bootable Linux partition over blocks 4-11; entry 1 empty
+    let mut mbr = [0u8; BLOCK_SIZE];
+    mbr[446] = 0x80;
+    mbr[446 + 4] = 0x83;
+    mbr[446 + 8..446 + 12].copy_from_slice(&4u32.to_le_bytes());
+    mbr[446 + 12..446 + 16].copy_from_slice(&8u32.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    let expected = partition::Partition {
+        bootable: true,
+        type_byte: 0x83,
+        start_lba: 4,
+        sector_count: 8,
+    };
+
+    let parse_ok = ramdisk.write_block(0, &mbr).is_ok()
+        && partition::read_partitions(&*ramdisk) == Ok([Some(expected), None, None, None]);
+
+    // Block ids inside the partition are offset by its start
+    let mut direct = [0u8; BLOCK_SIZE];
+    let mut remapped = [0u8; BLOCK_SIZE];
+    let remap_ok = match partition::PartitionedDevice::new(&mut *ramdisk, &expected) {
+        Ok(part) => {
+            part.block_count() == 8
+                && part.read_block(0, &mut remapped).is_ok()
+                && part.read_block(8, &mut remapped) == Err(block_device::BlockError::OutOfBounds)
+                && part.read_block(7, &mut remapped).is_ok()
+        }
+        Err(_) => false,
+    };
+    let remap_ok = remap_ok && ramdisk.read_block(11, &mut direct).is_ok() && direct == remapped;
+
+    mbr[510] = 0;
+    let signature_ok = ramdisk.write_block(0, &mbr).is_ok()
+        && partition::read_partitions(&*ramdisk) == Err(block_device::BlockError::NoPartitionTable);
+
+    let _ = ramdisk.write_block(0, &saved);
+    for (name, ok) in [("parse", parse_ok), ("remap", remap_ok), ("signature check", signature_ok)] {
+        writeln!(serial, "    Partition table {name}: {}", if ok { "PASSED" } else { "FAILED" }).unwrap();
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     // Disable interrupts in panic to prevent re-entrancy
     x86_64::instructions::interrupts::disable();
 
-    let mut serial = serial::SERIAL.lock();
-    writeln!(serial, "\nPANIC!").unwrap();
-    if let Some(location) = info.location() {
-        writeln!(serial, "{}:{}: {}", location.file(), location.line(), info.message()).unwrap();
-    } else {
-        writeln!(serial, "{}", info.message()).unwrap();
+    // `try_lock`, like `panic_to_screen`: if the panic happened with COM1
+    // locked, the report is skipped but the panic policy still runs
+    let mut serial = serial::SERIAL.try_lock();
+    if let Some(serial) = serial.as_mut() {
+        // Interrupts won't drain the transmit queue any more
+        serial.set_unbuffered();
+        let _ = writeln!(serial, "\nPANIC!");
+        let _ = match info.location() {
+            Some(location) => writeln!(serial, "{}:{}: {}", location.file(), location.line(), info.message()),
+            None => writeln!(serial, "{}", info.message()),
+        };
+        backtrace::write_panic_trace(&mut **serial);
     }
-    loop {
-        x86_64::instructions::hlt();
+    panic_to_screen(info);
+
+    match power::panic_policy() {
+        // Under an automated QEMU run this ends the test with a failure
+        // status; elsewhere it falls through to the halt
+        power::PanicPolicy::Halt => qemu::exit(qemu::QemuExitCode::Failed),
+        power::PanicPolicy::Reboot => {
+            if let Some(serial) = serial.as_mut() {
+                let _ = writeln!(serial, "Rebooting in {} ms", power::PANIC_REBOOT_DELAY_MS);
+            }
+            power::panic_reboot(power::PANIC_REBOOT_DELAY_MS);
+        }
+        power::PanicPolicy::Recover => {
+            // A panic inside the recovery shell itself must not loop
+            if PANICKED.swap(true, Ordering::Relaxed) {
+                if let Some(serial) = serial.as_mut() {
+                    let _ = writeln!(serial, "Panic during recovery; halting");
+                }
+            } else {
+                drop(serial);
+                shell::recover();
+            }
+        }
     }
+    power::halt_forever();
 }
+
+/// Show the panic in red on the framebuffer, if there is one
+///
+/// Uses `try_lock`: if the panic happened with the framebuffer locked (or
+/// while already panicking), the screen is skipped rather than deadlocking.
+fn panic_to_screen(info: &PanicInfo) {
+    let Some(mut fb) = framebuffer::FRAMEBUFFER.try_lock() else {
+        return;
+    };
+    let Some(writer) = fb.as_mut() else {
+        return;
+    };
+    // Output held back by Scroll Lock would hide the message
+    framebuffer::set_paused(false);
+    writer.flush_paused();
+
+    let (fg, bg) = writer.colors();
+    writer.set_colors(framebuffer::Color::new(0xFF, 0x40, 0x40), framebuffer::Color::new(0, 0, 0));
+    let _ = writeln!(writer, "\nKERNEL PANIC!");
+    let _ = match info.location() {
+        Some(location) => writeln!(writer, "{}:{}: {}", location.file(), location.line(), info.message()),
+        None => writeln!(writer, "{}", info.message()),
+    };
+    // Keep the recovery shell in the normal colors
+    writer.set_colors(fg, bg);
+}
+
+/// Set once the panic handler has tried to enter the recovery shell
+static PANICKED: AtomicBool = AtomicBool::new(false);
@@ -0,0 +1,300 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use limine::memory_map::{Entry, EntryType};
+use spin::Mutex;
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const MAX_REGIONS: usize = 64;
+const FRAME_SIZE: u64 = 4096;
+
+/// Offset of the bootloader's higher-half direct map (0 until `init_hhdm`)
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Record the HHDM offset reported by the bootloader
+pub fn init_hhdm(offset: u64) {
+    HHDM_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// The HHDM offset, or `None` if the bootloader didn't provide one
+pub fn hhdm_offset() -> Option<u64> {
+    match HHDM_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(offset),
+    }
+}
+
+/// Translate a physical address to its virtual address in the HHDM
+///
+/// Without an HHDM offset this is the identity, which only works by luck,
+/// for low memory the bootloader happens to identity map. Callers that
+/// can't rely on that should check `hhdm_offset` first.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(phys.as_u64() + HHDM_OFFSET.load(Ordering::Relaxed))
+}
+
+/// What a physical memory region holds, as reported by the bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    /// Bootloader data structures; usable once we're done with them
+    BootloaderReclaimable,
+    KernelAndModules,
+    Framebuffer,
+}
+
+impl RegionKind {
+    pub const ALL: [RegionKind; 8] = [
+        RegionKind::Usable,
+        RegionKind::Reserved,
+        RegionKind::AcpiReclaimable,
+        RegionKind::AcpiNvs,
+        RegionKind::BadMemory,
+        RegionKind::BootloaderReclaimable,
+        RegionKind::KernelAndModules,
+        RegionKind::Framebuffer,
+    ];
+
+    fn from_limine(entry_type: EntryType) -> Self {
+        match entry_type {
+            EntryType::USABLE => RegionKind::Usable,
+            EntryType::ACPI_RECLAIMABLE => RegionKind::AcpiReclaimable,
+            EntryType::ACPI_NVS => RegionKind::AcpiNvs,
+            EntryType::BAD_MEMORY => RegionKind::BadMemory,
+            EntryType::BOOTLOADER_RECLAIMABLE => RegionKind::BootloaderReclaimable,
+            EntryType::EXECUTABLE_AND_MODULES => RegionKind::KernelAndModules,
+            EntryType::FRAMEBUFFER => RegionKind::Framebuffer,
+            // Unknown types must be treated as reserved
+            _ => RegionKind::Reserved,
+        }
+    }
+
+    /// Ordinary RAM, as opposed to device memory or holes, so reading it
+    /// has no side effects
+    pub fn is_ram(self) -> bool {
+        !matches!(self, RegionKind::Reserved | RegionKind::BadMemory | RegionKind::Framebuffer)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RegionKind::Usable => "usable",
+            RegionKind::Reserved => "reserved",
+            RegionKind::AcpiReclaimable => "ACPI reclaimable",
+            RegionKind::AcpiNvs => "ACPI NVS",
+            RegionKind::BadMemory => "bad memory",
+            RegionKind::BootloaderReclaimable => "bootloader reclaimable",
+            RegionKind::KernelAndModules => "kernel and modules",
+            RegionKind::Framebuffer => "framebuffer",
+        }
+    }
+}
+
+/// A physical memory region from the bootloader's memory map
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub base: u64,
+    pub length: u64,
+    pub kind: RegionKind,
+}
+
+/// Copy of the bootloader's memory map, sorted by base address
+pub struct MemoryMap {
+    regions: [Option<Region>; MAX_REGIONS],
+    count: usize,
+    /// Entries the bootloader reported beyond `MAX_REGIONS`
+    pub skipped: usize,
+}
+
+impl MemoryMap {
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.count].iter().flatten()
+    }
+
+    /// Total bytes in regions of the given kind
+    pub fn bytes(&self, kind: RegionKind) -> u64 {
+        self.regions().filter(|r| r.kind == kind).map(|r| r.length).sum()
+    }
+
+    /// Whether every byte of `[start, end)` lies in regions whose kind
+    /// passes `allowed`
+    pub fn covers(&self, start: u64, end: u64, allowed: impl Fn(RegionKind) -> bool) -> bool {
+        // Regions are sorted, so walk forward extending the covered prefix
+        let mut covered = start;
+        for region in self.regions().filter(|r| allowed(r.kind)) {
+            if region.base > covered {
+                break;
+            }
+            covered = covered.max(region.base + region.length);
+            if covered >= end {
+                return true;
+            }
+        }
+        covered >= end
+    }
+
+    /// Total bytes covered by the map
+    pub fn total_bytes(&self) -> u64 {
+        self.regions().map(|r| r.length).sum()
+    }
+}
+
+pub static MEMORY_MAP: Mutex<Option<MemoryMap>> = Mutex::new(None);
+
+/// Record the bootloader's memory map, returning the usable byte count
+///
+/// Limine doesn't promise the entries are sorted, so they're sorted by base
+/// address here.
+pub fn init_memory_map(entries: &[&Entry]) -> u64 {
+    let mut map = MemoryMap {
+        regions: [None; MAX_REGIONS],
+        count: 0,
+        skipped: 0,
+    };
+    for entry in entries {
+        if map.count == MAX_REGIONS {
+            map.skipped += 1;
+            continue;
+        }
+        let region = Region {
+            base: entry.base,
+            length: entry.length,
+            kind: RegionKind::from_limine(entry.entry_type),
+        };
+        // Insertion sort: the map is short and this runs once
+        let mut i = map.count;
+        while i > 0 && map.regions[i - 1].is_some_and(|r| r.base > region.base) {
+            map.regions[i] = map.regions[i - 1];
+            i -= 1;
+        }
+        map.regions[i] = Some(region);
+        map.count += 1;
+    }
+
+    let usable = map.bytes(RegionKind::Usable);
+    *MEMORY_MAP.lock() = Some(map);
+    usable
+}
+
+/// Bump allocator handing out 4 KiB frames from the usable regions
+///
+/// Frames are never freed. Only `Usable` regions are used: the kernel image
+/// and bootloader-reclaimable memory have their own region types, so they
+/// stay untouched until they're explicitly reclaimed.
+pub struct FrameAllocator {
+    /// Frame-aligned `[start, end)` ranges, sorted by address
+    ranges: [(u64, u64); MAX_REGIONS],
+    count: usize,
+    /// Index into `ranges` and the next address to hand out within it
+    current: usize,
+    next: u64,
+    allocated: u64,
+    total: u64,
+}
+
+impl FrameAllocator {
+    pub fn new<'a>(regions: impl Iterator<Item = &'a Region>) -> Self {
+        let mut allocator = FrameAllocator {
+            ranges: [(0, 0); MAX_REGIONS],
+            count: 0,
+            current: 0,
+            next: 0,
+            allocated: 0,
+            total: 0,
+        };
+        for region in regions.filter(|r| r.kind == RegionKind::Usable) {
+            // Never hand out frame 0, so a null address is always a bug
+            let start = region.base.max(FRAME_SIZE).next_multiple_of(FRAME_SIZE);
+            let end = (region.base + region.length) & !(FRAME_SIZE - 1);
+            if start >= end || allocator.count == MAX_REGIONS {
+                continue;
+            }
+            allocator.ranges[allocator.count] = (start, end);
+            allocator.count += 1;
+            allocator.total += (end - start) / FRAME_SIZE;
+        }
+        allocator.next = allocator.ranges[0].0;
+        allocator
+    }
+
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        while self.current < self.count {
+            let (_, end) = self.ranges[self.current];
+            if self.next < end {
+                let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(self.next));
+                self.next += FRAME_SIZE;
+                self.allocated += 1;
+                return Some(frame);
+            }
+            self.current += 1;
+            if self.current < self.count {
+                self.next = self.ranges[self.current].0;
+            }
+        }
+        None
+    }
+
+    pub fn allocated(&self) -> u64 {
+        self.allocated
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+pub static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+/// Build the frame allocator from the recorded memory map
+///
+/// Returns the number of frames available, or `None` without a memory map.
+pub fn init_frame_allocator() -> Option<u64> {
+    let allocator = FrameAllocator::new(MEMORY_MAP.lock().as_ref()?.regions());
+    let total = allocator.total();
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+    Some(total)
+}
+
+/// Allocate a 4 KiB physical frame
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate()
+}
+
+/// `(allocated, total)` frame counts, or `None` before initialization
+pub fn frame_counts() -> Option<(u64, u64)> {
+    FRAME_ALLOCATOR.lock().as_ref().map(|a| (a.allocated(), a.total()))
+}
+
+/// Check the allocator against a small synthetic map
+///
+/// Nothing is written to the frames, so this doesn't touch real memory.
+pub fn frame_allocator_self_test() -> bool {
+    const FRAMES: u64 = 8;
+    // Two unaligned usable regions around a reserved hole, listed out of order
+    let regions = [
+        Region { base: 0x20_0800, length: 4 * FRAME_SIZE, kind: RegionKind::Usable },
+        Region { base: 0x10_0000, length: 5 * FRAME_SIZE, kind: RegionKind::Usable },
+        Region { base: 0x18_0000, length: 16 * FRAME_SIZE, kind: RegionKind::Reserved },
+    ];
+    let mut sorted = regions;
+    sorted.sort_unstable_by_key(|r| r.base);
+    let mut allocator = FrameAllocator::new(sorted.iter());
+    if allocator.total() != FRAMES {
+        return false;
+    }
+
+    let mut seen = [0u64; FRAMES as usize];
+    for i in 0..FRAMES as usize {
+        let Some(frame) = allocator.allocate() else {
+            return false;
+        };
+        let addr = frame.start_address().as_u64();
+        if seen[..i].contains(&addr) || (0x18_0000..0x19_0000).contains(&addr) {
+            return false;
+        }
+        seen[i] = addr;
+    }
+    allocator.allocate().is_none() && allocator.allocated() == FRAMES
+}
@@ -0,0 +1,208 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Button bits in `MousePacket::buttons`
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_MIDDLE: u8 = 1 << 2;
+
+/// One decoded PS/2 mouse movement report
+#[derive(Debug, Clone, Copy)]
+pub struct MousePacket {
+    /// Movement to the right, in mouse counts
+    pub dx: i16,
+    /// Movement upwards, in mouse counts
+    pub dy: i16,
+    pub buttons: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError {
+    /// The controller or device never answered
+    Timeout,
+    /// The device answered a command with something other than ACK
+    NoAck(u8),
+}
+
+const PACKET_QUEUE_LEN: usize = 64;
+
+struct PacketQueue {
+    packets: [MousePacket; PACKET_QUEUE_LEN],
+    read_pos: usize,
+    count: usize,
+}
+
+impl PacketQueue {
+    const fn new() -> Self {
+        PacketQueue {
+            packets: [MousePacket { dx: 0, dy: 0, buttons: 0 }; PACKET_QUEUE_LEN],
+            read_pos: 0,
+            count: 0,
+        }
+    }
+
+    /// Queue a packet, dropping it if the queue is full
+    fn push(&mut self, packet: MousePacket) {
+        if self.count < PACKET_QUEUE_LEN {
+            self.packets[(self.read_pos + self.count) % PACKET_QUEUE_LEN] = packet;
+            self.count += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<MousePacket> {
+        if self.count == 0 {
+            return None;
+        }
+        let packet = self.packets[self.read_pos];
+        self.read_pos = (self.read_pos + 1) % PACKET_QUEUE_LEN;
+        self.count -= 1;
+        Some(packet)
+    }
+}
+
+static PACKETS: Mutex<PacketQueue> = Mutex::new(PacketQueue::new());
+
+/// Set once `init` found a responding mouse
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Bytes of the packet being assembled by the IRQ handler
+static PARTIAL: Mutex<([u8; 3], usize)> = Mutex::new(([0; 3], 0));
+
+/// Bytes discarded while resynchronizing to packet boundaries
+static RESYNCS: AtomicU32 = AtomicU32::new(0);
+
+const TIMEOUT_SPINS: u32 = 100_000;
+
+fn wait_input_empty() -> Result<(), MouseError> {
+    let mut status = Port::<u8>::new(0x64);
+    for _ in 0..TIMEOUT_SPINS {
+        if unsafe { status.read() } & 0x02 == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(MouseError::Timeout)
+}
+
+/// Status register bit set when the output byte came from the aux port
+const STATUS_AUX_DATA: u8 = 0x20;
+
+fn read_data() -> Result<u8, MouseError> {
+    let mut status = Port::<u8>::new(0x64);
+    for _ in 0..TIMEOUT_SPINS {
+        if unsafe { status.read() } & 0x01 != 0 {
+            return Ok(unsafe { Port::<u8>::new(0x60).read() });
+        }
+        core::hint::spin_loop();
+    }
+    Err(MouseError::Timeout)
+}
+
+/// Like `read_data`, but skip keyboard bytes until one from the mouse
+/// arrives, so a keypress during init can't pass for a reply
+fn read_aux_data() -> Result<u8, MouseError> {
+    let mut status = Port::<u8>::new(0x64);
+    for _ in 0..TIMEOUT_SPINS {
+        let flags = unsafe { status.read() };
+        if flags & 0x01 != 0 {
+            let byte = unsafe { Port::<u8>::new(0x60).read() };
+            if flags & STATUS_AUX_DATA != 0 {
+                return Ok(byte);
+            }
+        }
+        core::hint::spin_loop();
+    }
+    Err(MouseError::Timeout)
+}
+
+fn controller_command(command: u8) -> Result<(), MouseError> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(0x64).write(command) };
+    Ok(())
+}
+
+fn write_data(value: u8) -> Result<(), MouseError> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(0x60).write(value) };
+    Ok(())
+}
+
+/// Send a command byte to the mouse (via the controller's aux port)
+fn mouse_command(command: u8) -> Result<(), MouseError> {
+    controller_command(0xD4)?;
+    write_data(command)?;
+    match read_aux_data()? {
+        0xFA => Ok(()),
+        other => Err(MouseError::NoAck(other)),
+    }
+}
+
+/// Enable the PS/2 aux port and start the mouse streaming packets
+///
+/// Must run with interrupts disabled and IRQ12 still masked, since the
+/// replies are polled. Unmask IRQ12 afterwards if this succeeds.
+pub fn init() -> Result<(), MouseError> {
+    // Enable the aux port
+    controller_command(0xA8)?;
+
+    // Turn on IRQ12 (bit 1) and the aux clock (clear bit 5)
+    controller_command(0x20)?;
+    let config = read_data()?;
+    controller_command(0x60)?;
+    write_data((config | 0x02) & !0x20)?;
+
+    // Defaults (100 samples/s, no scaling), then enable streaming
+    mouse_command(0xF6)?;
+    mouse_command(0xF4)?;
+
+    PRESENT.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// Next queued movement report, if any
+pub fn poll() -> Option<MousePacket> {
+    PACKETS.lock().pop()
+}
+
+/// IRQ12 handler
+pub fn handle_irq() {
+    let byte: u8 = unsafe { Port::new(0x60).read() };
+    let mut partial = PARTIAL.lock();
+    let (bytes, len) = &mut *partial;
+
+    // Bit 3 is always set in the first byte; anything else means we lost
+    // track of packet boundaries, so skip bytes until we find one
+    if *len == 0 && byte & 0x08 == 0 {
+        RESYNCS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    bytes[*len] = byte;
+    *len += 1;
+    if *len < 3 {
+        return;
+    }
+    *len = 0;
+
+    let flags = bytes[0];
+    // Overflowed movement values are meaningless
+    if flags & 0xC0 != 0 {
+        return;
+    }
+    // 9-bit two's complement; the sign bits live in the first byte
+    let dx = bytes[1] as i16 - (((flags as i16) << 4) & 0x100);
+    let dy = bytes[2] as i16 - (((flags as i16) << 3) & 0x100);
+    PACKETS.lock().push(MousePacket {
+        dx,
+        dy,
+        buttons: flags & 0x07,
+    });
+}
+
+/// Bytes dropped while resynchronizing to packet boundaries
+pub fn resync_count() -> u32 {
+    RESYNCS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,130 @@
+// Editing the active 4-level page tables.
+//
+// The kernel keeps running on the tables Limine built: CR3 is read, never
+// replaced. They are reached through the HHDM, so nothing here works until
+// `memory::init_hhdm` has run, and intermediate tables come from the frame
+// allocator. Only 4 KiB pages are mapped; huge pages the bootloader set up
+// are left alone.
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+
+/// Where `self_test` maps its scratch page, past the heap and back buffer
+const SELF_TEST_PAGE: u64 = 0xffff_c200_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The HHDM offset is unknown, so the page tables can't be edited
+    NoHhdm,
+    OutOfFrames,
+    /// Part of the range is already mapped (or covered by a huge page)
+    AlreadyMapped,
+    /// Nothing is mapped there, or it's part of a huge page
+    NotMapped,
+}
+
+/// The active page tables, reached through the HHDM
+fn page_tables() -> Option<OffsetPageTable<'static>> {
+    let offset = memory::hhdm_offset()?;
+    let (pml4_frame, _) = Cr3::read();
+    let pml4_virt = memory::phys_to_virt(pml4_frame.start_address());
+    unsafe {
+        let pml4 = &mut *pml4_virt.as_mut_ptr::<PageTable>();
+        Some(OffsetPageTable::new(pml4, VirtAddr::new(offset)))
+    }
+}
+
+/// Feeds the page table code frames from the global frame allocator
+struct GlobalFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        memory::allocate_frame()
+    }
+}
+
+/// Translate a virtual address through the active page tables
+///
+/// Returns `None` if the address is unmapped or the HHDM offset is unknown
+/// (the page tables themselves are only reachable through the HHDM).
+pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+    page_tables()?.translate_addr(virt)
+}
+
+/// Map the 4 KiB page at `virt` to the frame at `phys`
+///
+/// Both addresses are rounded down to a page boundary. Missing
+/// intermediate tables are allocated, taking the present, writable and
+/// user bits of `flags` so they don't restrict the new entry.
+pub fn map_page(virt: VirtAddr, phys: PhysAddr, flags: PageTableFlags) -> Result<(), MapError> {
+    let mut tables = page_tables().ok_or(MapError::NoHhdm)?;
+    let page = Page::<Size4KiB>::containing_address(virt);
+    let frame = PhysFrame::containing_address(phys);
+    let flush = unsafe { tables.map_to(page, frame, flags, &mut GlobalFrames) }.map_err(|e| match e {
+        MapToError::FrameAllocationFailed => MapError::OutOfFrames,
+        MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => MapError::AlreadyMapped,
+    })?;
+    flush.flush();
+    Ok(())
+}
+
+/// Remove the 4 KiB page at `virt`, returning the frame it pointed to
+///
+/// The frame isn't freed (the frame allocator can't take frames back), and
+/// intermediate tables stay in place even if they end up empty.
+pub fn unmap_page(virt: VirtAddr) -> Result<PhysFrame, MapError> {
+    let mut tables = page_tables().ok_or(MapError::NoHhdm)?;
+    let page = Page::<Size4KiB>::containing_address(virt);
+    let (frame, flush) = tables.unmap(page).map_err(|e| match e {
+        UnmapError::PageNotMapped | UnmapError::ParentEntryHugePage | UnmapError::InvalidFrameAddress(_) => {
+            MapError::NotMapped
+        }
+    })?;
+    flush.flush();
+    Ok(frame)
+}
+
+/// Back `count` pages starting at `start` with fresh frames
+///
+/// `start` must be page aligned. The frames aren't zeroed.
+pub fn map_pages(start: VirtAddr, count: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    let first = Page::<Size4KiB>::containing_address(start);
+    for page in Page::range(first, first + count) {
+        let frame = memory::allocate_frame().ok_or(MapError::OutOfFrames)?;
+        map_page(page.start_address(), frame.start_address(), flags)?;
+    }
+    Ok(())
+}
+
+/// Map a fresh frame at a fixed address, check writes through it land in
+/// that frame, then unmap it again
+///
+/// Uses up one frame (and possibly a few for page tables) for good.
+pub fn self_test() -> bool {
+    const PATTERN: u64 = 0x9a61_0c0d_e5e1_f7e5;
+    let Some(frame) = memory::allocate_frame() else {
+        return false;
+    };
+    let virt = VirtAddr::new(SELF_TEST_PAGE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if map_page(virt, frame.start_address(), flags).is_err() {
+        return false;
+    }
+
+    let ptr = virt.as_mut_ptr::<u64>();
+    // The same frame seen through the HHDM
+    let alias = memory::phys_to_virt(frame.start_address()).as_ptr::<u64>();
+    let ok = unsafe {
+        ptr.write_volatile(PATTERN);
+        ptr.add(511).write_volatile(!PATTERN);
+        ptr.read_volatile() == PATTERN && alias.add(511).read_volatile() == !PATTERN
+    } && translate(virt) == Some(frame.start_address());
+
+    unmap_page(virt) == Ok(frame) && translate(virt).is_none() && ok
+}
@@ -0,0 +1,105 @@
+// MBR partition tables.
+//
+// Only the four primary entries are parsed; extended partitions and GPT
+// protective entries show up as ordinary entries with their type byte.
+
+use crate::block_device::{check_range, BlockDevice, BlockError, BlockResult, BLOCK_SIZE};
+
+const TABLE_OFFSET: usize = 446;
+const ENTRY_LEN: usize = 16;
+const SIGNATURE_OFFSET: usize = 510;
+
+/// A primary partition entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub bootable: bool,
+    /// Partition type (e.g. 0x83 for Linux, 0x0C for FAT32 LBA)
+    pub type_byte: u8,
+    pub start_lba: u32,
+    pub sector_count: u32,
+}
+
+impl Partition {
+    /// Parse one 16-byte entry; type 0 or a zero length means unused
+    fn parse(entry: &[u8]) -> Option<Partition> {
+        let le32 = |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+        let partition = Partition {
+            bootable: entry[0] & 0x80 != 0,
+            type_byte: entry[4],
+            start_lba: le32(8),
+            sector_count: le32(12),
+        };
+        (partition.type_byte != 0 && partition.sector_count != 0).then_some(partition)
+    }
+}
+
+/// Read the primary partition table from block 0 of `dev`
+///
+/// Fails with `NoPartitionTable` if the 0x55AA signature is missing.
+pub fn read_partitions(dev: &impl BlockDevice) -> BlockResult<[Option<Partition>; 4]> {
+    let mut block = [0u8; BLOCK_SIZE];
+    dev.read_block(0, &mut block)?;
+    if block[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2] != [0x55, 0xAA] {
+        return Err(BlockError::NoPartitionTable);
+    }
+
+    let mut partitions = [None; 4];
+    for (slot, entry) in partitions
+        .iter_mut()
+        .zip(block[TABLE_OFFSET..SIGNATURE_OFFSET].chunks_exact(ENTRY_LEN))
+    {
+        *slot = Partition::parse(entry);
+    }
+    Ok(partitions)
+}
+
+/// One partition of a device, with block ids relative to its start
+pub struct PartitionedDevice<D: BlockDevice> {
+    device: D,
+    start: u64,
+    block_count: u64,
+}
+
+impl<D: BlockDevice> PartitionedDevice<D> {
+    /// Fails with `OutOfBounds` if the partition extends past the device
+    pub fn new(device: D, partition: &Partition) -> BlockResult<Self> {
+        let start = partition.start_lba as u64;
+        let block_count = partition.sector_count as u64;
+        check_range(start, block_count, device.block_count())?;
+        Ok(PartitionedDevice {
+            device,
+            start,
+            block_count,
+        })
+    }
+
+    fn remap(&self, block_id: u64) -> BlockResult<u64> {
+        if block_id >= self.block_count {
+            return Err(BlockError::OutOfBounds);
+        }
+        Ok(self.start + block_id)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionedDevice<D> {
+    fn read_block(&self, block_id: u64, buffer: &mut [u8; BLOCK_SIZE]) -> BlockResult<()> {
+        self.device.read_block(self.remap(block_id)?, buffer)
+    }
+
+    fn write_block(&mut self, block_id: u64, buffer: &[u8; BLOCK_SIZE]) -> BlockResult<()> {
+        let id = self.remap(block_id)?;
+        self.device.write_block(id, buffer)
+    }
+
+    fn flush(&mut self) -> BlockResult<()> {
+        self.device.flush()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
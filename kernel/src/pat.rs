@@ -0,0 +1,215 @@
+// Page Attribute Table control for the framebuffer mapping.
+//
+// Write-combining (WC) lets the CPU batch framebuffer stores into
+// full-line bursts instead of issuing one uncached bus write per pixel,
+// which is usually an order of magnitude faster. The trade-off is that WC
+// writes are weakly ordered and may sit in the CPU's fill buffers for a
+// while: pixels can reach the screen out of order or slightly late. That
+// is harmless for a text console, which never reads its own output back
+// for correctness, but anything that needs pixels visible at a precise
+// point must issue an `sfence`. WC does nothing for reads, so `scroll_up`
+// copying from VRAM stays slow either way.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::cpu;
+use crate::memory;
+
+const IA32_PAT: u32 = 0x277;
+
+const PRESENT: u64 = 1 << 0;
+const PWT: u64 = 1 << 3;
+const PCD: u64 = 1 << 4;
+const HUGE_PAGE: u64 = 1 << 7;
+const PAT_4K: u64 = 1 << 7;
+const PAT_HUGE: u64 = 1 << 12;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// PAT memory type encoding for write-combining
+pub const MT_WC: u8 = 0x01;
+
+/// PAT entry we program to WC if the bootloader didn't provide one
+const SPARE_PAT_INDEX: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatError {
+    /// The CPU doesn't implement the Page Attribute Table
+    Unsupported,
+    /// No HHDM offset, so the page tables can't be reached
+    NoHhdm,
+    /// The address isn't mapped by the active page tables
+    NotMapped,
+}
+
+/// Outcome of re-typing a mapping
+pub struct RemapResult {
+    /// Pages whose memory type was changed
+    pub changed: usize,
+    /// Huge pages left alone because they extend past the requested range
+    pub skipped: usize,
+}
+
+/// PAT index of the framebuffer pages before WC was applied
+static ORIGINAL_INDEX: Mutex<Option<u8>> = Mutex::new(None);
+
+/// Whether the CPU supports the PAT (CPUID.01h:EDX[16])
+pub fn supported() -> bool {
+    cpu::has(&cpu::PAT)
+}
+
+pub fn memory_type_name(memory_type: u8) -> &'static str {
+    match memory_type {
+        0x00 => "UC",
+        0x01 => "WC",
+        0x04 => "WT",
+        0x05 => "WP",
+        0x06 => "WB",
+        0x07 => "UC-",
+        _ => "reserved",
+    }
+}
+
+fn read_pat() -> u64 {
+    unsafe { Msr::new(IA32_PAT).read() }
+}
+
+fn pat_entry(pat: u64, index: u8) -> u8 {
+    ((pat >> (index * 8)) & 0x07) as u8
+}
+
+/// Find a PAT index configured as WC, programming the spare entry if needed
+fn wc_index() -> u8 {
+    let pat = read_pat();
+    if let Some(index) = (0..8).find(|&i| pat_entry(pat, i) == MT_WC) {
+        return index;
+    }
+
+    let shift = SPARE_PAT_INDEX * 8;
+    let new_pat = (pat & !(0xFF << shift)) | ((MT_WC as u64) << shift);
+    without_interrupts(|| unsafe {
+        core::arch::asm!("wbinvd", options(nostack));
+        Msr::new(IA32_PAT).write(new_pat);
+        core::arch::asm!("wbinvd", options(nostack));
+        tlb::flush_all();
+    });
+    SPARE_PAT_INDEX
+}
+
+/// Locate the leaf page-table entry that maps `virt`, and the page size
+fn leaf_entry(virt: u64) -> Result<(*mut u64, u64), PatError> {
+    memory::hhdm_offset().ok_or(PatError::NoHhdm)?;
+
+    let (pml4, _) = Cr3::read();
+    let mut table = pml4.start_address().as_u64();
+    let indices = [(virt >> 39) & 0x1FF, (virt >> 30) & 0x1FF, (virt >> 21) & 0x1FF, (virt >> 12) & 0x1FF];
+
+    for (level, &index) in indices.iter().enumerate() {
+        let base = memory::phys_to_virt(PhysAddr::new(table)).as_u64() as *mut u64;
+        let ptr = unsafe { base.add(index as usize) };
+        let entry = unsafe { ptr.read_volatile() };
+        if entry & PRESENT == 0 {
+            return Err(PatError::NotMapped);
+        }
+        match level {
+            1 if entry & HUGE_PAGE != 0 => return Ok((ptr, 1 << 30)),
+            2 if entry & HUGE_PAGE != 0 => return Ok((ptr, 1 << 21)),
+            3 => return Ok((ptr, 4096)),
+            _ => table = entry & ADDR_MASK,
+        }
+    }
+    Err(PatError::NotMapped)
+}
+
+fn entry_index(entry: u64, page_size: u64) -> u8 {
+    let pat_bit = if page_size == 4096 { PAT_4K } else { PAT_HUGE };
+    (((entry & pat_bit != 0) as u8) << 2) | (((entry & PCD != 0) as u8) << 1) | ((entry & PWT != 0) as u8)
+}
+
+fn with_index(entry: u64, page_size: u64, index: u8) -> u64 {
+    let pat_bit = if page_size == 4096 { PAT_4K } else { PAT_HUGE };
+    let mut entry = entry & !(pat_bit | PCD | PWT);
+    if index & 4 != 0 {
+        entry |= pat_bit;
+    }
+    if index & 2 != 0 {
+        entry |= PCD;
+    }
+    if index & 1 != 0 {
+        entry |= PWT;
+    }
+    entry
+}
+
+/// Memory type currently used by the mapping of `virt`
+pub fn memory_type(virt: u64) -> Result<u8, PatError> {
+    if !supported() {
+        return Err(PatError::Unsupported);
+    }
+    let (ptr, page_size) = leaf_entry(virt)?;
+    let index = entry_index(unsafe { ptr.read_volatile() }, page_size);
+    Ok(pat_entry(read_pat(), index))
+}
+
+/// Set every page in `[start, start + len)` to PAT index `index`
+fn remap(start: u64, len: u64, index: u8) -> Result<RemapResult, PatError> {
+    let end = start + len;
+    let mut result = RemapResult { changed: 0, skipped: 0 };
+    let mut addr = start & !0xFFF;
+
+    without_interrupts(|| {
+        while addr < end {
+            let (ptr, page_size) = leaf_entry(addr)?;
+            let page_start = addr & !(page_size - 1);
+            let page_end = page_start + page_size;
+
+            // A huge page reaching outside the range may cover other devices
+            if page_size > 4096 && (page_start < start & !0xFFF || page_end > (end + 0xFFF) & !0xFFF) {
+                result.skipped += 1;
+            } else {
+                unsafe {
+                    let entry = ptr.read_volatile();
+                    ptr.write_volatile(with_index(entry, page_size, index));
+                }
+                tlb::flush(VirtAddr::new(page_start));
+                result.changed += 1;
+            }
+            addr = page_end;
+        }
+        unsafe { core::arch::asm!("wbinvd", options(nostack)) };
+        Ok(result)
+    })
+}
+
+/// Switch the mapping of `[start, start + len)` to write-combining, or
+/// restore the memory type it had before
+pub fn set_write_combining(start: u64, len: u64, enable: bool) -> Result<RemapResult, PatError> {
+    if !supported() {
+        return Err(PatError::Unsupported);
+    }
+
+    let mut original = ORIGINAL_INDEX.lock();
+    if enable {
+        let (ptr, page_size) = leaf_entry(start)?;
+        let current = entry_index(unsafe { ptr.read_volatile() }, page_size);
+        let wc = wc_index();
+        let result = remap(start, len, wc)?;
+        if original.is_none() {
+            *original = Some(current);
+        }
+        Ok(result)
+    } else {
+        match *original {
+            Some(index) => {
+                let result = remap(start, len, index)?;
+                *original = None;
+                Ok(result)
+            }
+            None => Ok(RemapResult { changed: 0, skipped: 0 }),
+        }
+    }
+}
@@ -72,6 +72,43 @@ pub fn unmask_irq(irq: u8) {
     }
 }
 
+/// OCW3 command: the next read of the command port returns the ISR
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// Combined in-service registers: master in bits 0-7, slave in bits 8-15
+///
+/// A set bit means that IRQ has been delivered and not yet EOI'd.
+pub fn read_isr() -> u16 {
+    unsafe {
+        let mut pic1_cmd = Port::<u8>::new(PIC1_CMD);
+        let mut pic2_cmd = Port::<u8>::new(PIC2_CMD);
+        pic1_cmd.write(OCW3_READ_ISR);
+        pic2_cmd.write(OCW3_READ_ISR);
+        (pic2_cmd.read() as u16) << 8 | pic1_cmd.read() as u16
+    }
+}
+
+/// Disable an IRQ line again
+///
+/// Masking a slave IRQ leaves the cascade (IRQ2) alone, since other slave
+/// lines may still be in use.
+pub fn mask_irq(irq: u8) {
+    unsafe {
+        let (mut port, bit) = if irq < 8 {
+            (Port::<u8>::new(PIC1_DATA), irq)
+        } else {
+            (Port::<u8>::new(PIC2_DATA), irq - 8)
+        };
+        let mask = port.read();
+        port.write(mask | (1 << bit));
+    }
+}
+
+/// Current `(master, slave)` mask bytes; a set bit means the IRQ is masked
+pub fn get_masks() -> (u8, u8) {
+    unsafe { (Port::<u8>::new(PIC1_DATA).read(), Port::<u8>::new(PIC2_DATA).read()) }
+}
+
 pub fn send_eoi(vector: u8) {
     unsafe {
         if vector >= PIC2_OFFSET {
@@ -0,0 +1,107 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::keyboard;
+use crate::latency;
+
+/// PIT input clock in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// Programmed channel 0 rate in Hz (0 until `init`)
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// IRQ0 interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether IRQ0 is driven by the HPET rather than channel 0
+static HPET_SOURCE: AtomicBool = AtomicBool::new(false);
+
+/// Which timer generates the IRQ0 ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    Hpet,
+}
+
+impl TickSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            TickSource::Pit => "PIT",
+            TickSource::Hpet => "HPET",
+        }
+    }
+}
+
+/// Program PIT channel 0 as a rate generator firing IRQ0 at `hz`
+pub fn init(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz).clamp(1, 0xFFFF) as u16;
+    unsafe {
+        // Channel 0, lobyte/hibyte, mode 2 (rate generator), binary
+        Port::<u8>::new(0x43).write(0x34);
+        let mut data = Port::<u8>::new(0x40);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+    FREQUENCY.store(PIT_FREQUENCY / divisor as u32, Ordering::Relaxed);
+}
+
+/// Actual IRQ0 rate in Hz after divisor rounding, or 0 if not initialized
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Record that another timer now drives IRQ0 at `hz`
+///
+/// The IRQ0 handler and `frequency` keep working as before, so tick
+/// consumers don't need to know which timer is behind them.
+pub fn set_tick_source(source: TickSource, hz: u32) {
+    HPET_SOURCE.store(source == TickSource::Hpet, Ordering::Relaxed);
+    FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+pub fn tick_source() -> TickSource {
+    if HPET_SOURCE.load(Ordering::Relaxed) {
+        TickSource::Hpet
+    } else {
+        TickSource::Pit
+    }
+}
+
+/// Timer ticks since boot, at `frequency()` per second
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer started, from the tick count
+pub fn uptime_ms() -> u64 {
+    match frequency() {
+        0 => 0,
+        hz => ticks() * 1000 / hz as u64,
+    }
+}
+
+/// Halt until at least `ms` milliseconds' worth of ticks have passed
+///
+/// Needs interrupts enabled, or the tick count never advances; returns
+/// immediately (`false`) if they're disabled or the timer isn't running.
+pub fn sleep_ms(ms: u64) -> bool {
+    let hz = frequency() as u64;
+    if hz == 0 || !interrupts::are_enabled() {
+        return false;
+    }
+    // Whole ticks, rounded up; the first one may be partial, so this is
+    // accurate to within one tick period
+    let target = ticks() + (ms * hz).div_ceil(1000);
+    while ticks() < target {
+        hlt();
+    }
+    true
+}
+
+/// IRQ0 handler
+pub fn handle_irq() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    latency::record();
+    keyboard::auto_repeat(uptime_ms());
+}
@@ -0,0 +1,177 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::hlt;
+use x86_64::instructions::port::Port;
+
+use crate::block_device::{BlockDevice, BlockError, BlockResult};
+use crate::qemu;
+use crate::ramdisk;
+use crate::serial;
+use crate::tsc;
+
+/// Final action taken once the shutdown sequence has quiesced the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    /// Pulse the CPU reset line via the keyboard controller
+    Reboot,
+    /// Power off through the emulator shortcut ports
+    PowerOff,
+}
+
+/// Flush devices and reset the machine
+pub fn reboot() -> ! {
+    shutdown_sequence(PowerAction::Reboot)
+}
+
+/// Flush devices and power off, halting if that isn't possible
+pub fn shutdown() -> ! {
+    shutdown_sequence(PowerAction::PowerOff)
+}
+
+/// Bring the system down in an orderly fashion, then perform `action`
+///
+/// Block devices are flushed first, then pending serial output is drained,
+/// and only then are interrupts disabled. Locks are taken with `try_lock`
+/// so that a shutdown requested while a device is busy cannot deadlock; a
+/// flush failure is logged and the sequence carries on regardless.
+fn shutdown_sequence(action: PowerAction) -> ! {
+    if let Err(e) = sync_devices() {
+        if let Some(mut serial) = serial::SERIAL.try_lock() {
+            let _ = writeln!(serial, "[!] Flush failed during shutdown: {e}");
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        if let Some(mut serial) = serial::SERIAL.try_lock() {
+            serial.drain();
+        }
+    });
+
+    interrupts::disable();
+
+    match action {
+        PowerAction::Reboot => reset(),
+        PowerAction::PowerOff => power_off(),
+    }
+
+    // Safety net: halt if the action didn't take effect
+    loop {
+        hlt();
+    }
+}
+
+/// Flush every registered block device
+///
+/// Uses `try_lock` so that a device held by an interrupted writer reports
+/// `NotReady` instead of deadlocking.
+pub fn sync_devices() -> BlockResult<()> {
+    interrupts::without_interrupts(|| match ramdisk::RAMDISK.try_lock() {
+        Some(mut guard) => guard.as_mut().map_or(Ok(()), |disk| disk.flush()),
+        None => Err(BlockError::NotReady),
+    })
+}
+
+/// Power off without ACPI
+///
+/// Real ACPI needs the \_S5 sleep type from the DSDT's AML. Emulators
+/// accept a fixed value on well-known ports instead: QEMU's PIIX4 PM
+/// block at 0x604, and older QEMU and Bochs at 0xB004. On real hardware
+/// these writes do nothing and the caller halts.
+fn power_off() {
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+    }
+    // An automated QEMU run may only have the debug-exit device
+    qemu::exit_success();
+}
+
+fn reset() {
+    // Write 0xFE to keyboard controller command port to trigger reset
+    unsafe {
+        core::arch::asm!(
+            "out dx, al",
+            in("dx") 0x64u16,
+            in("al") 0xFEu8,
+            options(nomem, nostack)
+        );
+    }
+}
+
+// --- Panic policy ---
+
+/// What the panic handler does after reporting the panic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// Halt forever (the default)
+    Halt = 0,
+    /// Reset after `PANIC_REBOOT_DELAY_MS`, so unattended machines recover
+    Reboot = 1,
+    /// Best effort: drop into the shell if nothing it needs is locked.
+    /// The panicking code's state is abandoned, not unwound, so anything it
+    /// was in the middle of may be left inconsistent.
+    Recover = 2,
+}
+
+impl PanicPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            PanicPolicy::Halt => "halt",
+            PanicPolicy::Reboot => "reboot",
+            PanicPolicy::Recover => "recover",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "halt" => Some(PanicPolicy::Halt),
+            "reboot" => Some(PanicPolicy::Reboot),
+            "recover" => Some(PanicPolicy::Recover),
+            _ => None,
+        }
+    }
+}
+
+/// How long a panic message stays up before a `Reboot` policy resets
+pub const PANIC_REBOOT_DELAY_MS: u64 = 3000;
+
+static PANIC_POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+
+pub fn panic_policy() -> PanicPolicy {
+    match PANIC_POLICY.load(Ordering::Relaxed) {
+        1 => PanicPolicy::Reboot,
+        2 => PanicPolicy::Recover,
+        _ => PanicPolicy::Halt,
+    }
+}
+
+pub fn set_panic_policy(policy: PanicPolicy) {
+    PANIC_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Wait, then reset without going through `shutdown_sequence`
+///
+/// For use from the panic handler: nothing here takes a lock or depends on
+/// interrupts, since either may be unusable after a panic.
+pub fn panic_reboot(delay_ms: u64) -> ! {
+    // Without a calibrated TSC, fall back to a rough fixed spin
+    let ticks = tsc::ticks_per_ms().unwrap_or(1_000_000) * delay_ms;
+    let start = tsc::read();
+    while tsc::read().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+    reset();
+    loop {
+        hlt();
+    }
+}
+
+/// Halt forever with interrupts disabled
+pub fn halt_forever() -> ! {
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
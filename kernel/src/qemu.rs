@@ -0,0 +1,29 @@
+// QEMU isa-debug-exit device.
+//
+// Needs `-device isa-debug-exit,iobase=0xf4,iosize=0x04` on the QEMU
+// command line. QEMU then exits with status `(code << 1) | 1`, so
+// `Success` gives 33 and `Failed` gives 35; a test runner should map 33
+// back to success. Without the device (or on real hardware) the port write
+// is ignored and the caller carries on.
+
+use x86_64::instructions::port::Port;
+
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Ask QEMU to exit; returns only if there is no isa-debug-exit device
+pub fn exit(code: QemuExitCode) {
+    unsafe {
+        Port::<u32>::new(DEBUG_EXIT_PORT).write(code as u32);
+    }
+}
+
+pub fn exit_success() {
+    exit(QemuExitCode::Success);
+}
@@ -1,4 +1,8 @@
-use crate::block_device::{BlockDevice, BlockError, BlockResult, BLOCK_SIZE};
+use crate::block_device::{
+    block_span, check_range, BlockDevice, BlockError, BlockHeat, BlockResult, BLOCK_SIZE,
+};
+use crate::crc32::crc32;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
 /// A simple RAM disk that stores blocks in memory
@@ -10,6 +14,16 @@ pub struct RamDisk {
     storage: &'static mut [u8],
     /// Number of blocks in this RAM disk
     block_count: u64,
+    /// Per-block CRC-32 table used by checked mode
+    crc_table: Option<&'static mut [u32]>,
+    /// Whether reads verify and writes update `crc_table`
+    checked: bool,
+    /// Per-block access counters
+    heat_table: Option<&'static [BlockHeat]>,
+    /// Whether accesses are counted in `heat_table`
+    tracking: bool,
+    /// Whether writes are refused
+    read_only: bool,
 }
 
 impl RamDisk {
@@ -31,6 +45,110 @@ impl RamDisk {
         RamDisk {
             storage,
             block_count,
+            crc_table: None,
+            checked: false,
+            heat_table: None,
+            tracking: false,
+            read_only: false,
+        }
+    }
+
+    /// Attach a CRC table so checked mode can be enabled later
+    ///
+    /// # Panics
+    /// Panics if `table` has fewer entries than the disk has blocks
+    pub fn with_crc_table(mut self, table: &'static mut [u32]) -> Self {
+        assert!(
+            table.len() as u64 >= self.block_count,
+            "CRC table must have an entry per block"
+        );
+        self.crc_table = Some(table);
+        self
+    }
+
+    /// Attach access counters so heat tracking can be enabled later
+    ///
+    /// # Panics
+    /// Panics if `table` has fewer entries than the disk has blocks
+    pub fn with_heat_table(mut self, table: &'static [BlockHeat]) -> Self {
+        assert!(
+            table.len() as u64 >= self.block_count,
+            "heat table must have an entry per block"
+        );
+        self.heat_table = Some(table);
+        self
+    }
+
+    /// Refuse (or allow again) every write with `BlockError::ReadOnly`
+    ///
+    /// Meant for protecting an initrd image from accidental changes.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Enable or disable per-block access counting
+    ///
+    /// Counters keep their values while tracking is off. Fails with
+    /// `NotReady` if no table is attached.
+    pub fn set_tracking(&mut self, enabled: bool) -> BlockResult<()> {
+        if enabled && self.heat_table.is_none() {
+            return Err(BlockError::NotReady);
+        }
+        self.tracking = enabled;
+        Ok(())
+    }
+
+    /// Whether accesses are being counted
+    pub fn is_tracking(&self) -> bool {
+        self.tracking
+    }
+
+    /// The access counters, one per block, if a table is attached
+    pub fn heat(&self) -> Option<&[BlockHeat]> {
+        let table = self.heat_table?;
+        Some(&table[..self.block_count as usize])
+    }
+
+    fn record_read(&self, block_id: u64) {
+        if let (true, Some(table)) = (self.tracking, self.heat_table) {
+            table[block_id as usize].record_read();
+        }
+    }
+
+    /// Enable or disable checked mode
+    ///
+    /// In checked mode each `write_block` stores the block's CRC-32 in the
+    /// table (4 bytes per block) and each `read_block` verifies it, failing
+    /// with `BlockError::CorruptData` on mismatch. Enabling computes the CRC
+    /// of every block up front. Fails with `NotReady` if no table is attached.
+    pub fn set_checked(&mut self, enabled: bool) -> BlockResult<()> {
+        if !enabled {
+            self.checked = false;
+            return Ok(());
+        }
+
+        let table = self.crc_table.as_mut().ok_or(BlockError::NotReady)?;
+        for (id, entry) in table.iter_mut().take(self.block_count as usize).enumerate() {
+            let start = id * BLOCK_SIZE;
+            *entry = crc32(&self.storage[start..start + BLOCK_SIZE]);
+        }
+        self.checked = true;
+        Ok(())
+    }
+
+    /// Whether checked mode is enabled
+    pub fn is_checked(&self) -> bool {
+        self.checked
+    }
+
+    /// Verify a block against its stored CRC (always passes when unchecked)
+    pub fn verify_block(&self, block_id: u64) -> BlockResult<()> {
+        let data = self.get_block(block_id)?;
+        match self.crc_table {
+            Some(ref table) if self.checked && table[block_id as usize] != crc32(data) => {
+                Err(BlockError::CorruptData)
+            }
+            _ => Ok(()),
         }
     }
 
@@ -61,20 +179,80 @@ impl RamDisk {
 
 impl BlockDevice for RamDisk {
     fn read_block(&self, block_id: u64, buffer: &mut [u8; BLOCK_SIZE]) -> BlockResult<()> {
+        self.verify_block(block_id)?;
         let block_data = self.get_block(block_id)?;
         buffer.copy_from_slice(block_data);
+        self.record_read(block_id);
+        Ok(())
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        let count = block_span(buffer.len());
+        check_range(start, count, self.block_count)?;
+
+        // Checked mode has to verify each block individually
+        if self.checked {
+            for id in start..start + count {
+                self.verify_block(id)?;
+            }
+        }
+
+        let offset = start as usize * BLOCK_SIZE;
+        buffer.copy_from_slice(&self.storage[offset..offset + buffer.len()]);
+        for id in start..start + count {
+            self.record_read(id);
+        }
         Ok(())
     }
 
     fn write_block(&mut self, block_id: u64, buffer: &[u8; BLOCK_SIZE]) -> BlockResult<()> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
         let block_data = self.get_block_mut(block_id)?;
         block_data.copy_from_slice(buffer);
+        if let Some(ref mut table) = self.crc_table {
+            if self.checked {
+                table[block_id as usize] = crc32(buffer);
+            }
+        }
+        if let (true, Some(table)) = (self.tracking, self.heat_table) {
+            table[block_id as usize].record_write();
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> BlockResult<()> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let count = block_span(buffer.len());
+        check_range(start, count, self.block_count)?;
+
+        let offset = start as usize * BLOCK_SIZE;
+        self.storage[offset..offset + buffer.len()].copy_from_slice(buffer);
+        if let Some(ref mut table) = self.crc_table {
+            if self.checked {
+                for (i, chunk) in buffer.chunks_exact(BLOCK_SIZE).enumerate() {
+                    table[start as usize + i] = crc32(chunk);
+                }
+            }
+        }
+        if let (true, Some(table)) = (self.tracking, self.heat_table) {
+            for id in start..start + count {
+                table[id as usize].record_write();
+            }
+        }
         Ok(())
     }
 
     fn block_count(&self) -> u64 {
         self.block_count
     }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 // Define a static storage area for the RAM disk
@@ -82,14 +260,50 @@ impl BlockDevice for RamDisk {
 const RAMDISK_SIZE: usize = 1024 * 1024; // 1 MB
 static mut RAMDISK_STORAGE: [u8; RAMDISK_SIZE] = [0; RAMDISK_SIZE];
 
+/// CRC-32 table for checked mode, one entry per block
+static mut RAMDISK_CRC: [u32; RAMDISK_SIZE / BLOCK_SIZE] = [0; RAMDISK_SIZE / BLOCK_SIZE];
+
+/// Access counters for heat tracking, one entry per block
+static RAMDISK_HEAT: [BlockHeat; RAMDISK_SIZE / BLOCK_SIZE] =
+    [const { BlockHeat::new() }; RAMDISK_SIZE / BLOCK_SIZE];
+
 /// Global RAM disk instance wrapped in a mutex for thread safety
 pub static RAMDISK: Mutex<Option<RamDisk>> = Mutex::new(None);
 
+/// Set once `init_from_module` has filled the disk from a boot module
+static FROM_MODULE: AtomicBool = AtomicBool::new(false);
+
 /// Initialize the global RAM disk
 ///
 /// This should be called once during kernel initialization
 pub fn init() {
     let storage = unsafe { &mut RAMDISK_STORAGE };
-    let ramdisk = RamDisk::new(storage);
+    let crc_table = unsafe { &mut *core::ptr::addr_of_mut!(RAMDISK_CRC) };
+    let ramdisk = RamDisk::new(storage)
+        .with_crc_table(crc_table)
+        .with_heat_table(&RAMDISK_HEAT);
     *RAMDISK.lock() = Some(ramdisk);
 }
+
+/// Initialize the global RAM disk with a copy of `data`, e.g. a boot module
+///
+/// The image fills the disk from block 0; a partial last block and the
+/// rest of the disk are zero-filled. Fails with `OutOfBounds`, leaving the
+/// disk uninitialized, if the image is larger than the disk.
+pub fn init_from_module(data: &'static [u8]) -> BlockResult<()> {
+    if data.len() > RAMDISK_SIZE {
+        return Err(BlockError::OutOfBounds);
+    }
+    let storage = unsafe { &mut *core::ptr::addr_of_mut!(RAMDISK_STORAGE) };
+    storage[..data.len()].copy_from_slice(data);
+    storage[data.len()..].fill(0);
+    init();
+    FROM_MODULE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether the RAM disk holds a boot module (the initrd) rather than
+/// starting out blank
+pub fn loaded_from_module() -> bool {
+    FROM_MODULE.load(Ordering::Relaxed)
+}
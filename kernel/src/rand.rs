@@ -0,0 +1,38 @@
+// Seedable pseudo-random numbers for tests and stress commands.
+//
+// xorshift64*: tiny, fast and fully determined by its seed, so a failing
+// run can be reproduced exactly. Not suitable for anything security related.
+
+pub struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    /// A zero seed would get stuck at zero, so it's replaced with a constant
+    pub fn new(seed: u64) -> Self {
+        XorShift64 {
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform-enough value in `0..bound` (`bound` must be nonzero)
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
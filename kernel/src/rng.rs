@@ -0,0 +1,73 @@
+// Random numbers from RDRAND, with a seeded PRNG fallback.
+//
+// The fallback is xorshift64* seeded from the timer tick count and the TSC.
+// Both are guessable, so on CPUs without RDRAND the output is NOT
+// cryptographically secure; it's only good enough for hashing and tests.
+
+use core::arch::x86_64::_rdrand64_step;
+use spin::Mutex;
+
+use crate::cpu;
+use crate::pit;
+use crate::rand::XorShift64;
+use crate::tsc;
+
+/// RDRAND can briefly run dry; Intel suggests 10 retries before giving up
+const RDRAND_RETRIES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Rdrand,
+    /// Seeded PRNG; not cryptographically secure
+    Prng,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Rdrand => "RDRAND",
+            Source::Prng => "PRNG (not cryptographically secure)",
+        }
+    }
+}
+
+/// The fallback generator, seeded on first use
+static FALLBACK: Mutex<Option<XorShift64>> = Mutex::new(None);
+
+/// Where `u64` gets its numbers
+pub fn source() -> Source {
+    if cpu::has(&cpu::RDRAND) {
+        Source::Rdrand
+    } else {
+        Source::Prng
+    }
+}
+
+/// A random `u64`
+///
+/// Returns `None` only if RDRAND is present but keeps failing.
+pub fn u64() -> Option<u64> {
+    match source() {
+        Source::Rdrand => unsafe { rdrand() },
+        Source::Prng => {
+            let mut fallback = FALLBACK.lock();
+            let rng = fallback.get_or_insert_with(|| XorShift64::new(pit::ticks() ^ tsc::read()));
+            Some(rng.next_u64())
+        }
+    }
+}
+
+/// # Safety
+///
+/// The CPU must support RDRAND.
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..RDRAND_RETRIES {
+        // Carry set means a valid value was returned
+        if unsafe { _rdrand64_step(&mut value) } == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
@@ -0,0 +1,107 @@
+// CMOS real-time clock, read through the index/data ports 0x70/0x71.
+//
+// Read-only: the clock is never set and its interrupts stay off. The RTC
+// usually keeps local time, but nothing here knows the time zone.
+
+use core::fmt;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: an update is in progress and the time registers may be torn
+const STATUS_A_UPDATING: u8 = 1 << 7;
+/// Status B: hours are 24-hour rather than 12-hour with a PM bit
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status B: registers hold binary rather than BCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// PM flag in the hours register in 12-hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+/// The year register only has two digits
+const CENTURY: u16 = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+/// Raw time registers, taken once no update is in progress
+fn read_raw() -> [u8; 6] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(read_register)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// The current date and time from the RTC
+pub fn now() -> DateTime {
+    // An update can still start between the flag check and the last read,
+    // so read until two passes agree
+    let (raw, status_b) = without_interrupts(|| {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+    let [second, minute, hour, day, month, year] = raw;
+
+    // The PM bit is never BCD-encoded, so strip it before decoding
+    let pm = status_b & STATUS_B_24_HOUR == 0 && hour & HOUR_PM != 0;
+    let decode = |v: u8| if status_b & STATUS_B_BINARY != 0 { v } else { from_bcd(v) };
+    let mut hour = decode(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12-hour clock: 12 AM is midnight, 12 PM is noon
+        hour = (hour % 12) + if pm { 12 } else { 0 };
+    }
+
+    DateTime {
+        year: CENTURY + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
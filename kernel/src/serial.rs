@@ -2,7 +2,15 @@ use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+use crate::keyboard::KeyBuffer;
+
 const COM1: u16 = 0x3F8;
+const COM2: u16 = 0x2F8;
+
+/// Common baud rates
+pub const BAUD_9600: u32 = 9600;
+pub const BAUD_38400: u32 = 38400;
+pub const BAUD_115200: u32 = 115200;
 
 fn outb(port: u16, val: u8) {
     unsafe {
@@ -18,33 +26,123 @@ fn inb(port: u16) -> u8 {
     val
 }
 
+/// Bytes the UART's transmit FIFO holds once it reports empty
+const TX_FIFO_DEPTH: usize = 16;
+
 pub struct SerialPort {
     port: u16,
+    /// Bytes waiting for the transmitter, drained by the THR-empty interrupt
+    tx: KeyBuffer,
+    /// Whether `write_byte` queues into `tx` (once IRQ4 is set up)
+    buffered: bool,
 }
 
 impl SerialPort {
-    pub fn new(port: u16) -> Self {
+    pub fn new(port: u16, baud: u32) -> Self {
         // Initialize the serial port
         outb(port + 1, 0x00); // Disable all interrupts
-        outb(port + 3, 0x80); // Enable DLAB (set baud rate divisor)
-        outb(port + 0, 0x01); // Set divisor to 1 (115200 baud)
-        outb(port + 1, 0x00); //   (hi byte)
-        outb(port + 3, 0x03); // 8 bits, no parity, one stop bit
+        let mut serial = SerialPort {
+            port,
+            tx: KeyBuffer::new(),
+            buffered: false,
+        };
+        serial.set_baud(baud); // Also selects 8 bits, no parity, one stop bit
         outb(port + 2, 0xC7); // Enable FIFO, clear, 14-byte threshold
         outb(port + 4, 0x0B); // IRQs enabled, RTS/DSR set
 
-        SerialPort { port }
+        serial
+    }
+
+    /// Program the divisor for `baud`, returning the rate actually set
+    ///
+    /// The divisor is 115200 / `baud`, so rates that don't divide 115200
+    /// evenly (or 0) fall back to 115200.
+    pub fn set_baud(&mut self, baud: u32) -> u32 {
+        let baud = match baud {
+            0 => BAUD_115200,
+            b if BAUD_115200 % b != 0 => BAUD_115200,
+            b => b,
+        };
+        let divisor = (BAUD_115200 / baud) as u16;
+        outb(self.port + 3, 0x80); // Enable DLAB (set baud rate divisor)
+        outb(self.port, divisor as u8); // Divisor lo byte
+        outb(self.port + 1, (divisor >> 8) as u8); //   (hi byte)
+        outb(self.port + 3, 0x03); // 8 bits, no parity, one stop bit; DLAB off
+        baud
     }
 
     fn is_transmit_empty(&self) -> bool {
         inb(self.port + 5) & 0x20 != 0
     }
 
+    fn is_transmitter_idle(&self) -> bool {
+        inb(self.port + 5) & 0x40 != 0
+    }
+
+    /// Raise IRQ4 on received data and on the transmitter emptying, and
+    /// start queueing output for the interrupt to send
+    ///
+    /// The IRQ must be routed to `handle_irq` before it is unmasked.
+    pub fn enable_interrupts(&mut self) {
+        outb(self.port + 1, 0x03); // Data available | THR empty
+        self.buffered = true;
+    }
+
+    /// Go back to writing every byte directly, sending anything queued first
+    ///
+    /// For the panic path, where interrupts will never drain the queue.
+    pub fn set_unbuffered(&mut self) {
+        self.flush_queue();
+        self.buffered = false;
+    }
+
+    /// Move queued bytes into the transmit FIFO, if it has emptied
+    fn pump(&mut self) {
+        if !self.is_transmit_empty() {
+            return;
+        }
+        for _ in 0..TX_FIFO_DEPTH {
+            match self.tx.pop() {
+                Some(byte) => outb(self.port, byte),
+                None => break,
+            }
+        }
+    }
+
+    /// Spin until the software queue is empty
+    fn flush_queue(&mut self) {
+        while !self.tx.is_empty() {
+            self.pump();
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait until every queued byte has left the UART's shift register
+    pub fn drain(&mut self) {
+        self.flush_queue();
+        while !self.is_transmitter_idle() {
+            core::hint::spin_loop();
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
-        while !self.is_transmit_empty() {
+        if !self.buffered {
+            while !self.is_transmit_empty() {
+                core::hint::spin_loop();
+            }
+            outb(self.port, byte);
+            return;
+        }
+
+        // When full, make room by spinning rather than dropping output
+        while self.tx.is_full() {
+            self.pump();
             core::hint::spin_loop();
         }
-        outb(self.port, byte);
+        self.tx.push(byte);
+        // THR empty only interrupts on the transition to empty, so an idle
+        // transmitter has to be started from here
+        self.pump();
     }
 }
 
@@ -60,6 +158,72 @@ impl fmt::Write for SerialPort {
     }
 }
 
+/// Writes to COM1, taking `SERIAL` for each write instead of holding it
+///
+/// For long stretches of output like the boot log, so a panic partway
+/// through doesn't find the port locked.
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        x86_64::instructions::interrupts::without_interrupts(|| SERIAL.lock().write_str(s))
+    }
+}
+
 lazy_static! {
-    pub static ref SERIAL: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1));
+    pub static ref SERIAL: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1, BAUD_115200));
+    /// Second port, for logging kept apart from the interactive console
+    pub static ref SERIAL2: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM2, BAUD_115200));
+}
+
+/// Bytes received on COM1, queued by `handle_irq` until the shell polls them
+pub static RX_BUFFER: Mutex<KeyBuffer> = Mutex::new(KeyBuffer::new());
+
+/// IRQ4 handler: queue received bytes and keep the transmitter fed
+///
+/// Received bytes are read straight from the data register, so input keeps
+/// flowing while someone holds `SERIAL`; whoever holds it pumps the
+/// transmit queue on their next write, so a busy lock is simply skipped.
+pub fn handle_irq() {
+    // The IRQ is edge-triggered, so every pending cause has to be cleared
+    // before returning or the UART never raises the line again. Reading
+    // the IIR is what acknowledges THR empty.
+    loop {
+        let iir = inb(COM1 + 2);
+        if iir & 0x01 != 0 {
+            break;
+        }
+        match iir & 0x0E {
+            // Data available, or data left sitting in the FIFO
+            0x04 | 0x0C => {
+                let mut rx = RX_BUFFER.lock();
+                while inb(COM1 + 5) & 0x01 != 0 {
+                    rx.push(inb(COM1));
+                }
+            }
+            0x02 => {
+                if let Some(mut serial) = SERIAL.try_lock() {
+                    serial.pump();
+                }
+            }
+            // Line status: reading the LSR clears it
+            0x06 => {
+                inb(COM1 + 5);
+            }
+            // Modem status: reading the MSR clears it
+            _ => {
+                inb(COM1 + 6);
+            }
+        }
+    }
+}
+
+/// Parse a `baud=` boot argument; only the named rates are accepted
+pub fn parse_baud(arg: &str) -> Option<u32> {
+    match arg {
+        "9600" => Some(BAUD_9600),
+        "38400" => Some(BAUD_38400),
+        "115200" => Some(BAUD_115200),
+        _ => None,
+    }
 }
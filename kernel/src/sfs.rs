@@ -0,0 +1,449 @@
+// Simple writable filesystem ("sfs") on a block device.
+//
+// Deliberately tiny: one flat directory, and every file is a single
+// contiguous run of blocks. Layout:
+//
+//   block 0     superblock: magic, then a free-block bitmap (bit set = used)
+//   blocks 1-4  root directory, 64 entries of 32 bytes:
+//               name (24 bytes, NUL padded), start block (u32), length (u32)
+//   blocks 5..  file data
+//
+// An entry whose name starts with NUL is free. Empty files own no blocks.
+// Rewriting a file moves it to the first free run that fits; nothing is
+// ever defragmented, so a nearly full disk may refuse a write that would
+// fit in total.
+
+use core::fmt;
+
+use crate::block_device::{BlockDevice, BlockError, BLOCK_SIZE};
+
+const MAGIC: &[u8; 4] = b"SFS1";
+/// Offset of the bitmap in the superblock
+const BITMAP_OFFSET: usize = 8;
+/// Blocks the bitmap can describe; any beyond this go unused
+const MAX_BLOCKS: u64 = ((BLOCK_SIZE - BITMAP_OFFSET) * 8) as u64;
+
+pub const MAX_FILES: usize = 64;
+pub const NAME_LEN: usize = 24;
+const ENTRY_SIZE: usize = 32;
+const DIR_START: u64 = 1;
+const DIR_BYTES: usize = MAX_FILES * ENTRY_SIZE;
+/// First block that can hold file data
+const DATA_START: u64 = DIR_START + (DIR_BYTES / BLOCK_SIZE) as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SfsError {
+    Block(BlockError),
+    /// Block 0 doesn't hold an sfs superblock
+    NotFormatted,
+    /// A directory entry's blocks lie outside the data area
+    Corrupt,
+    NotFound,
+    Exists,
+    /// All directory entries are in use
+    DirectoryFull,
+    /// No run of free blocks is long enough
+    NoSpace,
+    /// Empty, longer than `NAME_LEN` bytes, or containing a NUL
+    BadName,
+}
+
+impl From<BlockError> for SfsError {
+    fn from(e: BlockError) -> Self {
+        SfsError::Block(e)
+    }
+}
+
+impl fmt::Display for SfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SfsError::Block(e) => write!(f, "{e}"),
+            SfsError::NotFormatted => write!(f, "No filesystem (run mkfs)"),
+            SfsError::Corrupt => write!(f, "Filesystem is corrupt (run mkfs)"),
+            SfsError::NotFound => write!(f, "File not found"),
+            SfsError::Exists => write!(f, "File already exists"),
+            SfsError::DirectoryFull => write!(f, "Directory full ({MAX_FILES} files)"),
+            SfsError::NoSpace => write!(f, "Not enough contiguous free space"),
+            SfsError::BadName => write!(f, "Names are 1-{NAME_LEN} bytes"),
+        }
+    }
+}
+
+pub type SfsResult<T> = Result<T, SfsError>;
+
+/// A file in the directory
+#[derive(Clone, Copy)]
+pub struct SfsEntry {
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    pub size: u64,
+    /// First data block (0 for an empty file)
+    start: u64,
+}
+
+impl SfsEntry {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("?")
+    }
+
+    fn blocks(&self) -> u64 {
+        self.size.div_ceil(BLOCK_SIZE as u64)
+    }
+
+    /// Whether the file's blocks all lie in the data area below `capacity`
+    fn in_range(&self, capacity: u64) -> bool {
+        self.blocks() == 0 || (self.start >= DATA_START && self.start + self.blocks() <= capacity)
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u64 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as u64
+}
+
+/// The root directory, as stored on disk
+struct Directory([u8; DIR_BYTES]);
+
+impl Directory {
+    fn raw(&self, slot: usize) -> &[u8] {
+        &self.0[slot * ENTRY_SIZE..(slot + 1) * ENTRY_SIZE]
+    }
+
+    fn entry(&self, slot: usize) -> Option<SfsEntry> {
+        let raw = self.raw(slot);
+        let name_len = raw[..NAME_LEN].iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        if name_len == 0 {
+            return None;
+        }
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&raw[..NAME_LEN]);
+        Some(SfsEntry {
+            name,
+            name_len,
+            size: read_u32(raw, 28),
+            start: read_u32(raw, 24),
+        })
+    }
+
+    fn set(&mut self, slot: usize, entry: Option<&SfsEntry>) {
+        let raw = &mut self.0[slot * ENTRY_SIZE..(slot + 1) * ENTRY_SIZE];
+        raw.fill(0);
+        if let Some(entry) = entry {
+            raw[..NAME_LEN].copy_from_slice(&entry.name);
+            raw[24..28].copy_from_slice(&(entry.start as u32).to_le_bytes());
+            raw[28..32].copy_from_slice(&(entry.size as u32).to_le_bytes());
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<(usize, SfsEntry)> {
+        (0..MAX_FILES)
+            .filter_map(|slot| Some((slot, self.entry(slot)?)))
+            .find(|(_, entry)| entry.name() == name)
+    }
+}
+
+/// Iterator over the files, in directory order
+pub struct Entries {
+    dir: Directory,
+    slot: usize,
+}
+
+impl Iterator for Entries {
+    type Item = SfsEntry;
+
+    fn next(&mut self) -> Option<SfsEntry> {
+        while self.slot < MAX_FILES {
+            self.slot += 1;
+            if let Some(entry) = self.dir.entry(self.slot - 1) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+/// The superblock, holding the free-block bitmap
+struct Superblock([u8; BLOCK_SIZE]);
+
+impl Superblock {
+    fn used(&self, block: u64) -> bool {
+        let i = block as usize;
+        self.0[BITMAP_OFFSET + i / 8] & (1 << (i % 8)) != 0
+    }
+
+    fn set_used(&mut self, blocks: core::ops::Range<u64>, used: bool) {
+        for block in blocks {
+            let (byte, bit) = (BITMAP_OFFSET + block as usize / 8, 1 << (block % 8));
+            if used {
+                self.0[byte] |= bit;
+            } else {
+                self.0[byte] &= !bit;
+            }
+        }
+    }
+
+    /// Start of the first run of `count` free blocks below `end`
+    fn find_run(&self, count: u64, end: u64) -> Option<u64> {
+        let mut start = DATA_START;
+        let mut block = DATA_START;
+        while block < end {
+            if self.used(block) {
+                start = block + 1;
+            } else if block + 1 - start == count {
+                return Some(start);
+            }
+            block += 1;
+        }
+        None
+    }
+}
+
+/// Whether block 0 of `dev` holds an sfs superblock
+pub fn is_formatted<D: BlockDevice + ?Sized>(dev: &D) -> bool {
+    let mut block = [0u8; BLOCK_SIZE];
+    dev.read_block(0, &mut block).is_ok() && &block[..4] == MAGIC
+}
+
+/// An sfs filesystem on a block device
+///
+/// Nothing is cached: each call reads the metadata it needs and writes
+/// back whatever it changed, so two `Sfs` values over the same device stay
+/// in step.
+pub struct Sfs<D: BlockDevice> {
+    dev: D,
+}
+
+impl<D: BlockDevice> Sfs<D> {
+    /// Create an empty filesystem on `dev`, replacing whatever was there
+    ///
+    /// Fails with `NoSpace` if the device can't hold the metadata and at
+    /// least one data block.
+    pub fn format(mut dev: D) -> SfsResult<Self> {
+        if dev.block_count() <= DATA_START {
+            return Err(SfsError::NoSpace);
+        }
+        let mut sb = Superblock([0; BLOCK_SIZE]);
+        sb.0[..4].copy_from_slice(MAGIC);
+        sb.set_used(0..DATA_START, true);
+        dev.write_blocks(DIR_START, &[0; DIR_BYTES])?;
+        dev.write_block(0, &sb.0)?;
+        Ok(Sfs { dev })
+    }
+
+    /// Use the filesystem already on `dev`
+    ///
+    /// Fails with `Corrupt` if a file's blocks run outside the data area,
+    /// which the bitmap can't describe.
+    pub fn open(dev: D) -> SfsResult<Self> {
+        if !is_formatted(&dev) {
+            return Err(SfsError::NotFormatted);
+        }
+        let fs = Sfs { dev };
+        let capacity = fs.capacity();
+        if !fs.list()?.all(|entry| entry.in_range(capacity)) {
+            return Err(SfsError::Corrupt);
+        }
+        Ok(fs)
+    }
+
+    /// Blocks available for metadata and data
+    fn capacity(&self) -> u64 {
+        self.dev.block_count().min(MAX_BLOCKS)
+    }
+
+    fn superblock(&self) -> SfsResult<Superblock> {
+        let mut sb = Superblock([0; BLOCK_SIZE]);
+        self.dev.read_block(0, &mut sb.0)?;
+        Ok(sb)
+    }
+
+    fn directory(&self) -> SfsResult<Directory> {
+        let mut dir = Directory([0; DIR_BYTES]);
+        self.dev.read_blocks(DIR_START, &mut dir.0)?;
+        Ok(dir)
+    }
+
+    /// The files in the directory
+    pub fn list(&self) -> SfsResult<Entries> {
+        Ok(Entries { dir: self.directory()?, slot: 0 })
+    }
+
+    /// `(used, total)` blocks, counting the metadata blocks as used
+    pub fn usage(&self) -> SfsResult<(u64, u64)> {
+        let sb = self.superblock()?;
+        let total = self.capacity();
+        Ok(((0..total).filter(|&b| sb.used(b)).count() as u64, total))
+    }
+
+    pub fn find(&self, name: &str) -> SfsResult<Option<SfsEntry>> {
+        Ok(self.directory()?.find(name).map(|(_, entry)| entry))
+    }
+
+    /// Add an empty file called `name`
+    pub fn create(&mut self, name: &str) -> SfsResult<()> {
+        let bytes = name.as_bytes();
+        if bytes.is_empty() || bytes.len() > NAME_LEN || bytes.contains(&0) {
+            return Err(SfsError::BadName);
+        }
+        let mut dir = self.directory()?;
+        if dir.find(name).is_some() {
+            return Err(SfsError::Exists);
+        }
+        let slot = (0..MAX_FILES).find(|&s| dir.entry(s).is_none()).ok_or(SfsError::DirectoryFull)?;
+        let mut entry = SfsEntry { name: [0; NAME_LEN], name_len: bytes.len(), size: 0, start: 0 };
+        entry.name[..bytes.len()].copy_from_slice(bytes);
+        dir.set(slot, Some(&entry));
+        self.dev.write_blocks(DIR_START, &dir.0)?;
+        Ok(())
+    }
+
+    /// Replace the contents of an existing file with `data`
+    ///
+    /// The old blocks count as free while looking for room, so a file can
+    /// be rewritten in place. On `NoSpace` the file is left as it was.
+    pub fn write(&mut self, name: &str, data: &[u8]) -> SfsResult<()> {
+        let mut dir = self.directory()?;
+        let (slot, mut entry) = dir.find(name).ok_or(SfsError::NotFound)?;
+        if data.len() > u32::MAX as usize {
+            return Err(SfsError::NoSpace);
+        }
+        let mut sb = self.superblock()?;
+        sb.set_used(entry.start..entry.start + entry.blocks(), false);
+
+        let count = data.len().div_ceil(BLOCK_SIZE) as u64;
+        let start = match count {
+            0 => 0,
+            _ => sb.find_run(count, self.capacity()).ok_or(SfsError::NoSpace)?,
+        };
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            let mut block = [0u8; BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.dev.write_block(start + i as u64, &block)?;
+        }
+        sb.set_used(start..start + count, true);
+        entry.start = start;
+        entry.size = data.len() as u64;
+        dir.set(slot, Some(&entry));
+        self.dev.write_blocks(DIR_START, &dir.0)?;
+        self.dev.write_block(0, &sb.0)?;
+        Ok(())
+    }
+
+    /// Delete `name` and free its blocks
+    pub fn remove(&mut self, name: &str) -> SfsResult<()> {
+        let mut dir = self.directory()?;
+        let (slot, entry) = dir.find(name).ok_or(SfsError::NotFound)?;
+        let mut sb = self.superblock()?;
+        sb.set_used(entry.start..entry.start + entry.blocks(), false);
+        dir.set(slot, None);
+        self.dev.write_blocks(DIR_START, &dir.0)?;
+        self.dev.write_block(0, &sb.0)?;
+        Ok(())
+    }
+
+    /// Read block `index` of a file's data into `block`, returning how many
+    /// of its bytes belong to the file (0 past the end)
+    pub fn read_chunk(&self, entry: &SfsEntry, index: u64, block: &mut [u8; BLOCK_SIZE]) -> SfsResult<usize> {
+        let offset = index * BLOCK_SIZE as u64;
+        if offset >= entry.size {
+            return Ok(0);
+        }
+        self.dev.read_block(entry.start + index, block)?;
+        Ok((entry.size - offset).min(BLOCK_SIZE as u64) as usize)
+    }
+}
+
+/// Exercise create, write, read-back, delete and block reuse on a small
+/// heap-backed disk
+///
+/// Needs the heap.
+pub fn self_test() -> bool {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use crate::block_device::{check_range, BlockResult};
+
+    struct MemDisk(Vec<u8>);
+
+    impl BlockDevice for MemDisk {
+        fn read_block(&self, block_id: u64, buffer: &mut [u8; BLOCK_SIZE]) -> BlockResult<()> {
+            check_range(block_id, 1, self.block_count())?;
+            let start = block_id as usize * BLOCK_SIZE;
+            buffer.copy_from_slice(&self.0[start..start + BLOCK_SIZE]);
+            Ok(())
+        }
+
+        fn write_block(&mut self, block_id: u64, buffer: &[u8; BLOCK_SIZE]) -> BlockResult<()> {
+            check_range(block_id, 1, self.block_count())?;
+            let start = block_id as usize * BLOCK_SIZE;
+            self.0[start..start + BLOCK_SIZE].copy_from_slice(buffer);
+            Ok(())
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.0.len() / BLOCK_SIZE) as u64
+        }
+    }
+
+    const BLOCKS: usize = 12;
+    let mut disk = MemDisk(vec![0xEE; BLOCKS * BLOCK_SIZE]);
+    if Sfs::open(&mut disk).err() != Some(SfsError::NotFormatted) {
+        return false;
+    }
+    let Ok(mut fs) = Sfs::format(&mut disk) else {
+        return false;
+    };
+
+    // Read back a file spanning a partial second block
+    let data: Vec<u8> = (0..700).map(|i| (i % 251) as u8).collect();
+    let read_back = |fs: &Sfs<&mut MemDisk>, name: &str| -> Option<Vec<u8>> {
+        let entry = fs.find(name).ok()??;
+        let mut out = Vec::new();
+        let mut block = [0u8; BLOCK_SIZE];
+        for index in 0.. {
+            match fs.read_chunk(&entry, index, &mut block).ok()? {
+                0 => break,
+                len => out.extend_from_slice(&block[..len]),
+            }
+        }
+        Some(out)
+    };
+    let written = fs.create("a").is_ok()
+        && fs.create("a") == Err(SfsError::Exists)
+        && fs.write("a", &data).is_ok()
+        && fs.create("b").is_ok()
+        && fs.write("b", b"second").is_ok();
+    let read_ok = read_back(&fs, "a").as_deref() == Some(&data[..])
+        && read_back(&fs, "b").as_deref() == Some(&b"second"[..]);
+    if !written || !read_ok {
+        return false;
+    }
+
+    // A deleted file's blocks go to the next file that fits
+    let Ok(Some(old)) = fs.find("a") else {
+        return false;
+    };
+    let reused = fs.remove("a").is_ok()
+        && fs.find("a").is_ok_and(|e| e.is_none())
+        && fs.create("c").is_ok()
+        && fs.write("c", &data[..600]).is_ok()
+        && fs.find("c").is_ok_and(|e| e.is_some_and(|e| e.start == old.start));
+    if !reused || read_back(&fs, "c").as_deref() != Some(&data[..600]) {
+        return false;
+    }
+
+    // 4 data blocks are free; a failed write leaves the file alone
+    let big = vec![0x5A; 5 * BLOCK_SIZE];
+    let full_ok = fs.write("c", &big) == Err(SfsError::NoSpace)
+        && read_back(&fs, "c").as_deref() == Some(&data[..600])
+        && fs.list().is_ok_and(|files| files.count() == 2)
+        && fs.usage() == Ok((DATA_START + 3, BLOCKS as u64));
+    if !full_ok {
+        return false;
+    }
+
+    // An entry pointing past the bitmap is refused at mount
+    let mut dir = [0u8; BLOCK_SIZE];
+    dir[0] = b'x';
+    dir[24..28].copy_from_slice(&(MAX_BLOCKS as u32).to_le_bytes());
+    dir[28..32].copy_from_slice(&1u32.to_le_bytes());
+    disk.write_block(DIR_START, &dir).is_ok() && Sfs::open(&mut disk).err() == Some(SfsError::Corrupt)
+}
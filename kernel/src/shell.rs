@@ -1,18 +1,69 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::hlt;
+use x86_64::PhysAddr;
 
-use crate::framebuffer;
+use crate::acpi;
+use crate::ansi::AnsiDecoder;
+use crate::console::{self, ConsoleKind, InputRoute};
+use crate::cpu;
+use crate::font;
+use crate::framebuffer::{self, Color};
+use crate::heap;
+use crate::hpet;
 use crate::serial;
+use crate::sfs::{self, Sfs, SfsEntry, SfsError, SfsResult};
+use crate::tarfs::{TarEntry, TarFs};
+use crate::interrupts;
 use crate::keyboard;
+use crate::keymode::{Binding, Dispatch, KeyMap};
+use crate::klog;
+use crate::latency;
+use crate::memory;
+use crate::mouse;
+use crate::paging;
+use crate::pat::{self, PatError};
+use crate::pic;
+use crate::rng;
+use crate::rtc;
+use crate::speaker;
+use crate::syscall;
+use crate::pit;
+use crate::qemu::{self, QemuExitCode};
+use crate::rand;
+use crate::power;
 use crate::ramdisk;
-use crate::block_device::{BlockDevice, BLOCK_SIZE};
+use crate::tsc;
+use crate::block_cache::BlockCache;
+use crate::block_device::{BlockDevice, BlockError, BLOCK_SIZE};
+
+// --- Key conventions ---
+
+/// Ctrl+D (EOF) is the universal "I'm done" key for interactive sub-modes.
+///
+/// Every sub-mode's input loop must check for it and return to the shell,
+/// saving or discarding its work according to that mode's own convention.
+/// At the top-level prompt there is no parent to return to, so it is ignored.
+const KEY_EOF: u8 = 0x04;
+
+/// Ctrl+C, which stops long-running commands and abandons the typed line
+const KEY_INTERRUPT: u8 = 0x03;
+
+/// Ctrl+L: clear the screen, keeping the line being typed
+const KEY_CLEAR: u8 = 0x0C;
 
 // --- LineBuffer: stack-allocated input buffer ---
 
 struct LineBuffer {
     buf: [u8; 256],
     len: usize,
+    /// Edit position as a byte offset, always on a character boundary
+    cursor: usize,
 }
 
 impl LineBuffer {
@@ -20,38 +71,186 @@ impl LineBuffer {
         LineBuffer {
             buf: [0; 256],
             len: 0,
+            cursor: 0,
         }
     }
 
-    fn push(&mut self, byte: u8) -> bool {
-        if self.len < self.buf.len() {
-            self.buf[self.len] = byte;
-            self.len += 1;
-            true
-        } else {
-            false
+    /// Insert `bytes` at the cursor and step past them, if they all fit
+    fn insert(&mut self, bytes: &[u8]) -> bool {
+        let (at, n) = (self.cursor, bytes.len());
+        if self.len + n > self.buf.len() {
+            return false;
         }
+        self.buf.copy_within(at..self.len, at + n);
+        self.buf[at..at + n].copy_from_slice(bytes);
+        self.len += n;
+        self.cursor += n;
+        true
     }
 
-    fn pop(&mut self) -> bool {
-        if self.len > 0 {
-            self.len -= 1;
-            true
+    fn push(&mut self, byte: u8) -> bool {
+        self.insert(&[byte])
+    }
+
+    /// Insert `c` UTF-8 encoded at the cursor, if it fits entirely
+    fn push_char(&mut self, c: char) -> bool {
+        let mut encoded = [0; 4];
+        self.insert(c.encode_utf8(&mut encoded).as_bytes())
+    }
+
+    /// Length in bytes of the character before (`back`) or at the cursor
+    fn char_len(&self, back: bool) -> usize {
+        let text = self.as_str();
+        let c = if back {
+            text[..self.cursor].chars().next_back()
         } else {
-            false
-        }
+            text[self.cursor..].chars().next()
+        };
+        c.map_or(0, char::len_utf8)
+    }
+
+    /// Remove the character before the cursor, returning its length
+    fn pop(&mut self) -> usize {
+        let n = self.char_len(true);
+        self.cursor -= n;
+        self.remove(n);
+        n
+    }
+
+    /// Remove the character at the cursor, returning its length
+    fn delete(&mut self) -> usize {
+        let n = self.char_len(false);
+        self.remove(n);
+        n
+    }
+
+    fn remove(&mut self, n: usize) {
+        self.buf.copy_within(self.cursor + n..self.len, self.cursor);
+        self.len -= n;
+    }
+
+    /// Bytes from the cursor to the end of the line
+    fn tail(&self) -> &str {
+        &self.as_str()[self.cursor..]
+    }
+
+    fn at_end(&self) -> bool {
+        self.cursor == self.len
     }
 
     fn clear(&mut self) {
         self.len = 0;
+        self.cursor = 0;
+    }
+
+    /// Replace the contents with `text`, dropping whole characters that
+    /// don't fit
+    fn set(&mut self, text: &str) {
+        self.clear();
+        for c in text.chars() {
+            if !self.push_char(c) {
+                break;
+            }
+        }
+    }
+
+    fn copy_from(&mut self, other: &LineBuffer) {
+        self.buf[..other.len].copy_from_slice(&other.buf[..other.len]);
+        self.len = other.len;
+        self.cursor = other.len;
     }
 
     fn as_str(&self) -> &str {
-        // All bytes pushed are printable ASCII, so this is safe
+        // Only printable ASCII and whole UTF-8 sequences from `push_char`
+        // are stored, and `pop`/`delete` remove whole characters, so this
+        // is safe
         unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
     }
 }
 
+// --- Command history ---
+
+const HISTORY_SIZE: usize = 64;
+
+/// The last `HISTORY_SIZE` command lines, recalled with Up/Down
+///
+/// Entries live on the heap; without one, nothing is recorded. Recalling
+/// copies an entry into the line being edited, so editing a recalled line
+/// never changes the stored entry.
+struct History {
+    /// Oldest entry first
+    entries: VecDeque<String>,
+    /// How far back Up has gone (1 = newest entry), or 0 when not browsing
+    browsing: usize,
+    /// The line as it was before browsing started, restored by Down
+    draft: String,
+}
+
+impl History {
+    const fn new() -> Self {
+        History {
+            entries: VecDeque::new(),
+            browsing: 0,
+            draft: String::new(),
+        }
+    }
+
+    /// Entry `back` steps back from the newest (1 = newest)
+    fn entry(&self, back: usize) -> &str {
+        &self.entries[self.entries.len() - back]
+    }
+
+    /// Record an entered line; blank lines and repeats of the newest
+    /// entry are skipped
+    fn push(&mut self, line: &LineBuffer) {
+        self.browsing = 0;
+        if !heap::is_ready()
+            || line.as_str().trim().is_empty()
+            || self.entries.back().is_some_and(|newest| newest == line.as_str())
+        {
+            return;
+        }
+        if self.entries.len() == HISTORY_SIZE {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(String::from(line.as_str()));
+    }
+
+    /// Step back (`older`) or forward; returns the line to show, if any
+    fn recall(&mut self, line: &LineBuffer, older: bool) -> Option<LineBuffer> {
+        let mut shown = LineBuffer::new();
+        if older {
+            if self.browsing == self.entries.len() {
+                return None;
+            }
+            if self.browsing == 0 {
+                self.draft.clear();
+                self.draft.push_str(line.as_str());
+            }
+            self.browsing += 1;
+            shown.set(self.entry(self.browsing));
+        } else {
+            match self.browsing {
+                0 => return None,
+                1 => shown.set(&self.draft),
+                n => shown.set(self.entry(n - 1)),
+            }
+            self.browsing -= 1;
+        }
+        Some(shown)
+    }
+}
+
+/// Replace the displayed and buffered line with `new`
+fn replace_line(line: &mut LineBuffer, new: &LineBuffer) {
+    move_cursor(line, line.len);
+    for _ in line.as_str().chars() {
+        do_backspace();
+    }
+    line.copy_from(new);
+    echo_line_text(line.as_str());
+}
+
 // --- FmtBuf: stack-allocated Write target for formatting numbers ---
 
 struct FmtBuf {
@@ -84,19 +283,114 @@ impl Write for FmtBuf {
     }
 }
 
-// --- Output helpers ---
+// --- Input helpers ---
 
-fn echo_byte(byte: u8) {
+/// How often `dmesg -f` checks the log for new lines
+const DMESG_POLL_MS: u64 = 50;
+
+/// Next key from the enabled input consoles
+///
+/// Shift+Page Up/Down are handled here, by scrolling the framebuffer's
+/// scrollback view, so they work wherever input is read. Any other key
+/// snaps the view back to the live screen.
+fn poll_key() -> Option<u8> {
+    let mut key = None;
+    if console::input_enabled(ConsoleKind::Framebuffer) {
+        key = without_interrupts(|| keyboard::KEY_BUFFER.lock().pop()).or_else(next_paste_key);
+    }
+    if key.is_none() && console::input_enabled(ConsoleKind::Serial) {
+        key = poll_serial();
+    }
+    let key = key?;
+
+    let direction = match key {
+        keyboard::KEY_SCROLL_UP => Some(1),
+        keyboard::KEY_SCROLL_DOWN => Some(-1),
+        _ => None,
+    };
     without_interrupts(|| {
-        let mut fb = framebuffer::FRAMEBUFFER.lock();
-        if let Some(ref mut writer) = *fb {
-            writer.write_byte(byte);
+        if let Some(ref mut writer) = *framebuffer::FRAMEBUFFER.lock() {
+            match direction {
+                Some(direction) => {
+                    let page = writer.max_rows().saturating_sub(1).max(1) as isize;
+                    writer.scroll_view(direction * page);
+                }
+                None => writer.snap_to_bottom(),
+            }
         }
     });
+    match direction {
+        Some(_) => None,
+        None => Some(key),
+    }
+}
+
+/// Escape-sequence state for serial input, kept across polls
+static SERIAL_DECODER: Mutex<AnsiDecoder> = Mutex::new(AnsiDecoder::new());
+
+/// Decode waiting serial bytes until a whole key is available
+fn poll_serial() -> Option<u8> {
     without_interrupts(|| {
-        let mut serial = serial::SERIAL.lock();
-        serial.write_byte(byte);
-    });
+        let mut rx = serial::RX_BUFFER.lock();
+        let mut decoder = SERIAL_DECODER.lock();
+        while let Some(byte) = rx.pop() {
+            trigger_feed(byte);
+            if let Some(key) = decoder.feed(byte) {
+                return Some(key);
+            }
+        }
+        None
+    })
+}
+
+/// Wait up to `ms` milliseconds for a key
+///
+/// Spins rather than halting, since no periodic interrupt is guaranteed to
+/// wake us. Without a calibrated TSC this degenerates into a single poll.
+fn wait_key_timeout(ms: u64) -> Option<u8> {
+    let deadline = tsc::ticks_per_ms().unwrap_or(0) * ms;
+    let start = tsc::read();
+    loop {
+        if let Some(key) = poll_key() {
+            return Some(key);
+        }
+        if tsc::read().wrapping_sub(start) >= deadline {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// --- Output helpers ---
+
+fn echo_byte(byte: u8) {
+    if console::output_enabled(ConsoleKind::Framebuffer) {
+        without_interrupts(|| {
+            let mut fb = framebuffer::FRAMEBUFFER.lock();
+            if let Some(ref mut writer) = *fb {
+                writer.write_byte(byte);
+            }
+        });
+    }
+    if console::output_enabled(ConsoleKind::Serial) {
+        without_interrupts(|| {
+            let mut serial = serial::SERIAL.lock();
+            serial.write_byte(byte);
+        });
+    }
+}
+
+/// Send `s` to the serial console only, e.g. terminal control sequences
+/// that have a dedicated framebuffer equivalent
+fn print_serial(s: &str) {
+    if console::output_enabled(ConsoleKind::Serial) {
+        without_interrupts(|| {
+            let mut serial = serial::SERIAL.lock();
+            for &b in s.as_bytes() {
+                serial.write_byte(b);
+            }
+        });
+    }
 }
 
 fn print_str(s: &str) {
@@ -111,166 +405,3921 @@ fn print_prompt() {
 
 fn do_backspace() {
     // Erase on framebuffer
-    without_interrupts(|| {
-        let mut fb = framebuffer::FRAMEBUFFER.lock();
-        if let Some(ref mut writer) = *fb {
-            writer.backspace();
-        }
-    });
+    if console::output_enabled(ConsoleKind::Framebuffer) {
+        without_interrupts(|| {
+            let mut fb = framebuffer::FRAMEBUFFER.lock();
+            if let Some(ref mut writer) = *fb {
+                writer.backspace();
+            }
+        });
+    }
     // Erase on serial: BS, space, BS
-    without_interrupts(|| {
-        let mut serial = serial::SERIAL.lock();
-        serial.write_byte(8);
-        serial.write_byte(b' ');
-        serial.write_byte(8);
-    });
+    if console::output_enabled(ConsoleKind::Serial) {
+        without_interrupts(|| {
+            let mut serial = serial::SERIAL.lock();
+            serial.write_byte(8);
+            serial.write_byte(b' ');
+            serial.write_byte(8);
+        });
+    }
 }
 
-// --- Command dispatch ---
+// --- Pager ---
 
-fn execute(line: &str) {
-    let trimmed = line.trim_start();
-    if trimmed.is_empty() {
-        return;
-    }
+#[derive(Clone, Copy, PartialEq)]
+enum PagerMode {
+    Normal,
+}
 
-    let (cmd, args) = match trimmed.find(' ') {
-        Some(pos) => (&trimmed[..pos], trimmed[pos + 1..].trim_start()),
-        None => (trimmed, ""),
-    };
+#[derive(Clone, Copy)]
+enum PagerAction {
+    NextPage,
+    NextLine,
+    Quit,
+}
 
-    match cmd {
-        "help" => cmd_help(),
-        "clear" => cmd_clear(),
-        "echo" => cmd_echo(args),
-        "info" => cmd_info(),
-        "reboot" => cmd_reboot(),
-        _ => {
-            print_str("Unknown command: ");
-            print_str(cmd);
-            print_str("\n");
+static PAGER_BINDINGS: [Binding<PagerMode, PagerAction>; 8] = [
+    Binding { mode: None, key: b' ', action: PagerAction::NextPage },
+    Binding { mode: None, key: b'f', action: PagerAction::NextPage },
+    Binding { mode: None, key: keyboard::KEY_PAGE_DOWN, action: PagerAction::NextPage },
+    Binding { mode: None, key: b'\n', action: PagerAction::NextLine },
+    Binding { mode: None, key: b'j', action: PagerAction::NextLine },
+    Binding { mode: None, key: keyboard::KEY_DOWN, action: PagerAction::NextLine },
+    Binding { mode: None, key: b'q', action: PagerAction::Quit },
+    Binding { mode: None, key: KEY_EOF, action: PagerAction::Quit },
+];
+
+const PAGER_PROMPT: &str = "-- More -- (space: page, enter: line, q: quit)";
+
+/// Block until a key arrives
+fn wait_key() -> u8 {
+    loop {
+        if let Some(key) = poll_key() {
+            return key;
         }
+        hlt();
     }
 }
 
-fn cmd_help() {
-    print_str("Available commands:\n");
-    print_str("  help    - Show this help message\n");
-    print_str("  clear   - Clear the screen\n");
-    print_str("  echo    - Print text to the screen\n");
-    print_str("  info    - Show system information\n");
-    print_str("  reboot  - Reboot the system\n");
+/// Screen size in text cells, with a classic 80x24 fallback for serial
+fn screen_size() -> (usize, usize) {
+    without_interrupts(|| match *framebuffer::FRAMEBUFFER.lock() {
+        Some(ref writer) => (writer.max_cols(), writer.max_rows()),
+        None => (80, 24),
+    })
 }
 
-fn cmd_clear() {
-    without_interrupts(|| {
-        let mut fb = framebuffer::FRAMEBUFFER.lock();
-        if let Some(ref mut writer) = *fb {
-            writer.clear_screen();
+/// Show `text` one screenful at a time
+fn page(text: &[u8]) {
+    let (cols, rows) = screen_size();
+    let page_rows = rows.saturating_sub(1).max(1);
+    let keymap = KeyMap::new(&PAGER_BINDINGS, PagerMode::Normal);
+
+    let mut lines = text.split(|&b| b == b'\n').peekable();
+    let mut budget = page_rows;
+    while let Some(line) = lines.next() {
+        if lines.peek().is_none() && line.is_empty() {
+            break;
         }
-    });
+        for &b in line {
+            echo_byte(b);
+        }
+        echo_byte(b'\n');
+        // Long lines wrap and take up several rows
+        budget = budget.saturating_sub(line.len().max(1).div_ceil(cols));
+
+        if budget == 0 && lines.peek().is_some() {
+            print_str(PAGER_PROMPT);
+            let action = loop {
+                if let Dispatch::Action(action) = keymap.dispatch(wait_key()) {
+                    break action;
+                }
+            };
+            print_str("\r");
+            for _ in 0..PAGER_PROMPT.len() {
+                print_str(" ");
+            }
+            print_str("\r");
+            match action {
+                PagerAction::NextPage => budget = page_rows,
+                PagerAction::NextLine => budget = 1,
+                PagerAction::Quit => return,
+            }
+        }
+    }
 }
 
-fn cmd_echo(args: &str) {
-    print_str(args);
-    print_str("\n");
+// --- Serial input triggers ---
+
+const MAX_TRIGGER_PATTERN: usize = 32;
+const MAX_TRIGGER_COMMAND: usize = 128;
+
+/// A one-shot "when this string arrives on serial, run that command"
+struct Trigger {
+    pattern: [u8; MAX_TRIGGER_PATTERN],
+    pattern_len: usize,
+    command: [u8; MAX_TRIGGER_COMMAND],
+    command_len: usize,
+    /// The most recent `pattern_len` input bytes, oldest first
+    window: [u8; MAX_TRIGGER_PATTERN],
+    seen: usize,
+    armed: bool,
+    fired: bool,
 }
 
-fn cmd_info() {
-    // Collect framebuffer info into a stack buffer (avoids holding lock while printing)
-    let mut fbuf = FmtBuf::new();
+impl Trigger {
+    const EMPTY: Trigger = Trigger {
+        pattern: [0; MAX_TRIGGER_PATTERN],
+        pattern_len: 0,
+        command: [0; MAX_TRIGGER_COMMAND],
+        command_len: 0,
+        window: [0; MAX_TRIGGER_PATTERN],
+        seen: 0,
+        armed: false,
+        fired: false,
+    };
+
+    fn pattern(&self) -> &str {
+        // Only ever filled from &str
+        unsafe { core::str::from_utf8_unchecked(&self.pattern[..self.pattern_len]) }
+    }
+
+    fn command(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.command[..self.command_len]) }
+    }
+}
 
+static TRIGGER: Mutex<Trigger> = Mutex::new(Trigger::EMPTY);
+
+/// Run one raw serial byte through the armed trigger, if any
+///
+/// The window persists between polls, so a pattern split across several
+/// reads still matches.
+fn trigger_feed(byte: u8) {
+    let mut trigger = TRIGGER.lock();
+    if !trigger.armed {
+        return;
+    }
+    let len = trigger.pattern_len;
+    trigger.window.copy_within(1..len, 0);
+    trigger.window[len - 1] = byte;
+    trigger.seen = (trigger.seen + 1).min(len);
+    if trigger.seen == len && trigger.window[..len] == trigger.pattern[..len] {
+        trigger.armed = false;
+        trigger.fired = true;
+    }
+}
+
+/// The command of a trigger that has fired since the last call
+fn take_fired_trigger() -> Option<(FmtBuf, FmtBuf)> {
     without_interrupts(|| {
-        let fb = framebuffer::FRAMEBUFFER.lock();
-        if let Some(ref writer) = *fb {
-            let _ = write!(
-                fbuf,
-                "Framebuffer: {}x{}\nText grid:   {}x{}\n",
-                writer.width(),
-                writer.height(),
-                writer.max_cols(),
-                writer.max_rows()
-            );
+        let mut trigger = TRIGGER.lock();
+        if !trigger.fired {
+            return None;
         }
-    });
+        trigger.fired = false;
+        let mut pattern = FmtBuf::new();
+        let _ = pattern.write_str(trigger.pattern());
+        let mut command = FmtBuf::new();
+        let _ = command.write_str(trigger.command());
+        Some((pattern, command))
+    })
+}
 
-    // Collect ramdisk info
-    let mut rbuf = FmtBuf::new();
+fn cmd_trigger(args: &str) {
+    if args.is_empty() {
+        without_interrupts(|| {
+            let trigger = TRIGGER.lock();
+            let mut buf = FmtBuf::new();
+            if trigger.armed {
+                let _ = writeln!(buf, "Waiting for \"{}\", then: {}", trigger.pattern(), trigger.command());
+            } else {
+                let _ = writeln!(buf, "No trigger set");
+            }
+            print_str(buf.as_str());
+        });
+        return;
+    }
+    if args == "clear" {
+        without_interrupts(|| *TRIGGER.lock() = Trigger::EMPTY);
+        print_str("Trigger cleared\n");
+        return;
+    }
+
+    // A quoted pattern may contain spaces
+    let (pattern, command) = match args.strip_prefix('"') {
+        Some(rest) => match rest.find('"') {
+            Some(end) => (&rest[..end], rest[end + 1..].trim_start()),
+            None => ("", ""),
+        },
+        None => split_word(args),
+    };
+    if pattern.is_empty() || command.is_empty() {
+        print_str("Usage: trigger <string> <command> | trigger clear\n");
+        return;
+    }
+    if pattern.len() > MAX_TRIGGER_PATTERN || command.len() > MAX_TRIGGER_COMMAND {
+        print_str("Trigger string or command too long\n");
+        return;
+    }
 
     without_interrupts(|| {
-        let rd = ramdisk::RAMDISK.lock();
-        if let Some(ref ramdisk) = *rd {
-            let blocks = ramdisk.block_count();
-            let kb = blocks * BLOCK_SIZE as u64 / 1024;
-            let _ = write!(rbuf, "RAM disk:    {} blocks ({} KB)\n", blocks, kb);
-        }
+        let mut trigger = TRIGGER.lock();
+        *trigger = Trigger::EMPTY;
+        trigger.pattern[..pattern.len()].copy_from_slice(pattern.as_bytes());
+        trigger.pattern_len = pattern.len();
+        trigger.command[..command.len()].copy_from_slice(command.as_bytes());
+        trigger.command_len = command.len();
+        trigger.armed = true;
     });
+    if !console::input_enabled(ConsoleKind::Serial) {
+        print_str("Note: serial input is off; use 'console input serial' or 'all'\n");
+    }
+}
 
-    print_str("ShadowOS v0.1.0\n");
-    print_str(fbuf.as_str());
-    print_str(rbuf.as_str());
+// --- Mouse text selection ---
+
+/// Largest selection that can be copied
+const CLIPBOARD_LIMIT: usize = 4096;
+
+/// Copied text, on the heap, and how much of it a paste has replayed
+struct Clipboard {
+    text: Vec<u8>,
+    /// Next byte `poll_key` replays; `text.len()` once a paste is done
+    paste_pos: usize,
 }
 
-fn cmd_reboot() {
-    print_str("Rebooting...\n");
-    // Write 0xFE to keyboard controller command port to trigger reset
-    unsafe {
-        core::arch::asm!(
-            "out dx, al",
-            in("dx") 0x64u16,
-            in("al") 0xFEu8,
-            options(nomem, nostack)
-        );
+static CLIPBOARD: Mutex<Clipboard> = Mutex::new(Clipboard {
+    text: Vec::new(),
+    paste_pos: 0,
+});
+
+struct Pointer {
+    /// Position in pixels; `None` until the first packet centers it
+    pos: Option<(usize, usize)>,
+    buttons: u8,
+    /// Cell where the current left-button drag started
+    anchor: Option<usize>,
+}
+
+static POINTER: Mutex<Pointer> = Mutex::new(Pointer {
+    pos: None,
+    buttons: 0,
+    anchor: None,
+});
+
+/// Apply queued mouse packets: move the pointer, drag out a selection
+/// with the left button (copied on release), paste with the middle button
+fn handle_mouse() {
+    while let Some(packet) = without_interrupts(mouse::poll) {
+        let paste = without_interrupts(|| {
+            let mut fb = framebuffer::FRAMEBUFFER.lock();
+            let writer = fb.as_mut()?;
+            let mut pointer = POINTER.lock();
+
+            let (w, h) = (writer.width() as isize, writer.height() as isize);
+            let (x, y) = pointer.pos.unwrap_or((w as usize / 2, h as usize / 2));
+            let x = (x as isize + packet.dx as isize).clamp(0, w - 1) as usize;
+            // Mouse y counts upwards, screen y downwards
+            let y = (y as isize - packet.dy as isize).clamp(0, h - 1) as usize;
+            pointer.pos = Some((x, y));
+            let cell = writer.cell_at(x, y)?;
+
+            let pressed = packet.buttons & !pointer.buttons;
+            let released = pointer.buttons & !packet.buttons;
+            pointer.buttons = packet.buttons;
+
+            if pressed & mouse::BUTTON_LEFT != 0 {
+                pointer.anchor = Some(cell);
+            }
+            let selection = match pointer.anchor {
+                Some(anchor) => Some((anchor, cell)),
+                None => writer.selection(),
+            };
+            writer.set_highlight(selection, Some(cell));
+
+            if released & mouse::BUTTON_LEFT != 0 {
+                if pointer.anchor.take() == Some(cell) {
+                    // A click without a drag just drops the selection
+                    writer.set_highlight(None, Some(cell));
+                } else if heap::is_ready() {
+                    let mut clipboard = CLIPBOARD.lock();
+                    let mut text = core::mem::take(&mut clipboard.text);
+                    text.resize(CLIPBOARD_LIMIT, 0);
+                    let len = writer.selected_text(&mut text);
+                    text.truncate(len);
+                    clipboard.paste_pos = len;
+                    clipboard.text = text;
+                }
+            }
+            Some(pressed & mouse::BUTTON_MIDDLE != 0)
+        });
+
+        if paste == Some(true) {
+            paste_clipboard();
+        }
     }
-    // Safety net: halt if reset doesn't happen immediately
-    loop {
-        hlt();
+}
+
+/// Start typing the clipboard contents into the shell as if from the
+/// keyboard
+///
+/// `poll_key` replays it a byte at a time once the key buffer is empty,
+/// so a long paste isn't cut short by the key buffer's size.
+fn paste_clipboard() {
+    without_interrupts(|| CLIPBOARD.lock().paste_pos = 0);
+}
+
+/// Next byte of a paste in progress
+///
+/// Only printable ASCII and newlines are replayed, so pasted text can't
+/// smuggle in control or navigation keys.
+fn next_paste_key() -> Option<u8> {
+    without_interrupts(|| {
+        let mut clipboard = CLIPBOARD.lock();
+        while let Some(&b) = clipboard.text.get(clipboard.paste_pos) {
+            clipboard.paste_pos += 1;
+            if b == b'\n' || (0x20..=0x7E).contains(&b) {
+                return Some(b);
+            }
+        }
+        None
+    })
+}
+
+fn cmd_mouse() {
+    if !mouse::is_present() {
+        print_str("Mouse: not detected\n");
+        return;
     }
+    let mut buf = FmtBuf::new();
+    without_interrupts(|| {
+        let pointer = POINTER.lock();
+        match pointer.pos {
+            Some((x, y)) => {
+                let _ = writeln!(buf, "Mouse: PS/2, pointer at ({x}, {y})");
+            }
+            None => {
+                let _ = writeln!(buf, "Mouse: PS/2, not moved yet");
+            }
+        }
+        let _ = writeln!(buf, "Clipboard: {} bytes", CLIPBOARD.lock().text.len());
+    });
+    let _ = writeln!(buf, "Resync bytes dropped: {}", mouse::resync_count());
+    print_str(buf.as_str());
 }
 
-// --- Main shell entry point ---
+// --- Hex codepoint entry ---
 
-pub fn run() -> ! {
-    print_str("ShadowOS v0.1.0\n");
-    print_str("Type 'help' for available commands.\n\n");
-    print_prompt();
+#[derive(Clone, Copy, PartialEq)]
+enum HexMode {
+    Entry,
+}
 
-    let mut line = LineBuffer::new();
+#[derive(Clone, Copy)]
+enum HexAction {
+    Finish,
+    Erase,
+    Abort,
+}
 
-    loop {
-        let key = without_interrupts(|| {
-            keyboard::KEY_BUFFER.lock().pop()
-        });
+static HEX_BINDINGS: [Binding<HexMode, HexAction>; 4] = [
+    Binding { mode: None, key: b'\n', action: HexAction::Finish },
+    Binding { mode: None, key: 8, action: HexAction::Erase },
+    Binding { mode: None, key: KEY_EOF, action: HexAction::Abort },
+    Binding { mode: None, key: keyboard::KEY_HEX_INPUT, action: HexAction::Abort },
+];
 
-        if let Some(byte) = key {
-            match byte {
-                b'\n' => {
-                    echo_byte(b'\n');
-                    execute(line.as_str());
-                    line.clear();
-                    print_prompt();
-                }
-                8 => {
-                    // Backspace
-                    if line.pop() {
-                        do_backspace();
-                    }
-                }
-                b'\t' => {
-                    // Ignore tabs
-                }
-                0x20..=0x7E => {
-                    // Printable ASCII
-                    if line.push(byte) {
-                        echo_byte(byte);
-                    }
+/// Most hex digits a codepoint can need (U+10FFFF)
+const MAX_HEX_DIGITS: usize = 6;
+
+/// Read a codepoint typed as hex digits after Ctrl+Shift+U
+///
+/// The digits are shown as `U+...` while typing and erased afterwards.
+/// Enter finishes; Ctrl+D or Ctrl+Shift+U again cancels.
+fn read_codepoint() -> Result<char, &'static str> {
+    let keymap = KeyMap::new(&HEX_BINDINGS, HexMode::Entry);
+    let mut digits = [0u8; MAX_HEX_DIGITS];
+    let mut len = 0;
+    print_str("U+");
+
+    let result = loop {
+        match keymap.dispatch(wait_key()) {
+            Dispatch::Action(HexAction::Finish) => {
+                let value = core::str::from_utf8(&digits[..len])
+                    .ok()
+                    .and_then(|s| u32::from_str_radix(s, 16).ok());
+                break match value {
+                    None => Err("Codepoint entry cancelled: no digits"),
+                    Some(0) => Err("Codepoint entry cancelled: U+0 can't be entered"),
+                    Some(v) => char::from_u32(v)
+                        .ok_or("Codepoint entry cancelled: not a valid codepoint"),
+                };
+            }
+            Dispatch::Action(HexAction::Erase) => {
+                if len > 0 {
+                    len -= 1;
+                    do_backspace();
                 }
-                _ => {
-                    // Ignore non-printable
+            }
+            Dispatch::Action(HexAction::Abort) => break Err("Codepoint entry cancelled"),
+            Dispatch::Unbound(key) if key.is_ascii_hexdigit() => {
+                if len == MAX_HEX_DIGITS {
+                    break Err("Codepoint entry cancelled: too many digits");
                 }
+                digits[len] = key;
+                len += 1;
+                echo_byte(key);
             }
+            Dispatch::Unbound(_) => break Err("Codepoint entry cancelled: not a hex digit"),
         }
+    };
 
-        hlt();
+    for _ in 0..len + 2 {
+        do_backspace();
+    }
+    result
+}
+
+// --- Progress indicator ---
+
+const PROGRESS_WIDTH: usize = 30;
+const SPINNER: &[u8] = b"|/-\\";
+
+/// In-place progress bar for long-running commands
+///
+/// Redraws the current line with `\r`, so it must not be interleaved with
+/// other output. Only redraws when the percentage changes. With a total
+/// of 0 it shows an indeterminate spinner instead. Dropping it erases the
+/// bar so the command's own output starts on a clean line.
+struct Progress {
+    total: u64,
+    last_percent: Option<u64>,
+    ticks: usize,
+}
+
+impl Progress {
+    fn new(total: u64) -> Self {
+        Progress {
+            total,
+            last_percent: None,
+            ticks: 0,
+        }
+    }
+
+    fn update(&mut self, current: u64) {
+        let mut buf = FmtBuf::new();
+        if self.total == 0 {
+            self.ticks += 1;
+            if self.ticks % 64 != 1 {
+                return;
+            }
+            let frame = SPINNER[(self.ticks / 64) % SPINNER.len()] as char;
+            let _ = write!(buf, "\r[{frame}] working...");
+        } else {
+            let percent = current.min(self.total) * 100 / self.total;
+            if self.last_percent == Some(percent) {
+                return;
+            }
+            self.last_percent = Some(percent);
+            let filled = percent as usize * PROGRESS_WIDTH / 100;
+            let _ = buf.write_str("\r[");
+            for i in 0..PROGRESS_WIDTH {
+                let _ = buf.write_char(if i < filled { '#' } else { '.' });
+            }
+            let _ = write!(buf, "] {percent:>3}%");
+        }
+        print_str(buf.as_str());
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.last_percent.is_some() || self.ticks > 0 {
+            print_str("\r");
+            for _ in 0..PROGRESS_WIDTH + 8 {
+                print_str(" ");
+            }
+            print_str("\r");
+        }
+    }
+}
+
+/// Cooperative pump for long-running commands
+///
+/// Call between chunks of work, with interrupts on and no locks held:
+/// redraws `progress` at `current`, catches up on framebuffer output held
+/// back by Scroll Lock and checks the keyboard. Returns `false` if q,
+/// Ctrl+C or Ctrl+D asks the command to stop.
+fn pump(progress: &mut Progress, current: u64) -> bool {
+    progress.update(current);
+    without_interrupts(|| {
+        if let Some(ref mut writer) = *framebuffer::FRAMEBUFFER.lock() {
+            writer.flush_paused();
+        }
+    });
+    !matches!(poll_key(), Some(b'q' | KEY_INTERRUPT | KEY_EOF))
+}
+
+/// Blocks `ramdisk_chunks` handles per chunk
+const PUMP_BLOCKS: u64 = 64;
+
+/// Why `ramdisk_chunks` stopped before the end of its range
+enum ChunkStop {
+    NoDisk,
+    Interrupted,
+    /// The per-block function returned `false`
+    Failed,
+}
+
+impl ChunkStop {
+    /// Say why the command stopped; a failed block has already said why
+    fn report(self, buf: &mut FmtBuf) {
+        let _ = match self {
+            ChunkStop::NoDisk => writeln!(buf, "RAM disk: not available"),
+            ChunkStop::Interrupted => writeln!(buf, "Interrupted"),
+            ChunkStop::Failed => Ok(()),
+        };
+    }
+}
+
+/// Call `f` on each block id in `range`, stopping if it returns `false`
+///
+/// The RAM disk is locked, with interrupts off, for one chunk of
+/// `PUMP_BLOCKS` at a time; `progress` is redrawn through `pump` in
+/// between, never while the lock is held.
+fn ramdisk_chunks(
+    range: core::ops::Range<u64>,
+    progress: &mut Progress,
+    mut f: impl FnMut(&mut ramdisk::RamDisk, u64) -> bool,
+) -> Result<(), ChunkStop> {
+    let mut id = range.start;
+    while id < range.end {
+        let end = (id + PUMP_BLOCKS).min(range.end);
+        without_interrupts(|| {
+            let mut rd = ramdisk::RAMDISK.lock();
+            let ramdisk = rd.as_mut().ok_or(ChunkStop::NoDisk)?;
+            if (id..end).all(|id| f(ramdisk, id)) {
+                Ok(())
+            } else {
+                Err(ChunkStop::Failed)
+            }
+        })?;
+        id = end;
+        if !pump(progress, id - range.start) {
+            return Err(ChunkStop::Interrupted);
+        }
+    }
+    Ok(())
+}
+
+// --- Command dispatch ---
+
+/// When set, every command line is logged to the kernel log before it runs
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Set while a script is executing; commands that never return are refused
+static IN_SCRIPT: AtomicBool = AtomicBool::new(false);
+
+/// Upper bound on lines executed from one script, in case it loops
+const MAX_SCRIPT_LINES: usize = 256;
+
+/// Enables commands that can crash or corrupt the system (`peek`, `poke`)
+static DEBUG_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Split off the first space-separated word, returning it and the rest
+fn split_word(s: &str) -> (&str, &str) {
+    match s.find(' ') {
+        Some(pos) => (&s[..pos], s[pos + 1..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+fn execute(line: &str) {
+    execute_at_depth(line, 0);
+}
+
+fn execute_at_depth(line: &str, depth: usize) {
+    let trimmed = line.trim_start();
+    if trimmed.is_empty() {
+        return;
+    }
+
+    let (word, args) = split_word(trimmed);
+
+    // A leading backslash bypasses alias expansion: `\ls` always runs `ls`
+    let (cmd, bypass_alias) = match word.strip_prefix('\\') {
+        Some(cmd) => (cmd, true),
+        None => (word, false),
+    };
+
+    // `trace` itself is left out so toggling doesn't clutter the log
+    if depth == 0 && TRACE.load(Ordering::Relaxed) && cmd != "trace" {
+        let known = find_command(cmd).is_some() || (!bypass_alias && alias_lookup(cmd).is_some());
+        let status = if known { "ok" } else { "unknown" };
+        klog::log(format_args!("trace: {trimmed} ({status})"));
+    }
+
+    if !bypass_alias {
+        if let Some(expansion) = alias_lookup(cmd) {
+            if depth >= MAX_ALIAS_DEPTH {
+                print_str("Alias expansion too deep (recursive alias?): ");
+                print_str(cmd);
+                print_str("\n");
+                return;
+            }
+            let mut expanded = FmtBuf::new();
+            let _ = write!(expanded, "{} {}", expansion.as_str(), args);
+            execute_at_depth(expanded.as_str(), depth + 1);
+            return;
+        }
+    }
+
+    if IN_SCRIPT.load(Ordering::Relaxed) && cmd == "reboot" {
+        print_str("Skipping '");
+        print_str(cmd);
+        print_str("' in script\n");
+        return;
+    }
+
+    match find_command(cmd) {
+        Some(command) => (command.run)(args),
+        None => {
+            print_str("Unknown command: ");
+            print_str(cmd);
+            print_str("\n");
+        }
+    }
+}
+
+// --- Aliases ---
+
+const MAX_ALIASES: usize = 16;
+const MAX_ALIAS_NAME: usize = 16;
+const MAX_ALIAS_VALUE: usize = 96;
+
+/// Nested expansions allowed before an alias is assumed to be recursive
+const MAX_ALIAS_DEPTH: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Alias {
+    name: [u8; MAX_ALIAS_NAME],
+    name_len: usize,
+    value: [u8; MAX_ALIAS_VALUE],
+    value_len: usize,
+}
+
+impl Alias {
+    const EMPTY: Alias = Alias {
+        name: [0; MAX_ALIAS_NAME],
+        name_len: 0,
+        value: [0; MAX_ALIAS_VALUE],
+        value_len: 0,
+    };
+
+    fn is_free(&self) -> bool {
+        self.name_len == 0
+    }
+
+    fn name(&self) -> &str {
+        // Only ever filled from &str, so always valid UTF-8
+        unsafe { core::str::from_utf8_unchecked(&self.name[..self.name_len]) }
+    }
+
+    fn value(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.value[..self.value_len]) }
+    }
+}
+
+static ALIASES: Mutex<[Alias; MAX_ALIASES]> = Mutex::new([Alias::EMPTY; MAX_ALIASES]);
+
+/// Copy of the expansion for `name`, if it is an alias
+fn alias_lookup(name: &str) -> Option<FmtBuf> {
+    without_interrupts(|| {
+        let aliases = ALIASES.lock();
+        let alias = aliases.iter().find(|a| !a.is_free() && a.name() == name)?;
+        let mut buf = FmtBuf::new();
+        let _ = buf.write_str(alias.value());
+        Some(buf)
+    })
+}
+
+fn cmd_alias(args: &str) {
+    if args.is_empty() {
+        without_interrupts(|| {
+            let aliases = ALIASES.lock();
+            for alias in aliases.iter().filter(|a| !a.is_free()) {
+                let mut buf = FmtBuf::new();
+                let _ = writeln!(buf, "alias {} \"{}\"", alias.name(), alias.value());
+                print_str(buf.as_str());
+            }
+        });
+        return;
+    }
+
+    let (name, value) = split_word(args);
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    if value.is_empty() || name.starts_with('\\') {
+        print_str("Usage: alias <name> <command line>\n");
+        return;
+    }
+    if name.len() > MAX_ALIAS_NAME || value.len() > MAX_ALIAS_VALUE {
+        print_str("Alias name or value too long\n");
+        return;
+    }
+
+    let stored = without_interrupts(|| {
+        let mut aliases = ALIASES.lock();
+        let slot = match aliases.iter().position(|a| !a.is_free() && a.name() == name) {
+            Some(i) => Some(i),
+            None => aliases.iter().position(|a| a.is_free()),
+        };
+        match slot {
+            Some(i) => {
+                let alias = &mut aliases[i];
+                alias.name[..name.len()].copy_from_slice(name.as_bytes());
+                alias.name_len = name.len();
+                alias.value[..value.len()].copy_from_slice(value.as_bytes());
+                alias.value_len = value.len();
+                true
+            }
+            None => false,
+        }
+    });
+    if !stored {
+        print_str("Alias table full\n");
+    }
+}
+
+fn cmd_unalias(args: &str) {
+    let removed = without_interrupts(|| {
+        let mut aliases = ALIASES.lock();
+        match aliases.iter_mut().find(|a| !a.is_free() && a.name() == args) {
+            Some(alias) => {
+                *alias = Alias::EMPTY;
+                true
+            }
+            None => false,
+        }
+    });
+    if !removed {
+        print_str("No such alias: ");
+        print_str(args);
+        print_str("\n");
+    }
+}
+
+// --- Help metadata ---
+
+/// Runs a command, given its arguments
+type CommandFn = fn(&str);
+
+/// A shell command: its name, `help` text, and the function that runs it
+struct Command {
+    name: &'static str,
+    summary: &'static str,
+    details: &'static str,
+    run: CommandFn,
+}
+
+/// Every shell command, in `help` order
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        summary: "Show this help message",
+        details: "help            list all commands\n\
+                  help <command>  show details for one command\n\
+                  help -k <word>  search command names and descriptions\n",
+        run: cmd_help,
+    },
+    Command {
+        name: "clear",
+        summary: "Clear the screen",
+        details: "Erase the framebuffer and move the cursor to the top left.\n\
+                  A serial terminal is sent the ANSI clear sequence too.\n",
+        run: |_| cmd_clear(),
+    },
+    Command {
+        name: "reset",
+        summary: "Reset colors and clear the screen",
+        details: "Restore the default colors, abandon any unfinished escape\n\
+                  sequence, and clear the screen. A serial terminal gets the\n\
+                  ANSI reset and clear sequences.\n",
+        run: |_| cmd_reset(),
+    },
+    Command {
+        name: "echo",
+        summary: "Print text to the screen",
+        details: "echo <text>  print the text followed by a newline\n",
+        run: cmd_echo,
+    },
+    Command {
+        name: "info",
+        summary: "Show system information",
+        details: "info        show a system summary\n\
+                  info cpu    processor vendor, model and features\n\
+                  info video  framebuffer details, write bandwidth and displays\n\
+                  info disk   RAM disk size\n\
+                  info kbd    keyboard stuck-key diagnostics\n\
+                  info irq    registered IRQ handlers and unclaimed IRQs\n",
+        run: cmd_info,
+    },
+    Command {
+        name: "reboot",
+        summary: "Reboot the system",
+        details: "Flush devices and reset the machine via the keyboard controller.\n",
+        run: |_| cmd_reboot(),
+    },
+    Command {
+        name: "shutdown",
+        summary: "Power off the system",
+        details: "Flush devices and power off. Uses the QEMU/Bochs power-off\n\
+                  ports, so on real hardware this halts instead.\n",
+        run: |_| cmd_shutdown(),
+    },
+    Command {
+        name: "qemuexit",
+        summary: "Exit QEMU with a test status",
+        details: "qemuexit       exit QEMU reporting success (status 33)\n\
+                  qemuexit fail  exit QEMU reporting failure (status 35)\n\
+                  Needs -device isa-debug-exit,iobase=0xf4,iosize=0x04;\n\
+                  meant as the last line of an automated init.sh.\n",
+        run: cmd_qemuexit,
+    },
+    Command {
+        name: "video",
+        summary: "Framebuffer mapping control",
+        details: "video wc         show the framebuffer memory type\n\
+                  video wc on|off  toggle write-combining via the PAT,\n\
+                  reporting write bandwidth before and after\n\
+                  video test       draw a test pattern until a key is pressed\n\
+                  video buffer     show whether drawing goes through a\n\
+                  RAM back buffer\n\
+                  video buffer on|off  switch the back buffer\n\
+                  video bench      time scrolling the screen\n",
+        run: cmd_video,
+    },
+    Command {
+        name: "mirror",
+        summary: "Duplicate output to all consoles",
+        details: "mirror         show the output routing mode\n\
+                  mirror on|off  send output to every console, or only to\n\
+                  the one selected with 'console'\n",
+        run: cmd_mirror,
+    },
+    Command {
+        name: "console",
+        summary: "Select the active output or input console",
+        details: "console                     show output and input routing\n\
+                  console fb|serial           select the output console\n\
+                  console input fb|serial|all select where input is read from\n",
+        run: cmd_console,
+    },
+    Command {
+        name: "trace",
+        summary: "Log every command line to the kernel log",
+        details: "trace         show whether tracing is enabled\n\
+                  trace on|off  log each command (with a timestamp and whether\n\
+                  it was recognized) to the kernel log; see 'dmesg'\n",
+        run: cmd_trace,
+    },
+    Command {
+        name: "dmesg",
+        summary: "Print the kernel log",
+        details: "dmesg     print the kernel log ring buffer\n\
+                  dmesg -f  print the log, then stream new lines until 'q'\n\
+                  dmesg -p  page through the log a screenful at a time\n",
+        run: cmd_dmesg,
+    },
+    Command {
+        name: "keymap",
+        summary: "Show extended and keypad key mappings",
+        details: "List the 0xE0-prefixed scancodes the keyboard driver maps to characters,\n\
+                  then the keypad keys that depend on Num Lock.\n",
+        run: |_| cmd_keymap(),
+    },
+    Command {
+        name: "setlayout",
+        summary: "Choose the keyboard layout",
+        details: "setlayout         show the active layout and the ones available\n\
+                  setlayout <name>  switch layouts (us, dvorak); the keypad and\n\
+                  navigation keys are the same in all of them\n",
+        run: cmd_setlayout,
+    },
+    Command {
+        name: "ramdisk",
+        summary: "RAM disk settings",
+        details: "ramdisk checked          show whether checked mode is on\n\
+                  ramdisk checked on|off   keep a CRC-32 per block, verified on\n\
+                  every read (reads of corrupted blocks then fail)\n\
+                  ramdisk readonly         show whether writes are refused\n\
+                  ramdisk readonly on|off  refuse or allow every write\n",
+        run: cmd_ramdisk,
+    },
+    Command {
+        name: "diskinfo",
+        summary: "Show block device geometry",
+        details: "Print the RAM disk's block size, block count, capacity and\n\
+                  whether it's read-only.\n",
+        run: |_| cmd_diskinfo(),
+    },
+    Command {
+        name: "crc",
+        summary: "Verify RAM disk block checksums",
+        details: "List blocks whose contents no longer match their CRC-32.\n\
+                  Requires 'ramdisk checked on'.\n",
+        run: |_| cmd_crc(),
+    },
+    Command {
+        name: "hexdump",
+        summary: "Show a RAM disk block as hex and ASCII",
+        details: "hexdump <block> [lines]  dump the block 16 bytes per line\n\
+                  (all 32 lines unless a count is given)\n",
+        run: cmd_hexdump,
+    },
+    Command {
+        name: "readblk",
+        summary: "Print a RAM disk block as text",
+        details: "readblk <block>  print the block's contents up to the first\n\
+                  NUL byte\n",
+        run: cmd_readblk,
+    },
+    Command {
+        name: "writeblk",
+        summary: "Write text into a RAM disk block",
+        details: "writeblk <block> <text>  store the rest of the line in the\n\
+                  block, zero-padded to 512 bytes\n",
+        run: cmd_writeblk,
+    },
+    Command {
+        name: "caps",
+        summary: "List capabilities in machine-readable form",
+        details: "Print one 'name=value' line per capability, for host tooling.\n\
+                  value is the capability's version, or 0 if it is compiled in\n\
+                  but did not come up on this boot. Names are never reused.\n",
+        run: |_| cmd_caps(),
+    },
+    Command {
+        name: "debug",
+        summary: "Enable dangerous debugging commands",
+        details: "debug         show whether debug mode is on\n\
+                  debug on|off  allow 'peek' and 'poke'\n",
+        run: cmd_debug,
+    },
+    Command {
+        name: "peek",
+        summary: "[DANGEROUS] Hexdump physical memory",
+        details: "peek [-b|-w|-d] <addr> [count]  dump count bytes, words or\n\
+                  dwords (16 bytes' worth by default, max 256) at physical\n\
+                  address addr via the HHDM. Only RAM in the memory map can\n\
+                  be read. Requires 'debug on'.\n",
+        run: cmd_peek,
+    },
+    Command {
+        name: "poke",
+        summary: "[DANGEROUS] Write bytes to physical memory",
+        details: "poke [-b|-w|-d] <addr> <value>...  write bytes, words or\n\
+                  dwords at physical address addr via the HHDM. Only usable\n\
+                  memory can be written, but that includes the kernel heap\n\
+                  and page tables: this can corrupt the kernel.\n\
+                  Requires 'debug on'.\n",
+        run: cmd_poke,
+    },
+    Command {
+        name: "font",
+        summary: "Show or reset the console font",
+        details: "font        show the current font's glyph size and count\n\
+                  font reset  switch back to the built-in 8x16 font\n",
+        run: cmd_font,
+    },
+    Command {
+        name: "color",
+        summary: "Set the console text colors",
+        details: "color              show the current colors\n\
+                  color <fg> [<bg>]  set the text (and background) color\n\
+                  color reset        back to gray on black\n\
+                  Colors: black red green yellow blue magenta cyan white.\n\
+                  Only text written afterwards changes color.\n",
+        run: cmd_color,
+    },
+    Command {
+        name: "panicmode",
+        summary: "Show or set what happens after a kernel panic",
+        details: "panicmode          show the current policy\n\
+                  panicmode halt     halt forever (default)\n\
+                  panicmode reboot   reset after a short delay\n\
+                  panicmode recover  drop back into the shell if possible;\n\
+                  best effort only, since the system state may be corrupt\n\
+                  Can also be set at boot with panic=halt|reboot|recover.\n",
+        run: cmd_panicmode,
+    },
+    Command {
+        name: "mouse",
+        summary: "Show mouse status; select and paste text with the mouse",
+        details: "mouse  show whether a PS/2 mouse was found and where it points\n\
+                  Drag with the left button to select on-screen text, which is\n\
+                  copied on release (up to 4 KB); click to drop the selection.\n\
+                  The middle button types the copied text at the prompt.\n\
+                  Only the live screen can be selected, not scrollback; while\n\
+                  scrolled back, dragging does nothing. Copying needs the heap.\n",
+        run: |_| cmd_mouse(),
+    },
+    Command {
+        name: "irqs",
+        summary: "Show or change the PIC's IRQ masks",
+        details: "irqs                show both PIC mask bytes and the\n\
+                  unmasked lines\n\
+                  irqs mask <irq>     disable IRQ line 0-15\n\
+                  irqs unmask <irq>   enable IRQ line 0-15\n\
+                  Masking IRQ1 stops the keyboard; use serial to undo it.\n",
+        run: cmd_irqs,
+    },
+    Command {
+        name: "syscall",
+        summary: "Make a system call through int 0x80",
+        details: "syscall               list the syscalls and how many were made\n\
+                  syscall write <text>  write <text> to serial with the write syscall\n\
+                  syscall exit [code]   call exit (only logs it for now)\n\
+                  syscall <number>      call <number> with no arguments, show RAX\n",
+        run: cmd_syscall,
+    },
+    Command {
+        name: "date",
+        summary: "Show the date and time from the RTC",
+        details: "date  print the real-time clock as YYYY-MM-DD HH:MM:SS\n\
+                  (usually local time; years are taken as 20xx)\n",
+        run: |_| cmd_date(),
+    },
+    Command {
+        name: "beep",
+        summary: "Sound the PC speaker",
+        details: "beep [<hz> [<ms>]]  beep at hz (default 880, 20-20000) for\n\
+                  ms milliseconds (default 200, at most 5000)\n",
+        run: cmd_beep,
+    },
+    Command {
+        name: "rand",
+        summary: "Print a random number",
+        details: "rand  print a random 64-bit number from RDRAND, or from a\n\
+                  timer-seeded PRNG (not secure) if the CPU lacks it\n",
+        run: |_| cmd_rand(),
+    },
+    Command {
+        name: "uptime",
+        summary: "Show time since boot",
+        details: "uptime  show seconds and milliseconds since the system timer\n\
+                  started, counted in timer ticks\n",
+        run: |_| cmd_uptime(),
+    },
+    Command {
+        name: "ticks",
+        summary: "Show the raw timer tick count",
+        details: "ticks  show the tick count, the timer frequency and the ticks\n\
+                  since the previous 'ticks', to check the timer is firing\n",
+        run: |_| cmd_ticks(),
+    },
+    Command {
+        name: "sleep",
+        summary: "Wait for a number of seconds",
+        details: "sleep <seconds>  wait, e.g. 'sleep 2' or 'sleep 0.25' (up to\n\
+                  3 decimal places, at most 600 s). Press q or Ctrl+C to stop.\n",
+        run: cmd_sleep,
+    },
+    Command {
+        name: "mem",
+        summary: "Show physical memory from the bootloader's map",
+        details: "mem  show total, usable, reclaimable and reserved memory,\n\
+                  followed by a breakdown by region type, the number of\n\
+                  physical frames handed out and kernel heap usage\n",
+        run: |_| cmd_mem(),
+    },
+    Command {
+        name: "memmap",
+        summary: "List the bootloader's memory map",
+        details: "memmap  print every region as start-end : type : size, in\n\
+                  address order, then the totals for each type\n",
+        run: |_| cmd_memmap(),
+    },
+    Command {
+        name: "latency",
+        summary: "Show the timer interrupt latency histogram",
+        details: "latency        show gaps between timer interrupts, bucketed by\n\
+                  powers of two; gaps over twice the period are flagged\n\
+                  latency reset  clear the histogram\n",
+        run: cmd_latency,
+    },
+    Command {
+        name: "acpi",
+        summary: "List the ACPI tables found at boot",
+        details: "acpi  show the ACPI revision, OEM and the signature and\n\
+                  physical address of each table in the RSDT/XSDT\n",
+        run: |_| cmd_acpi(),
+    },
+    Command {
+        name: "hpet",
+        summary: "Show the HPET frequency and counter",
+        details: "hpet  show the HPET counter frequency and value, which timer\n\
+                  drives the system tick, and a 10 ms HPET delay timed by the TSC\n\
+                  Boot with timer=pit to keep the PIT as the tick source.\n",
+        run: |_| cmd_hpet(),
+    },
+    Command {
+        name: "alias",
+        summary: "Define or list command aliases",
+        details: "alias                    list aliases\n\
+                  alias <name> \"<line>\"    make <name> run <line>; arguments\n\
+                  given to <name> are appended to <line>\n\
+                  Aliases take precedence over built-in commands; prefix a\n\
+                  command with '\\' (e.g. \\clear) to bypass aliases.\n",
+        run: cmd_alias,
+    },
+    Command {
+        name: "unalias",
+        summary: "Remove a command alias",
+        details: "unalias <name>  remove the alias <name>\n",
+        run: cmd_unalias,
+    },
+    Command {
+        name: "blkverify",
+        summary: "Stress the block layer with random write/read round trips",
+        details: "blkverify <iterations> [seed]\n\
+                  Each iteration writes random data to 1-4 random blocks, reads\n\
+                  it back singly and as a run, then restores the old contents.\n\
+                  Out-of-range reads are mixed in and must fail. The seed is\n\
+                  printed so a failure can be reproduced. Press q or Ctrl+C to stop.\n",
+        run: cmd_blkverify,
+    },
+    Command {
+        name: "blkheat",
+        summary: "Count per-block accesses and show the hottest blocks",
+        details: "blkheat         show the most accessed RAM disk blocks\n\
+                  blkheat on|off  start or stop counting reads and writes\n\
+                  blkheat reset   zero all counters\n",
+        run: cmd_blkheat,
+    },
+    Command {
+        name: "cachetune",
+        summary: "Measure a representative block workload per cache size",
+        details: "cachetune  run a read-only mixed workload (mostly a hot set of\n\
+                  blocks, some random ones) uncached and through LRU block\n\
+                  caches of 16 to 256 blocks, report hit rate and MB/s for\n\
+                  each, then the fastest size. Press q or Ctrl+C to stop.\n",
+        run: |_| cmd_cachetune(),
+    },
+    Command {
+        name: "ls",
+        summary: "List the files on the RAM disk",
+        details: "ls  list each file with its size in bytes: the files of the\n\
+                  sfs filesystem if the disk has one (see mkfs), otherwise the\n\
+                  regular files of the tar archive\n",
+        run: |_| cmd_ls(),
+    },
+    Command {
+        name: "cat",
+        summary: "Print a file from the RAM disk",
+        details: "cat <name>  print the file. Bytes other than printable ASCII,\n\
+                  newline and tab are sent to serial as-is but shown as '.'\n\
+                  on screen.\n",
+        run: cmd_cat,
+    },
+    Command {
+        name: "mkfs",
+        summary: "Create an empty filesystem on the RAM disk",
+        details: "mkfs  format the RAM disk with sfs, a flat filesystem of up to\n\
+                  64 files. Whatever the disk held, such as the initrd\n\
+                  archive, is lost.\n",
+        run: |_| cmd_mkfs(),
+    },
+    Command {
+        name: "touch",
+        summary: "Create an empty file",
+        details: "touch <name>  create <name> on the sfs filesystem if it doesn't\n\
+                  exist yet. Names are up to 24 bytes.\n",
+        run: cmd_touch,
+    },
+    Command {
+        name: "write",
+        summary: "Replace a file's contents with text",
+        details: "write <name> <text>  store <text> as the contents of <name>,\n\
+                  creating it if needed\n",
+        run: cmd_write,
+    },
+    Command {
+        name: "rm",
+        summary: "Delete a file",
+        details: "rm <name>  remove <name> from the sfs filesystem and free its\n\
+                  blocks\n",
+        run: cmd_rm,
+    },
+    Command {
+        name: "trigger",
+        summary: "Run a command when a string arrives on serial",
+        details: "trigger                     show the pending trigger\n\
+                  trigger <string> <command>  run <command> once <string> is\n\
+                  received on serial input; quote <string> to include spaces\n\
+                  trigger clear               remove the trigger\n\
+                  One trigger at a time; it fires once. Whatever was typed at\n\
+                  the prompt, including the string, is discarded when it fires.\n",
+        run: cmd_trigger,
+    },
+    Command {
+        name: "zerofree",
+        summary: "Count all-zero RAM disk blocks",
+        details: "Scan every RAM disk block and report how many are entirely zero.\n",
+        run: |_| cmd_zerofree(),
+    },
+    Command {
+        name: "wipe",
+        summary: "Zero a range of RAM disk blocks",
+        details: "wipe <start> <count>  zero blocks start..start+count; a range\n\
+                  running past the end of the disk is clamped with a warning\n",
+        run: cmd_wipe,
+    },
+    Command {
+        name: "filldisk",
+        summary: "Fill every RAM disk block with a byte",
+        details: "filldisk <byte>  write <byte> (decimal or 0x hex) to every byte\n\
+                  of every block, then check a write past the end is refused.\n\
+                  Destroys the disk's contents.\n",
+        run: cmd_filldisk,
+    },
+    Command {
+        name: "verifydisk",
+        summary: "Check every RAM disk block holds a byte",
+        details: "verifydisk <byte>  read every block and report the first one\n\
+                  holding anything other than <byte>, e.g. after filldisk\n",
+        run: cmd_verifydisk,
+    },
+];
+
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Check that no two commands share a name, so none is unreachable
+pub fn command_table_self_test() -> bool {
+    COMMANDS
+        .iter()
+        .enumerate()
+        .all(|(i, c)| COMMANDS[..i].iter().all(|earlier| earlier.name != c.name))
+}
+
+/// Case-insensitive ASCII substring test
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    let (h, n) = (haystack.as_bytes(), needle.as_bytes());
+    n.is_empty() || h.windows(n.len()).any(|w| w.eq_ignore_ascii_case(n))
+}
+
+fn print_help_line(c: &Command) {
+    print_str("  ");
+    print_str(c.name);
+    for _ in c.name.len()..8 {
+        print_str(" ");
+    }
+    print_str("- ");
+    print_str(c.summary);
+    print_str("\n");
+}
+
+fn cmd_help(args: &str) {
+    if args.is_empty() {
+        print_str("Available commands:\n");
+        for c in COMMANDS {
+            print_help_line(c);
+        }
+    } else if let Some(keyword) = args.strip_prefix("-k") {
+        let keyword = keyword.trim();
+        if keyword.is_empty() {
+            print_str("Usage: help -k <keyword>\n");
+            return;
+        }
+        let mut found = false;
+        for c in COMMANDS {
+            if contains_ignore_case(c.name, keyword)
+                || contains_ignore_case(c.summary, keyword)
+                || contains_ignore_case(c.details, keyword)
+            {
+                print_help_line(c);
+                found = true;
+            }
+        }
+        if !found {
+            print_str("No commands match '");
+            print_str(keyword);
+            print_str("'\n");
+        }
+    } else {
+        match find_command(args) {
+            Some(c) => {
+                print_str(c.name);
+                print_str(" - ");
+                print_str(c.summary);
+                print_str("\n");
+                print_str(c.details);
+            }
+            None => {
+                print_str("No help for '");
+                print_str(args);
+                print_str("'\n");
+            }
+        }
+    }
+}
+
+fn cmd_clear() {
+    without_interrupts(|| {
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        if let Some(ref mut writer) = *fb {
+            writer.clear_screen();
+        }
+    });
+    // Clear a serial terminal in step: erase display, cursor home
+    print_serial("\x1b[2J\x1b[H");
+}
+
+fn cmd_reset() {
+    without_interrupts(|| {
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        if let Some(ref mut writer) = *fb {
+            writer.reset();
+        }
+    });
+    // Reset attributes, erase display, cursor home
+    print_serial("\x1b[0m\x1b[2J\x1b[H");
+}
+
+fn cmd_echo(args: &str) {
+    print_str(args);
+    print_str("\n");
+}
+
+fn cmd_info(args: &str) {
+    match args {
+        "" => {
+            print_str("ShadowOS v0.1.0\n");
+            info_cpu(false);
+            info_video(false);
+            info_disk();
+        }
+        "cpu" => info_cpu(true),
+        "video" => info_video(true),
+        "disk" => info_disk(),
+        "kbd" => info_kbd(),
+        "irq" => info_irq(),
+        _ => print_str("Usage: info [cpu|video|disk|kbd|irq]\n"),
+    }
+}
+
+fn info_cpu(detailed: bool) {
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "CPU:         {}", cpu::brand().as_str());
+    if detailed {
+        let vendor = cpu::vendor();
+        let _ = writeln!(buf, "Vendor:      {}", core::str::from_utf8(&vendor).unwrap_or("?"));
+        let _ = write!(buf, "Features:   ");
+        for feature in cpu::FEATURES.iter().filter(|f| cpu::has(f)) {
+            let _ = write!(buf, " {}", feature.name);
+        }
+        let _ = writeln!(buf);
+    }
+    print_str(buf.as_str());
+}
+
+fn info_video(detailed: bool) {
+    // Collect framebuffer info into a stack buffer (avoids holding lock while printing)
+    let mut fbuf = FmtBuf::new();
+
+    without_interrupts(|| {
+        let fb = framebuffer::FRAMEBUFFER.lock();
+        if let Some(ref writer) = *fb {
+            let _ = write!(
+                fbuf,
+                "Framebuffer: {}x{}\nText grid:   {}x{}\n",
+                writer.width(),
+                writer.height(),
+                writer.max_cols(),
+                writer.max_rows()
+            );
+            if detailed {
+                match writer.write_bandwidth() {
+                    Some(mbps) if mbps < framebuffer::SLOW_BANDWIDTH_MBPS => {
+                        let _ = writeln!(fbuf, "Write speed: {mbps} MB/s (slow, likely uncached)");
+                    }
+                    Some(mbps) => {
+                        let _ = writeln!(fbuf, "Write speed: {mbps} MB/s");
+                    }
+                    None => {
+                        let _ = writeln!(fbuf, "Write speed: not measured");
+                    }
+                }
+                let back = if writer.back_buffer_enabled() { "on" } else { "off" };
+                let _ = writeln!(fbuf, "Back buffer: {back}");
+            }
+        } else {
+            let _ = writeln!(fbuf, "Framebuffer: not available (serial-only mode)");
+        }
+    });
+    if detailed {
+        let count = without_interrupts(framebuffer::count);
+        for i in 0..count {
+            without_interrupts(|| {
+                framebuffer::with_framebuffer(i, |writer| {
+                    let role = if i == 0 { " (console)" } else { "" };
+                    let _ = writeln!(
+                        fbuf,
+                        "Display {i}:   {}x{}, {} bpp{role}",
+                        writer.width(),
+                        writer.height(),
+                        writer.bpp()
+                    );
+                })
+            });
+        }
+    }
+
+    print_str(fbuf.as_str());
+}
+
+fn info_kbd() {
+    let (storms, suppressed) = keyboard::storm_stats();
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "Keyboard:    {storms} stuck-key storms, {suppressed} scancodes suppressed");
+    print_str(buf.as_str());
+}
+
+fn info_irq() {
+    print_str("IRQ  handler     unclaimed  spurious\n");
+    for irq in 0..16u8 {
+        let claimed = interrupts::irq_claimed(irq);
+        let unclaimed = interrupts::unclaimed_count(irq);
+        let spurious = interrupts::spurious_count(irq);
+        if !claimed && unclaimed == 0 && spurious == 0 {
+            continue;
+        }
+        let mut buf = FmtBuf::new();
+        let state = if claimed { "registered" } else { "none" };
+        let _ = writeln!(buf, "{irq:>3}  {state:<10} {unclaimed:<10} {spurious}");
+        print_str(buf.as_str());
+    }
+}
+
+fn info_disk() {
+    // Collect ramdisk info
+    let mut rbuf = FmtBuf::new();
+
+    without_interrupts(|| {
+        let rd = ramdisk::RAMDISK.lock();
+        if let Some(ref ramdisk) = *rd {
+            let blocks = ramdisk.block_count();
+            let kb = blocks * BLOCK_SIZE as u64 / 1024;
+            let _ = write!(rbuf, "RAM disk:    {} blocks ({} KB)\n", blocks, kb);
+        } else {
+            let _ = writeln!(rbuf, "RAM disk:    not available");
+        }
+    });
+
+    print_str(rbuf.as_str());
+}
+
+fn print_pat_error(e: PatError) {
+    match e {
+        PatError::Unsupported => print_str("PAT not supported by this CPU\n"),
+        PatError::NoHhdm => print_str("No HHDM offset; page tables unreachable\n"),
+        PatError::NotMapped => print_str("Framebuffer is not mapped\n"),
+    }
+}
+
+const VIDEO_USAGE: &str = "Usage: video wc [on|off] | video test | video buffer [on|off] | video bench\n";
+
+fn cmd_video(args: &str) {
+    match split_word(args) {
+        ("test", "") => video_test_pattern(),
+        ("wc", mode) => video_write_combining(mode),
+        ("buffer", mode) => video_back_buffer(mode),
+        ("bench", "") => video_bench(),
+        _ => print_str(VIDEO_USAGE),
+    }
+}
+
+fn video_write_combining(mode: &str) {
+    let range = without_interrupts(|| {
+        framebuffer::FRAMEBUFFER.lock().as_ref().map(|writer| writer.buffer_range())
+    });
+    let (start, len) = match range {
+        Some(range) => range,
+        None => {
+            print_str("Framebuffer: not available (serial-only mode)\n");
+            return;
+        }
+    };
+
+    let enable = match mode {
+        "" => {
+            let mut buf = FmtBuf::new();
+            match pat::memory_type(start) {
+                Ok(t) => {
+                    let _ = writeln!(buf, "Framebuffer memory type: {}", pat::memory_type_name(t));
+                    print_str(buf.as_str());
+                }
+                Err(e) => print_pat_error(e),
+            }
+            return;
+        }
+        "on" => true,
+        "off" => false,
+        _ => {
+            print_str(VIDEO_USAGE);
+            return;
+        }
+    };
+
+    // Each measurement repaints the whole screen, so report afterwards
+    let measure = || {
+        without_interrupts(|| {
+            framebuffer::FRAMEBUFFER.lock().as_mut().and_then(|writer| writer.measure_write_bandwidth())
+        })
+    };
+    let before = measure();
+    let result = pat::set_write_combining(start, len, enable);
+    let after = measure();
+
+    match result {
+        Ok(r) => {
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "Remapped {} pages ({} shared huge pages skipped)", r.changed, r.skipped);
+            if let (Some(before), Some(after)) = (before, after) {
+                let _ = writeln!(buf, "Write speed: {before} MB/s -> {after} MB/s");
+            }
+            print_str(buf.as_str());
+        }
+        Err(e) => print_pat_error(e),
+    }
+}
+
+fn video_back_buffer(mode: &str) {
+    let enable = match mode {
+        "" => None,
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => {
+            print_str(VIDEO_USAGE);
+            return;
+        }
+    };
+    let state = without_interrupts(|| {
+        framebuffer::FRAMEBUFFER.lock().as_mut().map(|writer| match enable {
+            Some(on) => writer.set_back_buffer(on),
+            None => writer.back_buffer_enabled(),
+        })
+    });
+    match state {
+        None => print_str("Framebuffer: not available (serial-only mode)\n"),
+        Some(false) if enable == Some(true) => print_str("No back buffer was mapped at boot\n"),
+        Some(on) => print_str(if on { "Back buffer: on\n" } else { "Back buffer: off\n" }),
+    }
+}
+
+/// Time a batch of full-screen scrolls with the current buffering
+fn video_bench() {
+    const SCROLLS: u64 = 100;
+    let result = without_interrupts(|| {
+        framebuffer::FRAMEBUFFER.lock().as_mut().map(|writer| {
+            (writer.back_buffer_enabled(), writer.time_scrolls(SCROLLS))
+        })
+    });
+    let mut buf = FmtBuf::new();
+    match result {
+        None => print_str("Framebuffer: not available (serial-only mode)\n"),
+        Some((_, None)) => print_str("TSC not calibrated; can't time scrolling\n"),
+        Some((back, Some(us))) => {
+            let mode = if back { "back buffer" } else { "direct" };
+            let _ = writeln!(buf, "{SCROLLS} scrolls ({mode}): {us} us each");
+            print_str(buf.as_str());
+        }
+    }
+}
+
+/// Color bars with a border and diagonals, to check pixel format and clipping
+fn video_test_pattern() {
+    const BARS: [Color; 8] = [
+        Color::new(0xFF, 0xFF, 0xFF),
+        Color::new(0xFF, 0xFF, 0x00),
+        Color::new(0x00, 0xFF, 0xFF),
+        Color::new(0x00, 0xFF, 0x00),
+        Color::new(0xFF, 0x00, 0xFF),
+        Color::new(0xFF, 0x00, 0x00),
+        Color::new(0x00, 0x00, 0xFF),
+        Color::new(0x00, 0x00, 0x00),
+    ];
+    let drawn = without_interrupts(|| {
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        let writer = fb.as_mut()?;
+        let (w, h) = (writer.width(), writer.height());
+        writer.fill_screen(Color::new(0x20, 0x20, 0x20));
+        let bar = w / BARS.len();
+        for (i, &color) in BARS.iter().enumerate() {
+            writer.fill_rect(i * bar, h / 4, bar, h / 2, color);
+        }
+        let white = BARS[0];
+        writer.draw_rect(0, 0, w, h, white);
+        let (right, bottom) = (w as isize - 1, h as isize - 1);
+        writer.draw_line(0, 0, right, bottom, white);
+        writer.draw_line(right, 0, 0, bottom, white);
+        Some(())
+    });
+    if drawn.is_none() {
+        print_str("Framebuffer: not available (serial-only mode)\n");
+        return;
+    }
+    wait_key();
+    cmd_clear();
+}
+
+fn cmd_mirror(args: &str) {
+    match args {
+        "" => {}
+        "on" => console::set_mirror(true),
+        "off" => {
+            console::set_mirror(false);
+            // Output defaults to the framebuffer even on a headless boot,
+            // where routing only there would leave nothing visible
+            if console::active_output() == ConsoleKind::Framebuffer && !framebuffer_available() {
+                console::set_active_output(ConsoleKind::Serial);
+            }
+        }
+        _ => {
+            print_str("Usage: mirror [on|off]\n");
+            return;
+        }
+    }
+    if console::mirror_enabled() {
+        print_str("Mirror: on (output to all consoles)\n");
+    } else {
+        print_str("Mirror: off (output to ");
+        print_str(console::active_output().name());
+        print_str(" only)\n");
+    }
+}
+
+fn framebuffer_available() -> bool {
+    without_interrupts(|| framebuffer::FRAMEBUFFER.lock().is_some())
+}
+
+fn cmd_console(args: &str) {
+    let (sub, rest) = split_word(args);
+
+    match sub {
+        "" => {
+            print_str("Output: ");
+            if console::mirror_enabled() {
+                print_str("all (mirror on)");
+            } else {
+                print_str(console::active_output().name());
+            }
+            print_str("\nInput:  ");
+            match console::input_route() {
+                InputRoute::All => print_str("all"),
+                InputRoute::Only(kind) => print_str(kind.name()),
+            }
+            print_str("\n");
+        }
+        "input" => {
+            let route = match rest {
+                "all" => InputRoute::All,
+                _ => match ConsoleKind::parse(rest) {
+                    Some(kind) => InputRoute::Only(kind),
+                    None => {
+                        print_str("Usage: console input fb|serial|all\n");
+                        return;
+                    }
+                },
+            };
+            console::set_input_route(route);
+        }
+        _ => match ConsoleKind::parse(sub) {
+            Some(ConsoleKind::Framebuffer) if !framebuffer_available() => {
+                print_str("Framebuffer: not available (serial-only mode)\n");
+            }
+            Some(kind) => {
+                console::set_active_output(kind);
+                if console::mirror_enabled() {
+                    print_str("Note: mirror is on; use 'mirror off' to route output\n");
+                }
+            }
+            None => print_str("Usage: console [fb|serial|input fb|serial|all]\n"),
+        },
+    }
+}
+
+fn cmd_trace(args: &str) {
+    match args {
+        "" => {}
+        "on" => TRACE.store(true, Ordering::Relaxed),
+        "off" => TRACE.store(false, Ordering::Relaxed),
+        _ => {
+            print_str("Usage: trace [on|off]\n");
+            return;
+        }
+    }
+    if TRACE.load(Ordering::Relaxed) {
+        print_str("Trace: on\n");
+    } else {
+        print_str("Trace: off\n");
+    }
+}
+
+/// Print everything in the log past `cursor`, noting any wrapped-over data
+fn print_log_from(cursor: &mut klog::LogCursor) {
+    let mut chunk = [0u8; 128];
+    loop {
+        let read = without_interrupts(|| klog::LOG.lock().read(cursor, &mut chunk));
+        if read.dropped > 0 {
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "\n-- log wrapped, {} bytes dropped --", read.dropped);
+            print_str(buf.as_str());
+        }
+        if read.copied == 0 {
+            break;
+        }
+        for &b in &chunk[..read.copied] {
+            echo_byte(b);
+        }
+    }
+}
+
+fn cmd_dmesg(args: &str) {
+    let follow = match args {
+        "" => false,
+        "-f" => true,
+        "-p" => {
+            let mut text = [0u8; klog::LOG_SIZE];
+            let len = without_interrupts(|| {
+                let log = klog::LOG.lock();
+                let mut cursor = log.cursor();
+                log.read(&mut cursor, &mut text).copied
+            });
+            page(&text[..len]);
+            return;
+        }
+        _ => {
+            print_str("Usage: dmesg [-f|-p]\n");
+            return;
+        }
+    };
+
+    let mut cursor = without_interrupts(|| klog::LOG.lock().cursor());
+    print_log_from(&mut cursor);
+
+    if follow {
+        print_str("-- following log, press 'q' or Ctrl+D to stop --\n");
+        loop {
+            match wait_key_timeout(DMESG_POLL_MS) {
+                Some(b'q') | Some(KEY_EOF) => break,
+                _ => print_log_from(&mut cursor),
+            }
+        }
+    }
+}
+
+/// Version of each capability reported by `caps` when it is present.
+/// Bump a version when the behavior a host tool relies on changes. A new
+/// subsystem gets an entry here, and an arm in `capability_present` if
+/// whether it's usable depends on what boot found. Boot records what came
+/// up with `subsystem_up`, and `caps_self_test` flags any it can't find.
+const CAPS: &[(&str, u32)] = &[
+    ("shell", 1),
+    ("serial", 1),
+    ("fb", 1),
+    ("block", 1),
+    ("block_crc", 1),
+    ("klog", 1),
+    ("hhdm", 1),
+    ("tsc", 1),
+    ("pat", 1),
+    ("acpi", 1),
+    ("hpet", 1),
+    ("mouse", 1),
+    ("heap", 1),
+    ("tarfs", 1),
+    ("initrd", 1),
+    ("rtc", 1),
+    ("rng", 1),
+    ("sfs", 1),
+    ("syscall", 1),
+];
+
+fn capability_present(name: &str) -> bool {
+    match name {
+        "fb" => framebuffer_available(),
+        "block" | "block_crc" | "tarfs" | "sfs" => without_interrupts(|| ramdisk::RAMDISK.lock().is_some()),
+        "hhdm" => memory::hhdm_offset().is_some(),
+        "tsc" => tsc::ticks_per_ms().is_some(),
+        "pat" => pat::supported(),
+        "acpi" => without_interrupts(|| acpi::ACPI.lock().is_some()),
+        "hpet" => hpet::frequency().is_some(),
+        "mouse" => mouse::is_present(),
+        "heap" => heap::is_ready(),
+        "initrd" => ramdisk::loaded_from_module(),
+        _ => true,
+    }
+}
+
+/// Most subsystems `subsystem_up` can record
+const MAX_SUBSYSTEMS: usize = 32;
+
+/// Subsystems boot brought up, and how many were recorded (which may
+/// exceed `MAX_SUBSYSTEMS`)
+static SUBSYSTEMS: Mutex<([&str; MAX_SUBSYSTEMS], usize)> = Mutex::new(([""; MAX_SUBSYSTEMS], 0));
+
+/// Record that boot brought up the subsystem `caps` lists as `name`
+pub fn subsystem_up(name: &'static str) {
+    without_interrupts(|| {
+        let mut subsystems = SUBSYSTEMS.lock();
+        let (names, count) = &mut *subsystems;
+        if let Some(slot) = names.get_mut(*count) {
+            *slot = name;
+        }
+        *count += 1;
+    });
+}
+
+/// Check every subsystem boot brought up has a `CAPS` entry, and that
+/// `caps` reports it present
+pub fn caps_self_test() -> bool {
+    let (names, count) = without_interrupts(|| *SUBSYSTEMS.lock());
+    count <= MAX_SUBSYSTEMS
+        && names[..count]
+            .iter()
+            .all(|&name| CAPS.iter().any(|&(cap, _)| cap == name) && capability_present(name))
+}
+
+fn cmd_caps() {
+    for &(name, version) in CAPS {
+        let value = if capability_present(name) { version } else { 0 };
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "{name}={value}");
+        print_str(buf.as_str());
+    }
+}
+
+fn cmd_font(args: &str) {
+    let mut buf = FmtBuf::new();
+
+    without_interrupts(|| {
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        let writer = match *fb {
+            Some(ref mut writer) => writer,
+            None => {
+                let _ = writeln!(buf, "Framebuffer: not available (serial-only mode)");
+                return;
+            }
+        };
+        match args {
+            "" => {}
+            "reset" => {
+                let _ = writer.set_font(Ok(font::Font::builtin()));
+            }
+            _ => {
+                let _ = writeln!(buf, "Usage: font [reset]");
+                return;
+            }
+        }
+        let font = writer.font();
+        let _ = writeln!(buf, "Font: 8x{}, {} glyphs", font.height(), font.glyph_count());
+    });
+
+    print_str(buf.as_str());
+}
+
+fn cmd_color(args: &str) {
+    let (fg_name, rest) = split_word(args);
+    let (bg_name, rest) = split_word(rest);
+    if !rest.is_empty() {
+        print_str("Usage: color [<fg> [<bg>] | reset]\n");
+        return;
+    }
+
+    let colors = match (fg_name, bg_name) {
+        ("", _) => None,
+        ("reset", "") => {
+            let (fg, bg) = framebuffer::default_colors();
+            Some((fg, Some(bg)))
+        }
+        _ => {
+            let lookup = |name: &str| {
+                let color = framebuffer::color_by_name(name);
+                if color.is_none() {
+                    print_str("Unknown color '");
+                    print_str(name);
+                    print_str("'. Valid colors:");
+                    for name in framebuffer::COLOR_NAMES {
+                        print_str(" ");
+                        print_str(name);
+                    }
+                    print_str("\n");
+                }
+                color
+            };
+            let Some(fg) = lookup(fg_name) else { return };
+            let bg = match bg_name {
+                "" => None,
+                name => match lookup(name) {
+                    Some(bg) => Some(bg),
+                    None => return,
+                },
+            };
+            Some((fg, bg))
+        }
+    };
+
+    let mut buf = FmtBuf::new();
+    without_interrupts(|| {
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        let Some(ref mut writer) = *fb else {
+            let _ = writeln!(buf, "Framebuffer: not available (serial-only mode)");
+            return;
+        };
+        if let Some((fg, bg)) = colors {
+            writer.set_fg(fg);
+            if let Some(bg) = bg {
+                writer.set_bg(bg);
+            }
+        }
+        let (fg, bg) = writer.colors();
+        let _ = writeln!(
+            buf,
+            "Colors: fg #{:02x}{:02x}{:02x}, bg #{:02x}{:02x}{:02x}",
+            fg.r, fg.g, fg.b, bg.r, bg.g, bg.b
+        );
+    });
+    print_str(buf.as_str());
+}
+
+fn cmd_acpi() {
+    without_interrupts(|| {
+        let acpi = acpi::ACPI.lock();
+        let info = match acpi.as_ref() {
+            Some(info) => info,
+            None => {
+                print_str("ACPI not available\n");
+                return;
+            }
+        };
+
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(
+            buf,
+            "ACPI revision {}, OEM '{}', root table {}",
+            info.revision,
+            info.oem_id(),
+            if info.xsdt { "XSDT" } else { "RSDT" }
+        );
+        print_str(buf.as_str());
+        for table in info.tables() {
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "  {}  {:#012x}", table.name(), table.phys.as_u64());
+            print_str(buf.as_str());
+        }
+        if info.skipped > 0 {
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "  ({} more not recorded)", info.skipped);
+            print_str(buf.as_str());
+        }
+    });
+}
+
+fn cmd_hpet() {
+    let source = pit::tick_source();
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "System tick: {} at {} Hz", source.name(), pit::frequency());
+    print_str(buf.as_str());
+
+    let (Some(freq), Some(period), Some(count), Some(timers)) = (
+        hpet::frequency(),
+        hpet::period_fs(),
+        hpet::counter(),
+        hpet::timer_count(),
+    ) else {
+        print_str("HPET not available\n");
+        return;
+    };
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "HPET: {freq} Hz ({period} fs period), {timers} timers");
+    let _ = writeln!(buf, "Counter: {count:#018x}");
+    print_str(buf.as_str());
+
+    if let Some(ticks_per_ms) = tsc::ticks_per_ms() {
+        let start = tsc::read();
+        hpet::sleep_us(10_000);
+        let us = (tsc::read() - start) * 1000 / ticks_per_ms;
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "10 ms HPET delay took {us} us by the TSC");
+        print_str(buf.as_str());
+    }
+}
+
+fn cmd_panicmode(args: &str) {
+    if !args.is_empty() {
+        match power::PanicPolicy::parse(args) {
+            Some(policy) => power::set_panic_policy(policy),
+            None => {
+                print_str("Usage: panicmode [halt|reboot|recover]\n");
+                return;
+            }
+        }
+    }
+    print_str("Panic policy: ");
+    print_str(power::panic_policy().name());
+    print_str("\n");
+}
+
+fn cmd_irqs(args: &str) {
+    let (action, rest) = split_word(args);
+    if !action.is_empty() {
+        let irq = match parse_number(rest.trim()) {
+            Some(irq) if irq < 16 => irq as u8,
+            _ => {
+                print_str("Usage: irqs [mask|unmask <irq 0-15>]\n");
+                return;
+            }
+        };
+        match action {
+            "mask" => without_interrupts(|| pic::mask_irq(irq)),
+            "unmask" => without_interrupts(|| pic::unmask_irq(irq)),
+            _ => {
+                print_str("Usage: irqs [mask|unmask <irq 0-15>]\n");
+                return;
+            }
+        }
+    }
+
+    let (master, slave) = without_interrupts(pic::get_masks);
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "PIC masks: master {master:#010b}, slave {slave:#010b}");
+    let _ = write!(buf, "Unmasked:");
+    let masks = (slave as u16) << 8 | master as u16;
+    for irq in (0..16).filter(|irq| masks & (1 << irq) == 0) {
+        let _ = write!(buf, " {irq}");
+    }
+    let _ = writeln!(buf);
+    print_str(buf.as_str());
+}
+
+const SYSCALL_USAGE: &str = "Usage: syscall [write <text> | exit [code] | <number>]\n";
+
+fn cmd_syscall(args: &str) {
+    let (action, rest) = split_word(args);
+    let mut buf = FmtBuf::new();
+    match action {
+        "" => {
+            for (number, name) in (0..).map_while(|n| Some((n, syscall::name(n)?))) {
+                let _ = writeln!(buf, "{number:>3}  {name}");
+            }
+            let _ = writeln!(buf, "{} calls since boot", syscall::call_count());
+        }
+        "write" => {
+            let mut text = FmtBuf::new();
+            let _ = writeln!(text, "{rest}");
+            let bytes = text.as_str().as_bytes();
+            let written = syscall::invoke(syscall::SYS_WRITE, bytes.as_ptr() as u64, bytes.len() as u64, 0);
+            let _ = writeln!(buf, "write returned {written}");
+        }
+        "exit" => {
+            let code = if rest.is_empty() { Some(0) } else { parse_number(rest) };
+            let Some(code) = code else {
+                print_str(SYSCALL_USAGE);
+                return;
+            };
+            let result = syscall::invoke(syscall::SYS_EXIT, code, 0, 0);
+            let _ = writeln!(buf, "exit returned {result}");
+        }
+        _ => {
+            let Some(number) = parse_number(action).filter(|_| rest.is_empty()) else {
+                print_str(SYSCALL_USAGE);
+                return;
+            };
+            let result = syscall::invoke(number, 0, 0, 0);
+            let _ = writeln!(buf, "RAX = {result:#x}");
+        }
+    }
+    print_str(buf.as_str());
+}
+
+fn cmd_date() {
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "{}", rtc::now());
+    print_str(buf.as_str());
+}
+
+/// Default `beep` tone and length, and the longest accepted length
+const BEEP_HZ: u64 = 880;
+const BEEP_MS: u64 = 200;
+const MAX_BEEP_MS: u64 = 5000;
+
+fn cmd_beep(args: &str) {
+    let (freq, rest) = split_word(args);
+    let (ms, rest) = split_word(rest);
+    let freq = if freq.is_empty() { Some(BEEP_HZ) } else { parse_number(freq) };
+    let ms = if ms.is_empty() { Some(BEEP_MS) } else { parse_number(ms) };
+    let range = speaker::MIN_FREQUENCY as u64..=speaker::MAX_FREQUENCY as u64;
+    let (freq, ms) = match (freq, ms) {
+        (Some(freq), Some(ms)) if rest.is_empty() && range.contains(&freq) && ms <= MAX_BEEP_MS => (freq, ms),
+        _ => {
+            print_str("Usage: beep [<hz 20-20000> [<ms up to 5000>]]\n");
+            return;
+        }
+    };
+    if !speaker::beep(freq as u32, ms) {
+        print_str("Timer not running\n");
+    }
+}
+
+fn cmd_rand() {
+    let mut buf = FmtBuf::new();
+    match rng::u64() {
+        Some(value) => {
+            let _ = writeln!(buf, "{value} ({value:#018x}) from {}", rng::source().name());
+        }
+        None => {
+            let _ = writeln!(buf, "RDRAND failed to return a value");
+        }
+    }
+    print_str(buf.as_str());
+}
+
+fn cmd_uptime() {
+    let ms = pit::uptime_ms();
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(
+        buf,
+        "Up {}.{:03} s ({} ticks at {} Hz)",
+        ms / 1000,
+        ms % 1000,
+        pit::ticks(),
+        pit::frequency()
+    );
+    print_str(buf.as_str());
+}
+
+/// Tick count at the previous `ticks` command
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn cmd_ticks() {
+    let ticks = pit::ticks();
+    let hz = pit::frequency();
+    let last = LAST_TICKS.swap(ticks, Ordering::Relaxed);
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "Ticks:     {ticks}");
+    let _ = writeln!(buf, "Frequency: {hz} Hz ({})", pit::tick_source().name());
+    if last != 0 && hz != 0 {
+        let delta = ticks - last;
+        let ms = delta * 1000 / hz as u64;
+        let _ = writeln!(buf, "Since last 'ticks': {delta} ticks ({}.{:03} s)", ms / 1000, ms % 1000);
+    }
+    if ticks == 0 {
+        let hint = if hz == 0 {
+            "the timer was never initialized"
+        } else if pic::get_masks().0 & 1 != 0 {
+            "IRQ0 is masked at the PIC"
+        } else {
+            "interrupts may be disabled"
+        };
+        let _ = writeln!(buf, "The timer isn't firing: {hint}");
+    }
+    print_str(buf.as_str());
+}
+
+/// Longest accepted `sleep`, in seconds
+const MAX_SLEEP_SECS: u64 = 600;
+/// How often `sleep` checks for a key to stop early
+const SLEEP_SLICE_MS: u64 = 50;
+
+/// Parse seconds with up to three decimal places into milliseconds
+fn parse_seconds_ms(s: &str) -> Option<u64> {
+    let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+    if (whole.is_empty() && frac.is_empty()) || frac.len() > 3 {
+        return None;
+    }
+    let digits = |d: &str| d.bytes().all(|b| b.is_ascii_digit());
+    if !digits(whole) || !digits(frac) {
+        return None;
+    }
+    let mut ms: u64 = 0;
+    for b in whole.bytes() {
+        ms = ms.checked_mul(10)?.checked_add((b - b'0') as u64)?;
+    }
+    ms = ms.checked_mul(1000)?;
+    let mut scale = 100;
+    for b in frac.bytes() {
+        ms += (b - b'0') as u64 * scale;
+        scale /= 10;
+    }
+    Some(ms)
+}
+
+fn cmd_sleep(args: &str) {
+    let ms = match parse_seconds_ms(args) {
+        Some(ms) if ms <= MAX_SLEEP_SECS * 1000 => ms,
+        Some(_) => {
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "Sleep is limited to {MAX_SLEEP_SECS} s");
+            print_str(buf.as_str());
+            return;
+        }
+        None => {
+            print_str("Usage: sleep <seconds>\n");
+            return;
+        }
+    };
+
+    let mut left = ms;
+    while left > 0 {
+        if matches!(poll_key(), Some(b'q' | KEY_INTERRUPT | KEY_EOF)) {
+            print_str("Interrupted\n");
+            return;
+        }
+        let slice = left.min(SLEEP_SLICE_MS);
+        if !pit::sleep_ms(slice) {
+            print_str("Timer not running\n");
+            return;
+        }
+        left -= slice;
+    }
+}
+
+fn cmd_mem() {
+    use memory::RegionKind;
+
+    without_interrupts(|| {
+        let map = memory::MEMORY_MAP.lock();
+        let Some(map) = map.as_ref() else {
+            print_str("Memory map not available\n");
+            return;
+        };
+
+        let total = map.total_bytes();
+        let usable = map.bytes(RegionKind::Usable);
+        let reclaimable =
+            map.bytes(RegionKind::BootloaderReclaimable) + map.bytes(RegionKind::AcpiReclaimable);
+        let reserved = total - usable - reclaimable;
+
+        let mut buf = FmtBuf::new();
+        for (label, bytes) in [
+            ("Total", total),
+            ("Usable", usable),
+            ("Reclaimable", reclaimable),
+            ("Reserved", reserved),
+        ] {
+            let _ = writeln!(buf, "{label:<12} {:>10} KB {:>7} MB", bytes / 1024, bytes >> 20);
+        }
+        print_str(buf.as_str());
+
+        print_str("\nBy type:\n");
+        print_region_totals(map);
+    });
+
+    if let Some((allocated, total)) = without_interrupts(memory::frame_counts) {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "\nFrames: {allocated} of {total} allocated (4 KB each)");
+        print_str(buf.as_str());
+    }
+    if heap::is_ready() {
+        let (used, free) = without_interrupts(heap::usage);
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "Heap:   {} KB used, {} KB free", used / 1024, free / 1024);
+        print_str(buf.as_str());
+    }
+}
+
+/// Region count and size for each type present, then any entries dropped
+fn print_region_totals(map: &memory::MemoryMap) {
+    for kind in memory::RegionKind::ALL {
+        let count = map.regions().filter(|r| r.kind == kind).count();
+        if count == 0 {
+            continue;
+        }
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(
+            buf,
+            "  {:<24} {:>3} regions {:>10} KB",
+            kind.name(),
+            count,
+            map.bytes(kind) / 1024
+        );
+        print_str(buf.as_str());
+    }
+    if map.skipped > 0 {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "  ({} more entries not recorded)", map.skipped);
+        print_str(buf.as_str());
+    }
+}
+
+fn cmd_memmap() {
+    without_interrupts(|| {
+        let map = memory::MEMORY_MAP.lock();
+        let Some(map) = map.as_ref() else {
+            print_str("Memory map not available\n");
+            return;
+        };
+
+        // Already sorted by base address when the map was recorded
+        print_str("Start              End                  Type                     Size\n");
+        for region in map.regions() {
+            let mut buf = FmtBuf::new();
+            let end = region.base + region.length;
+            let _ = write!(buf, "{:#018x}-{end:#018x} : {:<22} : ", region.base, region.kind.name());
+            let _ = match region.length {
+                len if len >= 1 << 20 => writeln!(buf, "{:>7} MB", len >> 20),
+                len if len >= 1 << 10 => writeln!(buf, "{:>7} KB", len >> 10),
+                len => writeln!(buf, "{len:>7} B"),
+            };
+            print_str(buf.as_str());
+        }
+
+        print_str("\nTotals:\n");
+        print_region_totals(map);
+    });
+}
+
+fn cmd_latency(args: &str) {
+    match args {
+        "" => {}
+        "reset" => {
+            latency::reset();
+            print_str("Latency histogram cleared\n");
+            return;
+        }
+        _ => {
+            print_str("Usage: latency [reset]\n");
+            return;
+        }
+    }
+
+    let (ticks_per_ms, hz) = match (tsc::ticks_per_ms(), pit::frequency()) {
+        (Some(t), hz) if hz > 0 => (t, hz as u64),
+        _ => {
+            print_str("Timer or TSC not initialized\n");
+            return;
+        }
+    };
+    let period = ticks_per_ms * 1000 / hz;
+    let counts = latency::snapshot();
+
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "Timer period: {} us ({hz} Hz)", period * 1000 / ticks_per_ms);
+    let _ = writeln!(buf, "        gap (us)        count");
+    print_str(buf.as_str());
+
+    for (i, &count) in counts.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        let low = (1u64 << i) * 1000 / ticks_per_ms;
+        let high = (1u64 << i).saturating_mul(2) * 1000 / ticks_per_ms;
+        let mut buf = FmtBuf::new();
+        let _ = write!(buf, "  {low:>8} - {high:<8} {count:>8}");
+        if 1u64 << i >= period * 2 {
+            let _ = write!(buf, "  <-- delayed");
+        }
+        let _ = writeln!(buf);
+        print_str(buf.as_str());
+    }
+}
+
+fn cmd_keymap() {
+    print_str("Extended keys (E0-prefixed):\n");
+    for ext in keyboard::EXTENDED_KEYS.iter() {
+        let mut buf = FmtBuf::new();
+        let _ = write!(buf, "  E0 {:02X}  {:<14} -> ", ext.scancode, ext.name);
+        if ext.ascii == b'\n' {
+            let _ = writeln!(buf, "Enter");
+        } else if ext.ascii >= 0x80 {
+            let _ = writeln!(buf, "key code {:#04x}", ext.ascii);
+        } else {
+            let _ = writeln!(buf, "'{}'", ext.ascii as char);
+        }
+        print_str(buf.as_str());
+    }
+    print_str("Keypad (Num Lock on / off):\n");
+    for pad in keyboard::KEYPAD_KEYS.iter() {
+        let mut buf = FmtBuf::new();
+        let _ = write!(buf, "  {:02X}     '{}' / ", pad.scancode, pad.digit as char);
+        if pad.nav == 0 {
+            let _ = writeln!(buf, "nothing");
+        } else {
+            let _ = writeln!(buf, "key code {:#04x}", pad.nav);
+        }
+        print_str(buf.as_str());
+    }
+    print_str("Ctrl+Shift+U <hex> Enter inserts a codepoint (control codes act as keys)\n");
+}
+
+fn cmd_setlayout(args: &str) {
+    let name = args.trim_end();
+    if !name.is_empty() && !keyboard::set_layout(name) {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "Unknown layout '{name}'");
+        print_str(buf.as_str());
+    }
+    let mut buf = FmtBuf::new();
+    let _ = write!(buf, "Layout: {} (available:", keyboard::layout().name);
+    for layout in keyboard::LAYOUTS.iter() {
+        let _ = write!(buf, " {}", layout.name);
+    }
+    let _ = writeln!(buf, ")");
+    print_str(buf.as_str());
+}
+
+fn cmd_diskinfo() {
+    let mut buf = FmtBuf::new();
+    without_interrupts(|| match ramdisk::RAMDISK.lock().as_ref() {
+        Some(disk) => write_device_info(&mut buf, "RAM disk", disk),
+        None => {
+            let _ = writeln!(buf, "RAM disk: not initialized");
+        }
+    });
+    print_str(buf.as_str());
+}
+
+/// Geometry of any block device, through the `BlockDevice` trait alone
+fn write_device_info(buf: &mut FmtBuf, name: &str, dev: &dyn BlockDevice) {
+    let (size, count) = (dev.block_size() as u64, dev.block_count());
+    let bytes = size * count;
+    let _ = writeln!(buf, "{name}:");
+    let _ = writeln!(buf, "  Block size:  {size} bytes");
+    let _ = writeln!(buf, "  Blocks:      {count}");
+    let _ = writeln!(buf, "  Capacity:    {bytes} bytes ({} KB, {} MB)", bytes / 1024, bytes / (1024 * 1024));
+    let _ = writeln!(buf, "  Read-only:   {}", if dev.is_read_only() { "yes" } else { "no" });
+}
+
+fn cmd_ramdisk(args: &str) {
+    const USAGE: &str = "Usage: ramdisk checked|readonly [on|off]\n";
+    let (setting, mode) = split_word(args);
+    if !matches!(setting, "checked" | "readonly") {
+        print_str(USAGE);
+        return;
+    }
+    let enable = match mode {
+        "" => None,
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => {
+            print_str(USAGE);
+            return;
+        }
+    };
+
+    let mut buf = FmtBuf::new();
+
+    without_interrupts(|| {
+        let mut rd = ramdisk::RAMDISK.lock();
+        let ramdisk = match *rd {
+            Some(ref mut ramdisk) => ramdisk,
+            None => {
+                let _ = writeln!(buf, "RAM disk: not available");
+                return;
+            }
+        };
+
+        if setting == "readonly" {
+            if let Some(enable) = enable {
+                ramdisk.set_read_only(enable);
+            }
+            let state = if ramdisk.is_read_only() { "on" } else { "off" };
+            let _ = writeln!(buf, "Read-only: {state}");
+            return;
+        }
+
+        if let Some(enable) = enable {
+            if let Err(e) = ramdisk.set_checked(enable) {
+                let _ = writeln!(buf, "Failed to change checked mode: {e}");
+                return;
+            }
+        }
+        let state = if ramdisk.is_checked() { "on" } else { "off" };
+        let _ = writeln!(buf, "Checked mode: {state}");
+    });
+
+    print_str(buf.as_str());
+}
+
+fn cmd_crc() {
+    let mut buf = FmtBuf::new();
+
+    let total = without_interrupts(|| match *ramdisk::RAMDISK.lock() {
+        None => {
+            let _ = writeln!(buf, "RAM disk: not available");
+            None
+        }
+        Some(ref ramdisk) if !ramdisk.is_checked() => {
+            let _ = writeln!(buf, "Checked mode is off; use 'ramdisk checked on'");
+            None
+        }
+        Some(ref ramdisk) => Some(ramdisk.block_count()),
+    });
+
+    if let Some(total) = total {
+        // List the first few failures, then just count
+        const MAX_LISTED: usize = 16;
+        let mut failed = 0usize;
+        let mut progress = Progress::new(total);
+        let result = ramdisk_chunks(0..total, &mut progress, |ramdisk, id| {
+            if ramdisk.verify_block(id).is_err() {
+                if failed < MAX_LISTED {
+                    let _ = writeln!(buf, "  block {id}: CRC mismatch");
+                }
+                failed += 1;
+            }
+            true
+        });
+        drop(progress);
+        if failed > MAX_LISTED {
+            let _ = writeln!(buf, "  ... and {} more", failed - MAX_LISTED);
+        }
+        match result {
+            Ok(()) => {
+                let _ = writeln!(buf, "{failed} of {total} blocks failed verification");
+            }
+            Err(stop) => stop.report(&mut buf),
+        }
+    }
+
+    print_str(buf.as_str());
+}
+
+/// Lines in a full `hexdump` of one block
+const HEXDUMP_LINES: usize = BLOCK_SIZE / 16;
+
+fn cmd_hexdump(args: &str) {
+    let (block, rest) = split_word(args);
+    let (lines, _) = split_word(rest);
+    let Some(block_id) = parse_number(block) else {
+        print_str("Usage: hexdump <block> [lines]\n");
+        return;
+    };
+    let lines = match lines {
+        "" => HEXDUMP_LINES,
+        n => match parse_number(n) {
+            Some(n) if n > 0 && n <= HEXDUMP_LINES as u64 => n as usize,
+            _ => {
+                let mut buf = FmtBuf::new();
+                let _ = writeln!(buf, "Lines must be between 1 and {HEXDUMP_LINES}");
+                print_str(buf.as_str());
+                return;
+            }
+        },
+    };
+
+    // Copy the block out so the lock isn't held while printing
+    let mut data = [0u8; BLOCK_SIZE];
+    let result = without_interrupts(|| {
+        let rd = ramdisk::RAMDISK.lock();
+        let disk = rd.as_ref().ok_or(BlockError::NotReady)?;
+        disk.read_block(block_id, &mut data)
+    });
+    if let Err(e) = result {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "{e}");
+        print_str(buf.as_str());
+        return;
+    }
+
+    for (i, chunk) in data.chunks_exact(16).take(lines).enumerate() {
+        let mut buf = FmtBuf::new();
+        let _ = write!(buf, "{:04x}  ", i * 16);
+        for (j, byte) in chunk.iter().enumerate() {
+            let gap = if j == 7 { "  " } else { " " };
+            let _ = write!(buf, "{byte:02x}{gap}");
+        }
+        let _ = buf.write_str(" |");
+        for &byte in chunk {
+            let shown = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+            let _ = buf.write_char(shown);
+        }
+        let _ = writeln!(buf, "|");
+        print_str(buf.as_str());
+    }
+}
+
+fn cmd_readblk(args: &str) {
+    let Some(block_id) = parse_number(args.trim_end()) else {
+        print_str("Usage: readblk <block>\n");
+        return;
+    };
+
+    let mut data = [0u8; BLOCK_SIZE];
+    let result = without_interrupts(|| {
+        let rd = ramdisk::RAMDISK.lock();
+        let disk = rd.as_ref().ok_or(BlockError::NotReady)?;
+        disk.read_block(block_id, &mut data)
+    });
+    if let Err(e) = result {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "{e}");
+        print_str(buf.as_str());
+        return;
+    }
+
+    let len = data.iter().position(|&b| b == 0).unwrap_or(BLOCK_SIZE);
+    for &byte in &data[..len] {
+        echo_file_byte(byte);
+    }
+    print_str("\n");
+}
+
+fn cmd_writeblk(args: &str) {
+    let (block, text) = split_word(args);
+    let Some(block_id) = parse_number(block) else {
+        print_str("Usage: writeblk <block> <text>\n");
+        return;
+    };
+    if text.len() > BLOCK_SIZE {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "Text is longer than {BLOCK_SIZE} bytes");
+        print_str(buf.as_str());
+        return;
+    }
+
+    let mut data = [0u8; BLOCK_SIZE];
+    data[..text.len()].copy_from_slice(text.as_bytes());
+    let result = without_interrupts(|| {
+        let mut rd = ramdisk::RAMDISK.lock();
+        let disk = rd.as_mut().ok_or(BlockError::NotReady)?;
+        disk.write_block(block_id, &data)
+    });
+    let mut buf = FmtBuf::new();
+    let _ = match result {
+        Ok(()) => writeln!(buf, "Wrote {} bytes to block {block_id}", text.len()),
+        Err(e) => writeln!(buf, "{e}"),
+    };
+    print_str(buf.as_str());
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal number
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn cmd_debug(args: &str) {
+    match args {
+        "" => {}
+        "on" => DEBUG_MODE.store(true, Ordering::Relaxed),
+        "off" => DEBUG_MODE.store(false, Ordering::Relaxed),
+        _ => {
+            print_str("Usage: debug [on|off]\n");
+            return;
+        }
+    }
+    if DEBUG_MODE.load(Ordering::Relaxed) {
+        print_str("Debug mode: on (peek/poke enabled)\n");
+    } else {
+        print_str("Debug mode: off\n");
+    }
+}
+
+/// Map physical range `[phys, phys + len)` into the HHDM, checking that
+/// every page of it is actually mapped so the access can't fault
+///
+/// The range must also be RAM according to the memory map, so device
+/// registers are never touched; writes are limited to usable memory.
+fn checked_phys_range(phys: u64, len: u64, write: bool) -> Option<*mut u8> {
+    if !DEBUG_MODE.load(Ordering::Relaxed) {
+        print_str("Refusing: this command is dangerous; enable it with 'debug on'\n");
+        return None;
+    }
+    if memory::hhdm_offset().is_none() {
+        print_str("No HHDM offset; physical memory is unreachable\n");
+        return None;
+    }
+    let end = match phys.checked_add(len) {
+        Some(end) if end <= 1 << 52 => end,
+        _ => {
+            print_str("Address out of range\n");
+            return None;
+        }
+    };
+
+    let allowed = without_interrupts(|| {
+        let map = memory::MEMORY_MAP.lock();
+        let map = map.as_ref()?;
+        Some(if write {
+            map.covers(phys, end, |kind| kind == memory::RegionKind::Usable)
+        } else {
+            map.covers(phys, end, memory::RegionKind::is_ram)
+        })
+    });
+    match allowed {
+        Some(true) => {}
+        Some(false) => {
+            let kind = if write { "usable memory" } else { "RAM" };
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "Refusing: {phys:#x}..{end:#x} is not all {kind} in the memory map");
+            print_str(buf.as_str());
+            return None;
+        }
+        None => {
+            print_str("No memory map; can't tell RAM from device memory\n");
+            return None;
+        }
+    }
+
+    let mut page = phys & !0xFFF;
+    while page < end {
+        let virt = memory::phys_to_virt(PhysAddr::new(page));
+        if paging::translate(virt).is_none() {
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "Physical page {page:#x} is not mapped");
+            print_str(buf.as_str());
+            return None;
+        }
+        page += 4096;
+    }
+    Some(memory::phys_to_virt(PhysAddr::new(phys)).as_mut_ptr())
+}
+
+/// Access width for `peek`/`poke` from an optional -b/-w/-d flag, in bytes
+fn parse_width(args: &str) -> (u64, &str) {
+    let (flag, rest) = split_word(args);
+    match flag {
+        "-b" => (1, rest),
+        "-w" => (2, rest),
+        "-d" => (4, rest),
+        _ => (1, args),
+    }
+}
+
+fn cmd_peek(args: &str) {
+    let (width, args) = parse_width(args);
+    let (addr, rest) = split_word(args);
+    let (count, _) = split_word(rest);
+    let addr = match parse_number(addr) {
+        Some(addr) if addr % width == 0 => addr,
+        Some(_) => {
+            print_str("Address must be aligned to the access width\n");
+            return;
+        }
+        None => {
+            print_str("Usage: peek [-b|-w|-d] <addr> [count]\n");
+            return;
+        }
+    };
+    let count = match count {
+        "" => 16 / width,
+        c => match parse_number(c) {
+            Some(n) if n > 0 && n * width <= 256 => n,
+            _ => {
+                print_str("Count must be between 1 and 256 bytes' worth\n");
+                return;
+            }
+        },
+    };
+
+    let ptr = match checked_phys_range(addr, count * width, false) {
+        Some(ptr) => ptr,
+        None => return,
+    };
+
+    let per_line = 16 / width;
+    for line in (0..count).step_by(per_line as usize) {
+        let mut buf = FmtBuf::new();
+        let _ = write!(buf, "{:016x}: ", addr + line * width);
+        for i in line..(line + per_line).min(count) {
+            let at = unsafe { ptr.add((i * width) as usize) };
+            let _ = match width {
+                2 => write!(buf, "{:04x} ", unsafe { core::ptr::read_volatile(at as *const u16) }),
+                4 => write!(buf, "{:08x} ", unsafe { core::ptr::read_volatile(at as *const u32) }),
+                _ => write!(buf, "{:02x} ", unsafe { core::ptr::read_volatile(at) }),
+            };
+        }
+        let _ = writeln!(buf);
+        print_str(buf.as_str());
+    }
+}
+
+fn cmd_poke(args: &str) {
+    let (width, args) = parse_width(args);
+    let (addr, mut rest) = split_word(args);
+    let addr = match parse_number(addr) {
+        Some(addr) if !rest.is_empty() && addr % width == 0 => addr,
+        Some(_) if !rest.is_empty() => {
+            print_str("Address must be aligned to the access width\n");
+            return;
+        }
+        _ => {
+            print_str("Usage: poke [-b|-w|-d] <addr> <value>...\n");
+            return;
+        }
+    };
+
+    // Parse everything up front so a typo doesn't leave a partial write
+    let max = u32::MAX as u64 >> (32 - 8 * width);
+    let mut values = [0u32; 64];
+    let mut len = 0;
+    while !rest.is_empty() {
+        let (word, next) = split_word(rest);
+        rest = next;
+        match parse_number(word) {
+            Some(v) if v <= max && len < values.len() => {
+                values[len] = v as u32;
+                len += 1;
+            }
+            _ => {
+                let mut buf = FmtBuf::new();
+                let _ = writeln!(buf, "Invalid value '{word}' (max 64 values, each 0-{max:#x})");
+                print_str(buf.as_str());
+                return;
+            }
+        }
+    }
+
+    let ptr = match checked_phys_range(addr, len as u64 * width, true) {
+        Some(ptr) => ptr,
+        None => return,
+    };
+    for (i, &v) in values[..len].iter().enumerate() {
+        let at = unsafe { ptr.add(i * width as usize) };
+        unsafe {
+            match width {
+                2 => core::ptr::write_volatile(at as *mut u16, v as u16),
+                4 => core::ptr::write_volatile(at as *mut u32, v),
+                _ => core::ptr::write_volatile(at, v as u8),
+            }
+        }
+    }
+
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "Wrote {} bytes at {addr:#x}", len as u64 * width);
+    print_str(buf.as_str());
+}
+
+/// Block count of the RAM disk, or `None` (after saying so) if there's
+/// no RAM disk
+fn ramdisk_blocks(buf: &mut FmtBuf) -> Option<u64> {
+    let total = without_interrupts(|| ramdisk::RAMDISK.lock().as_ref().map(|ramdisk| ramdisk.block_count()));
+    if total.is_none() {
+        let _ = writeln!(buf, "RAM disk: not available");
+    }
+    total
+}
+
+fn cmd_zerofree() {
+    let mut buf = FmtBuf::new();
+
+    if let Some(total) = ramdisk_blocks(&mut buf) {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut zero = 0u64;
+        let mut progress = Progress::new(total);
+        let result = ramdisk_chunks(0..total, &mut progress, |ramdisk, id| match ramdisk.read_block(id, &mut block) {
+            Ok(()) => {
+                if block.iter().all(|&b| b == 0) {
+                    zero += 1;
+                }
+                true
+            }
+            Err(e) => {
+                let _ = writeln!(buf, "Read of block {id} failed: {e}");
+                false
+            }
+        });
+        drop(progress);
+        match result {
+            Ok(()) => {
+                let _ = writeln!(
+                    buf,
+                    "{zero} of {total} blocks are zero ({} KB in use)",
+                    (total - zero) * BLOCK_SIZE as u64 / 1024
+                );
+            }
+            Err(stop) => stop.report(&mut buf),
+        }
+    }
+
+    print_str(buf.as_str());
+}
+
+fn cmd_wipe(args: &str) {
+    let (start, rest) = split_word(args);
+    let (count, _) = split_word(rest);
+    let (start, count) = match (start.parse::<u64>(), count.parse::<u64>()) {
+        (Ok(start), Ok(count)) => (start, count),
+        _ => {
+            print_str("Usage: wipe <start> <count>\n");
+            return;
+        }
+    };
+
+    let mut buf = FmtBuf::new();
+    let Some(total) = ramdisk_blocks(&mut buf) else {
+        print_str(buf.as_str());
+        return;
+    };
+    if start >= total {
+        let _ = writeln!(buf, "Start block {start} is past the end of the disk ({total} blocks)");
+        print_str(buf.as_str());
+        return;
+    }
+    let end = match start.checked_add(count) {
+        Some(end) if end <= total => end,
+        _ => {
+            let _ = writeln!(buf, "Warning: range clamped to end of disk ({total} blocks)");
+            total
+        }
+    };
+
+    let zero = [0u8; BLOCK_SIZE];
+    let mut progress = Progress::new(end - start);
+    let result = ramdisk_chunks(start..end, &mut progress, |ramdisk, id| match ramdisk.write_block(id, &zero) {
+        Ok(()) => true,
+        Err(e) => {
+            let _ = writeln!(buf, "Write of block {id} failed: {e}");
+            false
+        }
+    });
+    drop(progress);
+    match result {
+        Ok(()) => {
+            let _ = writeln!(buf, "Wiped {} blocks ({start}..{end})", end - start);
+        }
+        Err(stop) => stop.report(&mut buf),
+    }
+
+    print_str(buf.as_str());
+}
+
+/// The byte argument of `filldisk` and `verifydisk`
+fn parse_fill_byte(args: &str, command: &str) -> Option<u8> {
+    let byte = parse_number(args.trim_end()).and_then(|n| u8::try_from(n).ok());
+    if byte.is_none() {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "Usage: {command} <byte>  (0-255, or 0x00-0xff)");
+        print_str(buf.as_str());
+    }
+    byte
+}
+
+fn cmd_filldisk(args: &str) {
+    let Some(byte) = parse_fill_byte(args, "filldisk") else {
+        return;
+    };
+    let mut buf = FmtBuf::new();
+    let Some(total) = ramdisk_blocks(&mut buf) else {
+        print_str(buf.as_str());
+        return;
+    };
+
+    let block = [byte; BLOCK_SIZE];
+    let mut progress = Progress::new(total);
+    let result = ramdisk_chunks(0..total, &mut progress, |ramdisk, id| match ramdisk.write_block(id, &block) {
+        Ok(()) => true,
+        Err(e) => {
+            let _ = writeln!(buf, "Write of block {id} failed: {e}");
+            false
+        }
+    });
+    drop(progress);
+    if let Err(stop) = result {
+        stop.report(&mut buf);
+        print_str(buf.as_str());
+        return;
+    }
+    let _ = writeln!(buf, "Filled {total} blocks with {byte:#04x}");
+
+    // The first id past the end must be rejected
+    let past_end = without_interrupts(|| ramdisk::RAMDISK.lock().as_mut().map(|ramdisk| ramdisk.write_block(total, &block)));
+    match past_end {
+        None | Some(Err(BlockError::OutOfBounds)) => {}
+        Some(Err(e)) => {
+            let _ = writeln!(buf, "Write of block {total} failed with {e}, not out of bounds");
+        }
+        Some(Ok(())) => {
+            let _ = writeln!(buf, "Write of block {total}, past the end, was accepted");
+        }
+    }
+
+    print_str(buf.as_str());
+}
+
+fn cmd_verifydisk(args: &str) {
+    let Some(byte) = parse_fill_byte(args, "verifydisk") else {
+        return;
+    };
+    let mut buf = FmtBuf::new();
+
+    if let Some(total) = ramdisk_blocks(&mut buf) {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut progress = Progress::new(total);
+        let result = ramdisk_chunks(0..total, &mut progress, |ramdisk, id| {
+            if let Err(e) = ramdisk.read_block(id, &mut block) {
+                let _ = writeln!(buf, "Read of block {id} failed: {e}");
+                return false;
+            }
+            if let Some(offset) = block.iter().position(|&b| b != byte) {
+                let found = block[offset];
+                let _ = writeln!(buf, "Mismatch in block {id} at offset {offset}: {found:#04x}, expected {byte:#04x}");
+                return false;
+            }
+            true
+        });
+        drop(progress);
+        match result {
+            Ok(()) => {
+                let _ = writeln!(buf, "All {total} blocks hold {byte:#04x}");
+            }
+            Err(stop) => stop.report(&mut buf),
+        }
+    }
+
+    print_str(buf.as_str());
+}
+
+/// Largest run of blocks one `blkverify` iteration touches
+const VERIFY_MAX_RUN: usize = 4;
+
+/// Why a `blkverify` iteration failed
+enum VerifyFailure {
+    Io(u64, BlockError),
+    Mismatch(u64),
+    OutOfRangeAccepted(u64),
+}
+
+/// One `blkverify` round trip on `count` blocks starting at `start`
+///
+/// The blocks' old contents are restored before returning, even when the
+/// check fails, so the disk is left as it was found.
+fn verify_round_trip(
+    ramdisk: &mut ramdisk::RamDisk,
+    rng: &mut rand::XorShift64,
+    start: u64,
+    count: usize,
+) -> Result<(), VerifyFailure> {
+    let len = count * BLOCK_SIZE;
+    let mut saved = [0u8; VERIFY_MAX_RUN * BLOCK_SIZE];
+    let mut data = [0u8; VERIFY_MAX_RUN * BLOCK_SIZE];
+    let mut readback = [0u8; VERIFY_MAX_RUN * BLOCK_SIZE];
+
+    ramdisk
+        .read_blocks(start, &mut saved[..len])
+        .map_err(|e| VerifyFailure::Io(start, e))?;
+    rng.fill(&mut data[..len]);
+
+    let result = (|| {
+        for (i, chunk) in data[..len].chunks_exact(BLOCK_SIZE).enumerate() {
+            let id = start + i as u64;
+            let block: &[u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+            ramdisk.write_block(id, block).map_err(|e| VerifyFailure::Io(id, e))?;
+        }
+        // Check both the single-block and the multi-block read paths
+        for (i, chunk) in data[..len].chunks_exact(BLOCK_SIZE).enumerate() {
+            let id = start + i as u64;
+            let mut block = [0u8; BLOCK_SIZE];
+            ramdisk.read_block(id, &mut block).map_err(|e| VerifyFailure::Io(id, e))?;
+            if block[..] != *chunk {
+                return Err(VerifyFailure::Mismatch(id));
+            }
+        }
+        ramdisk
+            .read_blocks(start, &mut readback[..len])
+            .map_err(|e| VerifyFailure::Io(start, e))?;
+        for (i, (got, want)) in readback[..len]
+            .chunks_exact(BLOCK_SIZE)
+            .zip(data[..len].chunks_exact(BLOCK_SIZE))
+            .enumerate()
+        {
+            if got != want {
+                return Err(VerifyFailure::Mismatch(start + i as u64));
+            }
+        }
+        Ok(())
+    })();
+
+    ramdisk
+        .write_blocks(start, &saved[..len])
+        .map_err(|e| VerifyFailure::Io(start, e))?;
+    result
+}
+
+fn cmd_blkverify(args: &str) {
+    let (iterations, rest) = split_word(args);
+    let (seed, _) = split_word(rest);
+    let iterations = match parse_number(iterations) {
+        Some(n) if n > 0 => n,
+        _ => {
+            print_str("Usage: blkverify <iterations> [seed]\n");
+            return;
+        }
+    };
+    let seed = match seed {
+        "" => tsc::read(),
+        s => match parse_number(s) {
+            Some(seed) => seed,
+            None => {
+                print_str("Invalid seed\n");
+                return;
+            }
+        },
+    };
+
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "blkverify: {iterations} iterations, seed {seed:#x}");
+    print_str(buf.as_str());
+
+    let mut rng = rand::XorShift64::new(seed);
+    let mut progress = Progress::new(iterations);
+    let mut bytes = 0u64;
+    let mut done = 0u64;
+    let mut failure = None;
+    let start_tsc = tsc::read();
+
+    while done < iterations {
+        // Keep the lock per iteration so keyboard interrupts get through
+        let result = without_interrupts(|| {
+            let mut rd = ramdisk::RAMDISK.lock();
+            let ramdisk = rd.as_mut()?;
+            let total = ramdisk.block_count();
+            if rng.below(16) == 0 {
+                let id = total + rng.below(1024);
+                let mut block = [0u8; BLOCK_SIZE];
+                return Some(match ramdisk.read_block(id, &mut block) {
+                    Ok(()) => Err(VerifyFailure::OutOfRangeAccepted(id)),
+                    Err(_) => Ok(0),
+                });
+            }
+            let count = (1 + rng.below(VERIFY_MAX_RUN as u64)).min(total) as usize;
+            let start = rng.below(total - count as u64 + 1);
+            Some(verify_round_trip(ramdisk, &mut rng, start, count).map(|()| count))
+        });
+        match result {
+            None => {
+                drop(progress);
+                print_str("RAM disk: not available\n");
+                return;
+            }
+            Some(Ok(count)) => bytes += (count * BLOCK_SIZE) as u64,
+            Some(Err(e)) => {
+                failure = Some(e);
+                break;
+            }
+        }
+        done += 1;
+        if !pump(&mut progress, done) {
+            break;
+        }
+    }
+    drop(progress);
+
+    let mut buf = FmtBuf::new();
+    match failure {
+        Some(VerifyFailure::Io(id, e)) => {
+            let _ = writeln!(buf, "FAIL at iteration {}: block {id}: {e}", done + 1);
+        }
+        Some(VerifyFailure::Mismatch(id)) => {
+            let _ = writeln!(buf, "FAIL at iteration {}: block {id} read back different data", done + 1);
+        }
+        Some(VerifyFailure::OutOfRangeAccepted(id)) => {
+            let _ = writeln!(buf, "FAIL at iteration {}: out-of-range block {id} was accepted", done + 1);
+        }
+        None if done < iterations => {
+            let _ = writeln!(buf, "Stopped after {done} of {iterations} iterations");
+        }
+        None => {
+            let _ = write!(buf, "PASS: {done} iterations, {} KB verified", bytes / 1024);
+            if let Some(ticks_per_ms) = tsc::ticks_per_ms() {
+                let ms = (tsc::read() - start_tsc) / ticks_per_ms;
+                if ms > 0 {
+                    let _ = write!(buf, " at {} KB/s", bytes * 1000 / 1024 / ms);
+                }
+            }
+            let _ = writeln!(buf);
+        }
+    }
+    if failure.is_some() {
+        let _ = writeln!(buf, "Reproduce with: blkverify {iterations} {seed:#x}");
+    }
+    print_str(buf.as_str());
+}
+
+/// Blocks listed by `blkheat`
+const BLKHEAT_TOP: usize = 10;
+
+fn cmd_blkheat(args: &str) {
+    let mut buf = FmtBuf::new();
+
+    without_interrupts(|| {
+        let mut rd = ramdisk::RAMDISK.lock();
+        let ramdisk = match *rd {
+            Some(ref mut ramdisk) => ramdisk,
+            None => {
+                let _ = writeln!(buf, "RAM disk: not available");
+                return;
+            }
+        };
+
+        match args {
+            "" => {}
+            "on" | "off" => {
+                match ramdisk.set_tracking(args == "on") {
+                    Ok(()) => {
+                        let _ = writeln!(buf, "Block heat tracking {args}");
+                    }
+                    Err(e) => {
+                        let _ = writeln!(buf, "Block heat tracking unavailable: {e}");
+                    }
+                }
+                return;
+            }
+            "reset" => {
+                for heat in ramdisk.heat().unwrap_or(&[]) {
+                    heat.reset();
+                }
+                let _ = writeln!(buf, "Block heat counters cleared");
+                return;
+            }
+            _ => {
+                let _ = writeln!(buf, "Usage: blkheat [on|off|reset]");
+                return;
+            }
+        }
+
+        let heat = match ramdisk.heat() {
+            Some(heat) => heat,
+            None => {
+                let _ = writeln!(buf, "Block heat tracking unavailable");
+                return;
+            }
+        };
+
+        // Keep the hottest blocks sorted by total accesses, hottest first
+        let mut top = [(0u64, 0u64); BLKHEAT_TOP];
+        for (id, h) in heat.iter().enumerate() {
+            let total = h.reads() as u64 + h.writes() as u64;
+            if total > top[BLKHEAT_TOP - 1].1 {
+                let pos = top.iter().position(|&(_, t)| total > t).unwrap();
+                top.copy_within(pos..BLKHEAT_TOP - 1, pos + 1);
+                top[pos] = (id as u64, total);
+            }
+        }
+
+        let state = if ramdisk.is_tracking() { "on" } else { "off" };
+        let _ = writeln!(buf, "Tracking {state}. Most accessed blocks:");
+        let _ = writeln!(buf, "   block      reads     writes");
+        for &(id, _) in top.iter().take_while(|&&(_, total)| total > 0) {
+            let h = &heat[id as usize];
+            let _ = writeln!(buf, "  {id:>6} {:>10} {:>10}", h.reads(), h.writes());
+        }
+        if top[0].1 == 0 {
+            let _ = writeln!(buf, "  (no accesses recorded)");
+        }
+    });
+
+    print_str(buf.as_str());
+}
+
+/// Block reads per `cachetune` batch; interrupts are off during a batch
+const CACHETUNE_BATCH: u64 = 256;
+/// Block reads per `cachetune` run, a whole number of batches
+const CACHETUNE_READS: u64 = 80 * CACHETUNE_BATCH;
+/// Blocks in the workload's hot set
+const CACHETUNE_HOT_BLOCKS: u64 = 64;
+
+/// Cache sizes `cachetune` compares, in blocks (0 = uncached)
+const CACHETUNE_SIZES: [usize; 6] = [0, 16, 32, 64, 128, 256];
+
+/// Run the `cachetune` workload against `device`, returning the TSC ticks
+/// spent in reads, or `None` if stopped
+///
+/// Four in five reads go to a small hot set and the rest anywhere on the
+/// disk, roughly what a filesystem's metadata-heavy access looks like.
+/// Only reads are issued, so the disk contents are never touched. The
+/// same `seed` always produces the same sequence of reads.
+fn run_cache_workload(
+    device: &impl BlockDevice,
+    seed: u64,
+    progress: &mut Progress,
+    base: u64,
+) -> Option<u64> {
+    let mut rng = rand::XorShift64::new(seed);
+    let mut block = [0u8; BLOCK_SIZE];
+    let total = device.block_count();
+    let hot = CACHETUNE_HOT_BLOCKS.min(total);
+    let mut ticks = 0;
+
+    // Read in batches so keyboard interrupts get through in between
+    let mut done = 0;
+    while done < CACHETUNE_READS {
+        ticks += without_interrupts(|| {
+            let start = tsc::read();
+            for _ in 0..CACHETUNE_BATCH {
+                let id = if rng.below(5) < 4 { rng.below(hot) } else { rng.below(total) };
+                device.read_block(id, &mut block).ok()?;
+            }
+            Some(tsc::read() - start)
+        })?;
+        done += CACHETUNE_BATCH;
+        if !pump(progress, base + done) {
+            return None;
+        }
+    }
+    Some(ticks)
+}
+
+/// One `cachetune` run: cache size, hits, misses and TSC ticks
+type CachetuneRow = (usize, u64, u64, u64);
+
+/// Run the `cachetune` workload once with a `size`-block cache (0 for
+/// none), or `None` if stopped
+///
+/// The RAM disk lock is held with interrupts on, so the workload can be
+/// stopped; no interrupt handler touches the RAM disk. It's released
+/// again between runs rather than held for the whole sweep.
+fn cachetune_run(size: usize, seed: u64, progress: &mut Progress, base: u64) -> Option<CachetuneRow> {
+    let mut rd = ramdisk::RAMDISK.lock();
+    let disk = rd.as_mut()?;
+    if size == 0 {
+        let ticks = run_cache_workload(&*disk, seed, progress, base)?;
+        return Some((0, 0, 0, ticks));
+    }
+    // The cache only lives for this run, so the disk is left uncached
+    // afterwards, as it was
+    let cache = BlockCache::new(&mut *disk, size);
+    let ticks = run_cache_workload(&cache, seed, progress, base)?;
+    let (hits, misses) = cache.stats();
+    Some((size, hits, misses, ticks))
+}
+
+/// Print the table of `cachetune` runs and recommend the fastest size
+fn cachetune_report(rows: &[CachetuneRow], ticks_per_ms: u64) {
+    let mut buf = FmtBuf::new();
+    let _ = writeln!(buf, "  cache size   hit rate       MB/s");
+    // Fastest size so far and its MB/s; ties go to the smaller cache
+    let mut best = (0, 0);
+    for &(size, hits, misses, ticks) in rows {
+        let ms = (ticks / ticks_per_ms).max(1);
+        let kb = CACHETUNE_READS * BLOCK_SIZE as u64 / 1024;
+        let mb_per_s = kb * 1000 / 1024 / ms;
+        let percent = hits * 100 / (hits + misses).max(1);
+        let _ = match size {
+            0 => writeln!(buf, "  {:>10} {percent:>9}% {mb_per_s:>10}", "none"),
+            _ => writeln!(buf, "  {size:>10} {percent:>9}% {mb_per_s:>10}"),
+        };
+        if mb_per_s > best.1 {
+            best = (size, mb_per_s);
+        }
+    }
+    let _ = match best {
+        (0, mb_per_s) => writeln!(buf, "Best: no cache ({mb_per_s} MB/s)"),
+        (size, mb_per_s) => writeln!(buf, "Best: {size} blocks ({mb_per_s} MB/s)"),
+    };
+    if !heap::is_ready() {
+        let _ = writeln!(buf, "No heap for cache slots; only the uncached baseline was run.");
+    }
+    print_str(buf.as_str());
+}
+
+fn cmd_cachetune() {
+    let Some(ticks_per_ms) = tsc::ticks_per_ms() else {
+        print_str("TSC not calibrated\n");
+        return;
+    };
+    if without_interrupts(|| ramdisk::RAMDISK.lock().is_none()) {
+        print_str("RAM disk not initialized\n");
+        return;
+    }
+    // Without a heap there's nowhere to put cache slots
+    let sizes = if heap::is_ready() { &CACHETUNE_SIZES[..] } else { &CACHETUNE_SIZES[..1] };
+
+    // Every run replays the same reads, so the sizes are compared fairly
+    let seed = tsc::read();
+    let mut progress = Progress::new(CACHETUNE_READS * sizes.len() as u64);
+    let mut rows = [(0, 0, 0, 0); CACHETUNE_SIZES.len()];
+    for (i, &size) in sizes.iter().enumerate() {
+        match cachetune_run(size, seed, &mut progress, CACHETUNE_READS * i as u64) {
+            Some(row) => rows[i] = row,
+            None => {
+                drop(progress);
+                print_str("Stopped\n");
+                return;
+            }
+        }
+    }
+    drop(progress);
+    cachetune_report(&rows[..sizes.len()], ticks_per_ms);
+}
+
+fn cmd_reboot() {
+    print_str("Rebooting...\n");
+    power::reboot();
+}
+
+fn cmd_shutdown() {
+    print_str("Powering off...\n");
+    power::shutdown();
+}
+
+fn cmd_qemuexit(args: &str) {
+    match args.trim_end() {
+        "" => qemu::exit_success(),
+        "fail" => qemu::exit(QemuExitCode::Failed),
+        _ => {
+            print_str("Usage: qemuexit [fail]\n");
+            return;
+        }
+    }
+    // Still here: no isa-debug-exit device
+    print_str("No QEMU debug-exit device\n");
+}
+
+// --- Files ---
+
+fn cmd_ls() {
+    without_interrupts(|| {
+        let mut rd = ramdisk::RAMDISK.lock();
+        let Some(disk) = rd.as_mut() else {
+            print_str("RAM disk not initialized\n");
+            return;
+        };
+        let mut files = 0;
+        if sfs::is_formatted(disk) {
+            match Sfs::open(disk).and_then(|fs| fs.list()) {
+                Ok(entries) => {
+                    for entry in entries {
+                        let mut buf = FmtBuf::new();
+                        let _ = writeln!(buf, "{:>10}  {}", entry.size, entry.name());
+                        print_str(buf.as_str());
+                        files += 1;
+                    }
+                }
+                Err(e) => {
+                    let mut buf = FmtBuf::new();
+                    let _ = writeln!(buf, "Filesystem error: {e}");
+                    print_str(buf.as_str());
+                }
+            }
+        } else {
+            for entry in TarFs::new(&*disk).list() {
+                let mut buf = FmtBuf::new();
+                match entry {
+                    Ok(entry) => {
+                        let _ = writeln!(buf, "{:>10}  {}", entry.size, entry.name());
+                        files += 1;
+                    }
+                    Err(e) => {
+                        let _ = writeln!(buf, "Archive error: {e}");
+                    }
+                }
+                print_str(buf.as_str());
+            }
+        }
+        if files == 0 {
+            print_str("No files\n");
+        }
+    });
+}
+
+/// Write a file byte: raw to serial, non-text bytes as '.' on screen
+fn echo_file_byte(byte: u8) {
+    let text = byte.is_ascii_graphic() || matches!(byte, b' ' | b'\n' | b'\t');
+    if text {
+        echo_byte(byte);
+        return;
+    }
+    if console::output_enabled(ConsoleKind::Framebuffer) {
+        without_interrupts(|| {
+            if let Some(ref mut writer) = *framebuffer::FRAMEBUFFER.lock() {
+                writer.write_byte(b'.');
+            }
+        });
+    }
+    if console::output_enabled(ConsoleKind::Serial) {
+        without_interrupts(|| serial::SERIAL.lock().write_byte(byte));
+    }
+}
+
+/// A file found by `cat`, in whichever format the RAM disk holds
+///
+/// Only ever lives on the stack for one command, so the size gap between
+/// the variants doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum FileEntry {
+    Tar(TarEntry),
+    Sfs(SfsEntry),
+}
+
+fn cmd_cat(args: &str) {
+    let name = args.trim_end();
+    if name.is_empty() {
+        print_str("Usage: cat <name>\n");
+        return;
+    }
+
+    let found = without_interrupts(|| -> SfsResult<Option<FileEntry>> {
+        let mut rd = ramdisk::RAMDISK.lock();
+        let disk = rd.as_mut().ok_or(BlockError::NotReady)?;
+        if sfs::is_formatted(disk) {
+            Ok(Sfs::open(disk)?.find(name)?.map(FileEntry::Sfs))
+        } else {
+            Ok(TarFs::new(&*disk).find(name)?.map(FileEntry::Tar))
+        }
+    });
+    let entry = match found {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            print_str("cat: file not found\n");
+            return;
+        }
+        Err(e) => {
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "cat: {e}");
+            print_str(buf.as_str());
+            return;
+        }
+    };
+
+    // Read a block at a time so the RAM disk isn't locked while printing
+    let mut block = [0u8; BLOCK_SIZE];
+    let mut last = b'\n';
+    for index in 0.. {
+        let len = without_interrupts(|| -> SfsResult<usize> {
+            let mut rd = ramdisk::RAMDISK.lock();
+            let disk = rd.as_mut().ok_or(BlockError::NotReady)?;
+            match entry {
+                FileEntry::Tar(ref entry) => Ok(TarFs::new(&*disk).read_chunk(entry, index, &mut block)?),
+                FileEntry::Sfs(ref entry) => Sfs::open(disk)?.read_chunk(entry, index, &mut block),
+            }
+        });
+        match len {
+            Ok(0) => break,
+            Ok(len) => {
+                for &byte in &block[..len] {
+                    echo_file_byte(byte);
+                }
+                last = block[len - 1];
+            }
+            Err(e) => {
+                let mut buf = FmtBuf::new();
+                let _ = writeln!(buf, "\ncat: {e}");
+                print_str(buf.as_str());
+                return;
+            }
+        }
+    }
+    // Keep the prompt on its own line
+    if last != b'\n' {
+        print_str("\n");
+    }
+}
+
+/// Run `f` on the RAM disk's sfs filesystem
+fn with_sfs<T>(f: impl FnOnce(&mut Sfs<&mut ramdisk::RamDisk>) -> SfsResult<T>) -> SfsResult<T> {
+    without_interrupts(|| {
+        let mut rd = ramdisk::RAMDISK.lock();
+        let disk = rd.as_mut().ok_or(BlockError::NotReady)?;
+        f(&mut Sfs::open(disk)?)
+    })
+}
+
+fn print_sfs_result(command: &str, result: SfsResult<()>) {
+    if let Err(e) = result {
+        let mut buf = FmtBuf::new();
+        let _ = writeln!(buf, "{command}: {e}");
+        print_str(buf.as_str());
+    }
+}
+
+fn cmd_mkfs() {
+    let result = without_interrupts(|| {
+        let mut rd = ramdisk::RAMDISK.lock();
+        let disk = rd.as_mut().ok_or(BlockError::NotReady)?;
+        Sfs::format(disk)?.usage()
+    });
+    match result {
+        Ok((used, total)) => {
+            let mut buf = FmtBuf::new();
+            let free = total - used;
+            let _ = writeln!(buf, "Created sfs: {free} of {total} blocks free, room for {} files", sfs::MAX_FILES);
+            print_str(buf.as_str());
+        }
+        Err(e) => print_sfs_result("mkfs", Err(e)),
+    }
+}
+
+fn cmd_touch(args: &str) {
+    let name = args.trim_end();
+    if name.is_empty() {
+        print_str("Usage: touch <name>\n");
+        return;
+    }
+    let result = with_sfs(|fs| match fs.create(name) {
+        Err(SfsError::Exists) => Ok(()),
+        result => result,
+    });
+    print_sfs_result("touch", result);
+}
+
+fn cmd_write(args: &str) {
+    let (name, text) = split_word(args);
+    if name.is_empty() {
+        print_str("Usage: write <name> <text>\n");
+        return;
+    }
+    let result = with_sfs(|fs| {
+        match fs.create(name) {
+            Ok(()) | Err(SfsError::Exists) => {}
+            Err(e) => return Err(e),
+        }
+        fs.write(name, text.as_bytes())
+    });
+    print_sfs_result("write", result);
+}
+
+fn cmd_rm(args: &str) {
+    let name = args.trim_end();
+    if name.is_empty() {
+        print_str("Usage: rm <name>\n");
+        return;
+    }
+    print_sfs_result("rm", with_sfs(|fs| fs.remove(name)));
+}
+
+// --- Scripts ---
+
+/// Execute `script` line by line, returning how many lines were run
+///
+/// Blank lines and lines starting with `#` are skipped, as are lines that
+/// aren't valid UTF-8. Execution stops after `MAX_SCRIPT_LINES` lines.
+pub fn run_script(script: &[u8]) -> usize {
+    IN_SCRIPT.store(true, Ordering::Relaxed);
+    let mut executed = 0;
+
+    for raw in script.split(|&b| b == b'\n') {
+        let line = match core::str::from_utf8(raw) {
+            Ok(line) => line.trim(),
+            Err(_) => continue,
+        };
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if executed == MAX_SCRIPT_LINES {
+            print_str("Script line limit reached, stopping\n");
+            break;
+        }
+        execute(line);
+        executed += 1;
+    }
+
+    IN_SCRIPT.store(false, Ordering::Relaxed);
+    executed
+}
+
+// --- Main shell entry point ---
+
+/// Re-enter the shell from the panic handler (`panic=recover`)
+///
+/// Returns if a lock the shell depends on is held, since the panicking
+/// code will never release it; the caller then halts as usual.
+pub fn recover() {
+    let free = keyboard::KEY_BUFFER.try_lock().is_some()
+        && framebuffer::FRAMEBUFFER.try_lock().is_some()
+        && serial::SERIAL.try_lock().is_some();
+    if !free {
+        return;
+    }
+
+    IN_SCRIPT.store(false, Ordering::Relaxed);
+    // The panic may have happened inside an IRQ handler, before its EOI
+    pic::send_eoi(pic::PIC2_OFFSET);
+    x86_64::instructions::interrupts::enable();
+
+    print_str("\n*** Kernel panic; entering recovery shell. ***\n");
+    print_str("*** State may be inconsistent: reboot as soon as possible. ***\n");
+    run();
+}
+
+pub fn run() -> ! {
+    print_str("ShadowOS v0.1.0\n");
+    print_str("Type 'help' for available commands.\n\n");
+    print_prompt();
+
+    let mut line = LineBuffer::new();
+    let mut history = History::new();
+
+    loop {
+        let key = poll_key();
+
+        // Catch up on output held back while Scroll Lock was on, and
+        // blink the cursor
+        without_interrupts(|| {
+            if let Some(ref mut writer) = *framebuffer::FRAMEBUFFER.lock() {
+                writer.flush_paused();
+                writer.tick(pit::uptime_ms());
+            }
+        });
+
+        if let Some(byte) = key {
+            handle_key(&mut line, &mut history, byte);
+        }
+        handle_mouse();
+
+        // Run a serial trigger's command in place of whatever was typed,
+        // which includes the string that fired it
+        if let Some((pattern, command)) = take_fired_trigger() {
+            line.clear();
+            let mut buf = FmtBuf::new();
+            let _ = writeln!(buf, "\n[trigger] \"{}\" seen, running: {}", pattern.as_str(), command.as_str());
+            print_str(buf.as_str());
+            execute(command.as_str());
+            print_str("[trigger] done\n");
+            print_prompt();
+        }
+
+        // Keep going while keys (or a paste) are queued up
+        if key.is_none() {
+            hlt();
+        }
+    }
+}
+
+// --- Line editing ---
+//
+// One cell is echoed per character, so character counts in the line are
+// screen columns relative to the end of the prompt. The cursor moves with the
+// ANSI cursor forward/back sequences, which both consoles understand;
+// like a terminal's, they stay within a row, so editing a line that has
+// wrapped onto a second row can misplace the cursor.
+
+/// Move the cursor by `n` cells, back or forward
+fn step_cursor(n: usize, back: bool) {
+    if n > 0 {
+        let mut buf = FmtBuf::new();
+        let _ = write!(buf, "\x1b[{n}{}", if back { 'D' } else { 'C' });
+        print_str(buf.as_str());
+    }
+}
+
+/// Echo text from the line being edited, one cell per character
+///
+/// Serial gets the UTF-8 as is, for the terminal to draw. The framebuffer
+/// draws a byte per glyph, so anything beyond ASCII shows there as `?`.
+fn echo_line_text(text: &str) {
+    print_serial(text);
+    if console::output_enabled(ConsoleKind::Framebuffer) {
+        without_interrupts(|| {
+            if let Some(ref mut writer) = *framebuffer::FRAMEBUFFER.lock() {
+                for c in text.chars() {
+                    writer.write_byte(if c.is_ascii() { c as u8 } else { b'?' });
+                }
+            }
+        });
+    }
+}
+
+/// Put the line's cursor at byte offset `to`, moving the screen cursor
+/// by the characters in between
+fn move_cursor(line: &mut LineBuffer, to: usize) {
+    let from = core::mem::replace(&mut line.cursor, to);
+    let cells = line.as_str()[from.min(to)..from.max(to)].chars().count();
+    step_cursor(cells, to < from);
+}
+
+/// Redraw from the cursor to the end of the line, blanking `erased`
+/// cells beyond it, and return the screen cursor to the line's cursor
+fn redraw_tail(line: &LineBuffer, erased: usize) {
+    let tail = line.tail();
+    echo_line_text(tail);
+    for _ in 0..erased {
+        echo_byte(b' ');
+    }
+    step_cursor(tail.chars().count() + erased, true);
+}
+
+/// Insert typed text at the cursor and show it
+fn insert_text(line: &mut LineBuffer, text: &str) {
+    if !line.insert(text.as_bytes()) {
+        return;
+    }
+    echo_line_text(text);
+    if !line.at_end() {
+        redraw_tail(line, 0);
+    }
+}
+
+/// Tab: complete the command name being typed
+///
+/// A unique match is completed in place; several are listed, then the
+/// prompt and the line are shown again. Only the first word completes.
+fn complete_command(line: &mut LineBuffer) {
+    let prefix = line.as_str();
+    if prefix.contains(' ') || !line.at_end() {
+        return;
+    }
+    let mut matches = COMMANDS.iter().map(|c| c.name).filter(|name| name.starts_with(prefix));
+    let (Some(first), second) = (matches.next(), matches.next()) else {
+        return;
+    };
+    let Some(second) = second else {
+        for &b in &first.as_bytes()[prefix.len()..] {
+            if line.push(b) {
+                echo_byte(b);
+            }
+        }
+        return;
+    };
+
+    let mut buf = FmtBuf::new();
+    let _ = write!(buf, "\n{first}  {second}");
+    for name in matches {
+        let _ = write!(buf, "  {name}");
+    }
+    let _ = writeln!(buf);
+    print_str(buf.as_str());
+    print_prompt();
+    echo_line_text(line.as_str());
+}
+
+/// Apply one key to the line being edited at the prompt
+fn handle_key(line: &mut LineBuffer, history: &mut History, byte: u8) {
+    match byte {
+        b'\n' => {
+            move_cursor(line, line.len);
+            echo_byte(b'\n');
+            history.push(line);
+            execute(line.as_str());
+            line.clear();
+            print_prompt();
+        }
+        KEY_EOF => {
+            if line.len == 0 {
+                print_str("^D\nNothing to exit; type 'reboot' to leave ShadowOS.\n");
+                print_prompt();
+            }
+        }
+        KEY_INTERRUPT => {
+            // Abandon the line, leaving it visible above the new prompt
+            move_cursor(line, line.len);
+            print_str("^C\n");
+            line.clear();
+            history.browsing = 0;
+            print_prompt();
+        }
+        KEY_CLEAR => {
+            cmd_clear();
+            print_prompt();
+            echo_line_text(line.as_str());
+            step_cursor(line.tail().chars().count(), true);
+        }
+        keyboard::KEY_UP | keyboard::KEY_DOWN => {
+            if let Some(shown) = history.recall(line, byte == keyboard::KEY_UP) {
+                replace_line(line, &shown);
+            }
+        }
+        // Backspace and Delete remove one character, which is one cell
+        8 if line.at_end() => {
+            if line.pop() > 0 {
+                do_backspace();
+            }
+        }
+        8 => {
+            let cells = usize::from(line.pop() > 0);
+            step_cursor(cells, true);
+            redraw_tail(line, cells);
+        }
+        keyboard::KEY_DELETE => {
+            let cells = usize::from(line.delete() > 0);
+            redraw_tail(line, cells);
+        }
+        keyboard::KEY_LEFT => move_cursor(line, line.cursor - line.char_len(true)),
+        keyboard::KEY_RIGHT => move_cursor(line, line.cursor + line.char_len(false)),
+        keyboard::KEY_HOME => move_cursor(line, 0),
+        keyboard::KEY_END => move_cursor(line, line.len),
+        keyboard::KEY_HEX_INPUT => match read_codepoint() {
+            // Control characters act as the key they stand for
+            Ok(c) if c.is_ascii_control() => {
+                without_interrupts(|| keyboard::KEY_BUFFER.lock().push(c as u8));
+            }
+            Ok(c) => {
+                let mut encoded = [0; 4];
+                insert_text(line, c.encode_utf8(&mut encoded));
+            }
+            Err(reason) => {
+                print_str("\n");
+                print_str(reason);
+                print_str("\n");
+                print_prompt();
+                echo_line_text(line.as_str());
+                step_cursor(line.tail().chars().count(), true);
+            }
+        },
+        b'\t' => complete_command(line),
+        // Printable ASCII
+        0x20..=0x7E => insert_text(line, char::from(byte).encode_utf8(&mut [0; 4])),
+        _ => {
+            // Ignore non-printable
+        }
     }
 }
@@ -0,0 +1,47 @@
+// PC speaker, driven by PIT channel 2 through the gate bits in port 0x61.
+
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::instructions::port::Port;
+
+use crate::pit::{self, PIT_FREQUENCY};
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const SPEAKER_CONTROL: u16 = 0x61;
+
+/// Port 0x61 bit 0 gates channel 2, bit 1 connects it to the speaker
+const SPEAKER_ENABLE: u8 = 0b11;
+
+/// Audible range accepted by `beep`, in Hz
+pub const MIN_FREQUENCY: u32 = 20;
+pub const MAX_FREQUENCY: u32 = 20_000;
+
+/// Sound the speaker at `freq_hz` for `duration_ms`
+///
+/// Timing comes from `pit::sleep_ms`, so this needs the timer running and
+/// interrupts enabled; returns `false` without a sound otherwise. Port
+/// 0x61 is restored afterwards rather than cleared, since its other bits
+/// belong to the keyboard controller.
+pub fn beep(freq_hz: u32, duration_ms: u64) -> bool {
+    if pit::frequency() == 0 || !interrupts::are_enabled() {
+        return false;
+    }
+    let divisor = (PIT_FREQUENCY / freq_hz.clamp(MIN_FREQUENCY, MAX_FREQUENCY)) as u16;
+    let prior = without_interrupts(|| unsafe {
+        // Channel 2, lobyte/hibyte, mode 3 (square wave), binary
+        Port::<u8>::new(PIT_COMMAND).write(0xB6);
+        let mut data = Port::<u8>::new(PIT_CHANNEL2);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+
+        let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+        let prior = control.read();
+        control.write(prior | SPEAKER_ENABLE);
+        prior
+    });
+
+    pit::sleep_ms(duration_ms);
+
+    without_interrupts(|| unsafe { Port::<u8>::new(SPEAKER_CONTROL).write(prior) });
+    true
+}
@@ -0,0 +1,155 @@
+// System calls through `int 0x80`.
+//
+// The calling convention follows Linux's: the syscall number goes in RAX,
+// arguments in RDI, RSI and RDX, and the result comes back in RAX. The
+// entry stub saves every caller-saved register, so a caller only loses
+// RAX. There's no user space yet, so pointers are only checked for null.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::serial;
+
+/// `write(ptr, len)`: send `len` bytes at `ptr` to serial, returns `len`,
+/// or `ERR_BAD_ADDRESS` for a null `ptr`
+pub const SYS_WRITE: u64 = 0;
+/// `exit(code)`: log the exit code; returns 0 until there are processes
+/// to end
+pub const SYS_EXIT: u64 = 1;
+
+/// Returned in RAX for a syscall number with no handler
+pub const ERR_NO_SYSCALL: u64 = u64::MAX;
+/// Returned in RAX when a pointer argument is null
+pub const ERR_BAD_ADDRESS: u64 = u64::MAX - 1;
+
+/// Vector the entry stub is installed at
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Registers saved by `syscall_entry`, lowest address first
+#[repr(C)]
+struct SavedRegs {
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rax: u64,
+}
+
+type Handler = fn(u64, u64, u64) -> u64;
+
+/// Handlers, indexed by syscall number
+static SYSCALLS: [(&str, Handler); 2] = [("write", sys_write), ("exit", sys_exit)];
+
+/// Syscalls dispatched so far, including unknown numbers
+static CALLS: AtomicU64 = AtomicU64::new(0);
+
+// Entry point for vector 0x80. The CPU has pushed a 5-word frame onto a
+// 16-byte-aligned stack; the 9 pushes here bring it back to alignment
+// for the call.
+core::arch::global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "push rax",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rax",
+    "iretq",
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Address of the entry stub, for the IDT
+pub fn entry_address() -> u64 {
+    syscall_entry as usize as u64
+}
+
+extern "C" fn dispatch(regs: &mut SavedRegs) {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    regs.rax = match SYSCALLS.get(regs.rax as usize) {
+        Some((_, handler)) => handler(regs.rdi, regs.rsi, regs.rdx),
+        None => ERR_NO_SYSCALL,
+    };
+}
+
+fn sys_write(ptr: u64, len: u64, _: u64) -> u64 {
+    if len == 0 {
+        return 0;
+    }
+    if ptr == 0 {
+        return ERR_BAD_ADDRESS;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    let mut serial = serial::SERIAL.lock();
+    for &byte in bytes {
+        serial.write_byte(byte);
+    }
+    len
+}
+
+fn sys_exit(code: u64, _: u64, _: u64) -> u64 {
+    let _ = writeln!(serial::SERIAL.lock(), "[*] syscall: exit({})", code as i64);
+    0
+}
+
+/// Name of syscall `number`, if it has a handler
+pub fn name(number: u64) -> Option<&'static str> {
+    SYSCALLS.get(number as usize).map(|&(name, _)| name)
+}
+
+/// Number of syscalls dispatched since boot
+pub fn call_count() -> u64 {
+    CALLS.load(Ordering::Relaxed)
+}
+
+/// Make a syscall from the kernel through `int 0x80`
+///
+/// Must not be called with the serial lock held, since `write` and `exit`
+/// take it.
+pub fn invoke(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let result;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            inlateout("rax") number => result,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+        );
+    }
+    result
+}
+
+/// Call `write` with a real buffer and a null one, then an unknown
+/// syscall number, and check each result comes back through RAX
+pub fn self_test() -> bool {
+    const MESSAGE: &[u8] = b"[*] int 0x80 write ok\n";
+    let before = call_count();
+    invoke(SYS_WRITE, MESSAGE.as_ptr() as u64, MESSAGE.len() as u64, 0) == MESSAGE.len() as u64
+        && invoke(SYS_WRITE, 0, 1, 0) == ERR_BAD_ADDRESS
+        && invoke(SYSCALLS.len() as u64, 0, 0, 0) == ERR_NO_SYSCALL
+        && call_count() == before + 3
+}
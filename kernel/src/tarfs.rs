@@ -0,0 +1,208 @@
+// Read-only USTAR archive reader.
+//
+// Walks a tar archive stored from block 0 of a `BlockDevice`. Every header
+// and every file's data start on a 512-byte boundary, so archive blocks
+// are device blocks. Only regular files are listed; directories, links
+// and other entries are skipped.
+
+use alloc::vec::Vec;
+
+use crate::block_device::{BlockDevice, BlockError, BlockResult, BLOCK_SIZE};
+
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+/// Longest path: prefix, '/', name
+const PATH_LEN: usize = PREFIX_LEN + 1 + NAME_LEN;
+
+/// A regular file in the archive
+#[derive(Clone, Copy)]
+pub struct TarEntry {
+    path: [u8; PATH_LEN],
+    path_len: usize,
+    pub size: u64,
+    /// Block holding the first byte of the file's data
+    data_block: u64,
+}
+
+impl TarEntry {
+    /// Path inside the archive, without any leading "./"
+    pub fn name(&self) -> &str {
+        let path = core::str::from_utf8(&self.path[..self.path_len]).unwrap_or("?");
+        path.strip_prefix("./").unwrap_or(path)
+    }
+}
+
+/// Bytes of a NUL-terminated header field
+fn field(header: &[u8], offset: usize, len: usize) -> &[u8] {
+    let raw = &header[offset..offset + len];
+    let end = raw.iter().position(|&b| b == 0).unwrap_or(len);
+    &raw[..end]
+}
+
+/// Parse an octal header field, allowing the usual space/NUL padding
+fn octal(header: &[u8], offset: usize, len: usize) -> Option<u64> {
+    let mut value: u64 = 0;
+    let mut digits = 0;
+    for &b in field(header, offset, len) {
+        match b {
+            b'0'..=b'7' => {
+                value = value.checked_mul(8)? + (b - b'0') as u64;
+                digits += 1;
+            }
+            b' ' if digits == 0 => {}
+            b' ' => break,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Whether the header's checksum field matches its contents
+fn checksum_ok(header: &[u8; BLOCK_SIZE]) -> bool {
+    // The checksum is computed with its own field read as spaces
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum();
+    octal(header, 148, 8) == Some(sum)
+}
+
+/// Iterator over the regular files of an archive
+///
+/// Yields `CorruptData` once and stops if a header is damaged or claims
+/// more data than the device holds.
+pub struct Entries<'a, D: BlockDevice> {
+    dev: &'a D,
+    next_header: u64,
+    done: bool,
+}
+
+impl<D: BlockDevice> Entries<'_, D> {
+    fn next_entry(&mut self) -> BlockResult<Option<TarEntry>> {
+        let mut header = [0u8; BLOCK_SIZE];
+        loop {
+            if self.next_header >= self.dev.block_count() {
+                return Ok(None);
+            }
+            self.dev.read_block(self.next_header, &mut header)?;
+            // End of archive: two zero blocks, but one is enough to stop
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            if !checksum_ok(&header) {
+                return Err(BlockError::CorruptData);
+            }
+
+            let size = octal(&header, 124, 12).ok_or(BlockError::CorruptData)?;
+            let data_block = self.next_header + 1;
+            // The data must fit on the device, so later code can trust `size`
+            self.next_header = data_block + size.div_ceil(BLOCK_SIZE as u64);
+            if self.next_header > self.dev.block_count() {
+                return Err(BlockError::CorruptData);
+            }
+
+            if !matches!(header[156], b'0' | 0) {
+                continue;
+            }
+
+            let mut entry = TarEntry {
+                path: [0; PATH_LEN],
+                path_len: 0,
+                size,
+                data_block,
+            };
+            let mut push = |bytes: &[u8]| {
+                entry.path[entry.path_len..entry.path_len + bytes.len()].copy_from_slice(bytes);
+                entry.path_len += bytes.len();
+            };
+            let prefix = field(&header, 345, PREFIX_LEN);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                push(prefix);
+                push(b"/");
+            }
+            push(field(&header, 0, NAME_LEN));
+            return Ok(Some(entry));
+        }
+    }
+}
+
+impl<D: BlockDevice> Iterator for Entries<'_, D> {
+    type Item = BlockResult<TarEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_entry().transpose();
+        self.done = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+/// A tar archive on a block device
+pub struct TarFs<'a, D: BlockDevice> {
+    dev: &'a D,
+}
+
+impl<'a, D: BlockDevice> TarFs<'a, D> {
+    pub fn new(dev: &'a D) -> Self {
+        TarFs { dev }
+    }
+
+    /// The archive's regular files, in archive order
+    pub fn list(&self) -> Entries<'a, D> {
+        Entries {
+            dev: self.dev,
+            next_header: 0,
+            done: false,
+        }
+    }
+
+    /// The first regular file called `name` (a leading "./" is ignored)
+    pub fn find(&self, name: &str) -> BlockResult<Option<TarEntry>> {
+        let name = name.strip_prefix("./").unwrap_or(name);
+        for entry in self.list() {
+            let entry = entry?;
+            if entry.name() == name {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read block `index` of a file's data into `block`, returning how many
+    /// of its bytes belong to the file (0 past the end)
+    pub fn read_chunk(&self, entry: &TarEntry, index: u64, block: &mut [u8; BLOCK_SIZE]) -> BlockResult<usize> {
+        let offset = index * BLOCK_SIZE as u64;
+        if offset >= entry.size {
+            return Ok(0);
+        }
+        self.dev.read_block(entry.data_block + index, block)?;
+        Ok((entry.size - offset).min(BLOCK_SIZE as u64) as usize)
+    }
+
+    /// Pass a file's contents to `f` one block-sized chunk at a time
+    pub fn read_data(&self, entry: &TarEntry, mut f: impl FnMut(&[u8])) -> BlockResult<()> {
+        let mut block = [0u8; BLOCK_SIZE];
+        for index in 0..entry.size.div_ceil(BLOCK_SIZE as u64) {
+            let len = self.read_chunk(entry, index, &mut block)?;
+            f(&block[..len]);
+        }
+        Ok(())
+    }
+
+    /// Copy of the file called `name`, on the heap
+    ///
+    /// Fails with `OutOfMemory` rather than panicking if the heap can't
+    /// hold it.
+    pub fn read_file(&self, name: &str) -> BlockResult<Option<Vec<u8>>> {
+        let Some(entry) = self.find(name)? else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        data.try_reserve_exact(entry.size as usize).map_err(|_| BlockError::OutOfMemory)?;
+        self.read_data(&entry, |chunk| data.extend_from_slice(chunk))?;
+        Ok(Some(data))
+    }
+}
@@ -0,0 +1,75 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::pit::PIT_FREQUENCY;
+/// Calibration window length in milliseconds
+const CALIBRATION_MS: u64 = 10;
+
+/// Measured TSC ticks per millisecond (0 until `calibrate` has run)
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// TSC value at the end of calibration, used as the uptime origin
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Read the CPU's time-stamp counter
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Measure the TSC frequency against PIT channel 2
+///
+/// Channel 2 is gated through port 0x61 and never raises an IRQ, so this is
+/// safe to run with interrupts enabled or disabled and doesn't disturb the
+/// channel 0 system timer. Returns the measured ticks per millisecond.
+pub fn calibrate() -> u64 {
+    let count = PIT_FREQUENCY as u64 * CALIBRATION_MS / 1000;
+
+    unsafe {
+        let mut gate = Port::<u8>::new(0x61);
+        let mut command = Port::<u8>::new(0x43);
+        let mut channel2 = Port::<u8>::new(0x42);
+
+        // Gate high, speaker off
+        let saved = gate.read();
+        gate.write((saved & !0x02) | 0x01);
+
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        command.write(0xB0);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        // Restart the count by pulsing the gate
+        let value = gate.read();
+        gate.write(value & !0x01);
+        gate.write(value | 0x01);
+
+        let start = read();
+        // OUT2 (bit 5) goes high once the count reaches zero
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = read();
+
+        gate.write(saved);
+
+        let ticks_per_ms = (end - start) / CALIBRATION_MS;
+        TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+        BOOT_TSC.store(end, Ordering::Relaxed);
+        ticks_per_ms
+    }
+}
+
+/// TSC ticks per millisecond, or `None` if not yet calibrated
+pub fn ticks_per_ms() -> Option<u64> {
+    match TICKS_PER_MS.load(Ordering::Relaxed) {
+        0 => None,
+        t => Some(t),
+    }
+}
+
+/// Milliseconds since calibration, or 0 if the TSC isn't calibrated
+pub fn uptime_ms() -> u64 {
+    match ticks_per_ms() {
+        Some(t) => read().wrapping_sub(BOOT_TSC.load(Ordering::Relaxed)) / t,
+        None => 0,
+    }
+}